@@ -0,0 +1,178 @@
+//! Minimal ACPI table parsing
+//!
+//! Only the bits of the FADT needed before a real ACPI subsystem exists are read.
+
+use core::{mem::size_of, ptr::read_unaligned, slice};
+
+use conquer_once::spin::OnceCell;
+use log::{info, warn};
+use uefi::table::cfg::{ConfigTableEntry, ACPI2_GUID, ACPI_GUID};
+use x86_64::instructions::port::Port;
+
+use boot_lib::PHYS_MAP_OFFSET;
+
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_RESET_REG_OFFSET: usize = 116;
+const FADT_RESET_VALUE_OFFSET: usize = 128;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+static RESET_REG: OnceCell<ResetRegister> = OnceCell::uninit();
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    ext_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// ACPI Generic Address Structure
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub space_id: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ResetRegister {
+    pub reg: GenericAddress,
+    pub value: u8,
+}
+
+/// Converts a physical table address to a pointer into the physical map
+fn phys_to_virt<T>(addr: u64) -> *const T {
+    if addr >= PHYS_MAP_OFFSET {
+        addr as _
+    } else {
+        (addr + PHYS_MAP_OFFSET) as _
+    }
+}
+
+unsafe fn checksum_ok(ptr: *const u8, len: usize) -> bool {
+    slice::from_raw_parts(ptr, len)
+        .iter()
+        .fold(0u8, |acc, x| acc.wrapping_add(*x))
+        == 0
+}
+
+/// Find a table by its signature in the RSDT or XSDT
+unsafe fn find_table(rsdp: *const Rsdp, signature: &[u8; 4]) -> Option<*const SdtHeader> {
+    let rsdp = read_unaligned(rsdp);
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (phys_to_virt::<SdtHeader>(rsdp.xsdt_address), 8)
+    } else {
+        (phys_to_virt::<SdtHeader>(rsdp.rsdt_address as u64), 4)
+    };
+
+    let len = read_unaligned(root).length as usize;
+    let entries = (len - size_of::<SdtHeader>()) / entry_size;
+    let base = (root as *const u8).add(size_of::<SdtHeader>());
+
+    (0..entries)
+        .map(|i| {
+            let ptr = base.add(i * entry_size);
+            if entry_size == 8 {
+                read_unaligned(ptr as *const u64)
+            } else {
+                read_unaligned(ptr as *const u32) as u64
+            }
+        })
+        .map(phys_to_virt::<SdtHeader>)
+        .find(|table| {
+            let header = read_unaligned(*table);
+            &header.signature == signature
+                && checksum_ok(*table as *const u8, header.length as usize)
+        })
+}
+
+/// Find the RSDP in the UEFI configuration table and cache what is needed later
+///
+/// Must be called while the ACPI tables are still intact
+pub unsafe fn init(config: &[ConfigTableEntry]) {
+    let rsdp = match config
+        .iter()
+        .find(|e| e.guid == ACPI2_GUID)
+        .or_else(|| config.iter().find(|e| e.guid == ACPI_GUID))
+    {
+        Some(entry) => phys_to_virt::<Rsdp>(entry.address as u64),
+        None => {
+            warn!("No ACPI RSDP found");
+            return;
+        }
+    };
+
+    if &read_unaligned(rsdp).signature != b"RSD PTR " {
+        warn!("Invalid ACPI RSDP signature");
+        return;
+    }
+
+    let fadt = match find_table(rsdp, b"FACP") {
+        Some(fadt) => fadt as *const u8,
+        None => {
+            warn!("No FADT found");
+            return;
+        }
+    };
+
+    let len = read_unaligned(fadt as *const SdtHeader).length as usize;
+    if len <= FADT_RESET_VALUE_OFFSET {
+        info!("FADT too old to contain a reset register");
+        return;
+    }
+
+    let flags = read_unaligned(fadt.add(FADT_FLAGS_OFFSET) as *const u32);
+    if flags & FADT_RESET_REG_SUP == 0 {
+        info!("ACPI reset register not supported");
+        return;
+    }
+
+    let reset = ResetRegister {
+        reg: read_unaligned(fadt.add(FADT_RESET_REG_OFFSET) as *const GenericAddress),
+        value: *fadt.add(FADT_RESET_VALUE_OFFSET),
+    };
+
+    info!("ACPI reset register: {:x?}", reset);
+
+    RESET_REG.init_once(|| reset);
+}
+
+/// Write the reset value into the FADT reset register
+///
+/// Returns if the register is not supported or the reset did not happen
+pub fn reset() {
+    if let Ok(reset) = RESET_REG.try_get() {
+        let addr = reset.reg.address;
+        match reset.reg.space_id {
+            GAS_SYSTEM_IO => unsafe { Port::new(addr as u16).write(reset.value) },
+            GAS_SYSTEM_MEMORY => unsafe {
+                (phys_to_virt::<u8>(addr) as *mut u8).write_volatile(reset.value)
+            },
+            id => warn!("Unsupported ACPI reset register address space {}", id),
+        }
+    }
+}
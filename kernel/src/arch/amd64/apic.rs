@@ -0,0 +1,78 @@
+//! Local APIC
+//!
+//! Only what interprocessor interrupts need: the registers are mapped uncached, the APIC is
+//! software-enabled and the ID of each CPU is recorded so that IPIs can be addressed to it. The
+//! legacy PICs still deliver the device interrupts.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use log::info;
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::Msr, PhysAddr,
+};
+
+use crate::{
+    arch::{
+        cpu::{self, MAX_CPUS},
+        interrupt::IntIdx,
+    },
+    data::late_init::LateInit,
+    mm::mmio::{ioremap, CacheMode, MmioRegion},
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REGS_LEN: usize = 0x400;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+
+const NO_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// APIC ID of each CPU, `u32::MAX` until it started
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_ID; MAX_CPUS];
+
+static LAPIC: LateInit<MmioRegion> = LateInit::new();
+
+/// Map and enable the local APIC of this CPU, after the memory manager is up
+pub fn init() {
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & BASE_MASK;
+    let regs = ioremap(PhysAddr::new(base), REGS_LEN, CacheMode::Uncached)
+        .expect("Cannot map the local APIC");
+    regs.write32(REG_SVR, SVR_ENABLE | IntIdx::ApicSpurious.as_u8() as u32);
+
+    let id = regs.read32(REG_ID) >> 24;
+    APIC_IDS[cpu::this_cpu()].store(id, Ordering::Release);
+    LAPIC.init(regs);
+    info!("Local APIC {} at {:#x}", id, base);
+}
+
+/// Send interrupt `vector` to `cpu`, false if it has no APIC yet
+pub fn send_ipi(cpu: usize, vector: u8) -> bool {
+    let id = APIC_IDS[cpu].load(Ordering::Acquire);
+    let regs = match LAPIC.try_get() {
+        Some(regs) if id != u32::MAX => regs,
+        _ => return false,
+    };
+
+    // Both halves of the ICR must be written without an interrupt sending in between
+    without_interrupts(|| {
+        regs.write32(REG_ICR_HIGH, id << 24);
+        regs.write32(REG_ICR_LOW, vector as u32);
+        while regs.read32(REG_ICR_LOW) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+    true
+}
+
+/// Signal the end of an interrupt delivered by the local APIC
+pub fn eoi() {
+    LAPIC.write32(REG_EOI, 0);
+}
@@ -1,7 +1,7 @@
 #![feature(abi_efiapi)]
 #![no_std]
 
-use arrayvec::ArrayVec;
+use arrayvec::{ArrayString, ArrayVec};
 use core::fmt::{Debug, Formatter};
 use uefi::{
     proto::console::gop::ModeInfo,
@@ -11,6 +11,7 @@ use uefi::{
 pub type KernelEntryPoint = extern "efiapi" fn(*mut KernelArgs) -> !;
 
 pub const KERNEL_ARGS_MDL_SIZE: u64 = 512;
+pub const KERNEL_CMDLINE_MAX: usize = 256;
 
 pub const KERNEL_MEM_TYPE_RANGE_START: u32 = 0x80000000;
 pub const KERNEL_RX_MEM_TYPE: u32 = 0x80000001;
@@ -30,11 +31,15 @@ pub struct KernelArgs {
     pub uefi_rst: SystemTable<Runtime>,
     pub fb_addr: *mut u8,
     pub fb_info: ModeInfo,
+    pub cmdline: ArrayString<KERNEL_CMDLINE_MAX>,
 }
 
 impl Debug for KernelArgs {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        f.write_str("KernelArgs with MDL: ")?;
+        f.write_fmt(format_args!(
+            "KernelArgs with cmdline {:?} and MDL: ",
+            self.cmdline
+        ))?;
 
         for i in self.mmap.iter() {
             f.write_fmt(format_args!("{:?}\n", i))?
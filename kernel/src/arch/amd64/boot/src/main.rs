@@ -5,7 +5,7 @@
 extern crate alloc;
 
 use alloc::{string::ToString, vec::Vec};
-use arrayvec::ArrayVec;
+use arrayvec::{ArrayString, ArrayVec};
 use core::mem::{size_of, zeroed, MaybeUninit};
 
 use log::{error, info};
@@ -24,8 +24,8 @@ use x86_64::{
 
 use alloc::vec;
 use boot_lib::{
-    KernelArgs, KernelEntryPoint, KERNEL_ARGS_MEM_TYPE, KERNEL_STACK_BOTTOM, KERNEL_STACK_MEM_TYPE,
-    KERNEL_STACK_SIZE_PAGES, PHYS_MAP_OFFSET, PTE_MEM_TYPE,
+    KernelArgs, KernelEntryPoint, KERNEL_ARGS_MEM_TYPE, KERNEL_CMDLINE_MAX, KERNEL_STACK_BOTTOM,
+    KERNEL_STACK_MEM_TYPE, KERNEL_STACK_SIZE_PAGES, PHYS_MAP_OFFSET, PTE_MEM_TYPE,
 };
 use core::{arch::asm, iter::FromIterator, ptr::addr_of_mut};
 use uefi::{
@@ -40,6 +40,7 @@ use x86_64::structures::paging::{
 mod elf;
 
 static K_FILE: &'static str = "kernel";
+static CMDLINE_FILE: &'static str = "cmdline";
 
 struct UefiAlloc();
 
@@ -176,7 +177,7 @@ unsafe fn map_stack<M>(
             .map_to_with_table_flags(
                 Page::containing_address(bottom - Size4KiB::SIZE * i),
                 PhysFrame::containing_address(PhysAddr::new(
-                    mem + Size4KiB::SIZE * (size_pages - i),
+                    mem + Size4KiB::SIZE * (size_pages - 1 - i),
                 )),
                 PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | PageTableFlags::WRITABLE,
                 PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
@@ -247,6 +248,51 @@ unsafe fn map_kernel<M: Mapper<Size4KiB>>(
     elf::map_elf(&k_buf, page_table, system_table)
 }
 
+/// Read the kernel command line from an optional file next to the kernel executable
+fn load_cmdline(
+    handle: Handle,
+    system_table: &mut SystemTable<Boot>,
+) -> ArrayString<KERNEL_CMDLINE_MAX> {
+    let mut cmdline = ArrayString::new();
+
+    let fs = unsafe {
+        &mut *system_table
+            .boot_services()
+            .get_image_file_system(handle.clone())
+            .expect_success("Failed to open FS")
+            .get()
+    };
+
+    let mut dir = fs
+        .open_volume()
+        .expect_success("Failed to open root directory");
+
+    let mut fd = match dir.open(CMDLINE_FILE, FileMode::Read, FileAttribute::empty()) {
+        Ok(fd) => unsafe { RegularFile::new(fd.log()) },
+        Err(_) => {
+            info!("No kernel command line file found");
+            return cmdline;
+        }
+    };
+
+    let mut buf = [0; KERNEL_CMDLINE_MAX];
+
+    let len = fd
+        .read(&mut buf)
+        .expect_success("Failed to read kernel command line");
+
+    fd.close();
+
+    match core::str::from_utf8(&buf[..len]) {
+        Ok(s) => cmdline.push_str(s.trim()),
+        Err(_) => error!("Kernel command line is not valid UTF-8, ignoring"),
+    }
+
+    info!("Kernel command line: {}", cmdline);
+
+    cmdline
+}
+
 fn init_fb(system_table: &mut SystemTable<Boot>) -> (FrameBuffer<'static>, ModeInfo) {
     let gop = unsafe {
         system_table
@@ -414,6 +460,8 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     let entry = unsafe { map_kernel(handle, &mut system_table, &mut page_table) };
 
+    let cmdline = load_cmdline(handle, &mut system_table);
+
     info!(
         "Allocating kernel stack and mapping it at {:#x}",
         KERNEL_STACK_BOTTOM
@@ -481,6 +529,7 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 addr_of_mut!((*args_ptr).fb_addr)
                     .write((fb.as_mut_ptr() as u64 + PHYS_MAP_OFFSET) as _);
                 addr_of_mut!((*args_ptr).fb_info).write(fb_mode);
+                addr_of_mut!((*args_ptr).cmdline).write(cmdline);
             }
 
             let args_ptr = args.assume_init_mut() as *mut KernelArgs;
@@ -0,0 +1,151 @@
+//! CPU feature detection and hardening
+//!
+//! The bootloader leaves CR0 and CR4 as the firmware configured them, with `WRITE_PROTECT`
+//! cleared, so everything the kernel relies on is enabled here.
+
+use core::arch::asm;
+
+use bitflags::bitflags;
+use conquer_once::spin::OnceCell;
+use log::info;
+use raw_cpuid::CpuId;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags},
+    model_specific::{Efer, EferFlags},
+};
+
+bitflags! {
+    /// Features detected and enabled during boot
+    pub struct CpuFeatures: u32 {
+        const NX = 1 << 0;
+        const PGE = 1 << 1;
+        const PCID = 1 << 2;
+        const INVPCID = 1 << 3;
+        const SMEP = 1 << 4;
+        const SMAP = 1 << 5;
+        const UMIP = 1 << 6;
+        const PAT = 1 << 7;
+        const HUGE_1GIB = 1 << 8;
+    }
+}
+
+/// The kernel cannot run without these, the physical map is global and non-executable
+const REQUIRED: CpuFeatures =
+    CpuFeatures::from_bits_truncate(CpuFeatures::NX.bits() | CpuFeatures::PGE.bits());
+
+static FEATURES: OnceCell<CpuFeatures> = OnceCell::uninit();
+
+/// No SMP yet, everything runs on CPU 0
+pub const MAX_CPUS: usize = 1;
+
+/// Index of the CPU running this code
+pub fn this_cpu() -> usize {
+    0
+}
+
+fn detect() -> CpuFeatures {
+    let cpuid = CpuId::new();
+    let mut features = CpuFeatures::empty();
+
+    if let Some(info) = cpuid.get_feature_info() {
+        features.set(CpuFeatures::PGE, info.has_pge());
+        features.set(CpuFeatures::PCID, info.has_pcid());
+        features.set(CpuFeatures::PAT, info.has_pat());
+    }
+
+    if let Some(info) = cpuid.get_extended_feature_info() {
+        features.set(CpuFeatures::SMEP, info.has_smep());
+        features.set(CpuFeatures::SMAP, info.has_smap());
+        features.set(CpuFeatures::UMIP, info.has_umip());
+        features.set(CpuFeatures::INVPCID, info.has_invpcid());
+    }
+
+    if let Some(info) = cpuid.get_extended_processor_and_feature_identifiers() {
+        features.set(CpuFeatures::NX, info.has_execute_disable());
+        features.set(CpuFeatures::HUGE_1GIB, info.has_1gib_pages());
+    }
+
+    features
+}
+
+/// Detect CPU features and enable the protection ones
+///
+/// Panics if a required feature is missing
+pub fn init() {
+    let features = detect();
+
+    let missing = REQUIRED - features;
+    if !missing.is_empty() {
+        panic!("CPU lacks required features: {:?}", missing);
+    }
+
+    unsafe {
+        Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT);
+
+        if features.contains(CpuFeatures::PCID) {
+            // PCIDE can only be set while the current PCID is 0
+            let (frame, _) = Cr3::read();
+            Cr3::write(frame, Cr3Flags::empty());
+        }
+
+        Cr4::update(|cr4| {
+            *cr4 |= Cr4Flags::PAGE_GLOBAL;
+            cr4.set(Cr4Flags::PCID, features.contains(CpuFeatures::PCID));
+            cr4.set(
+                Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+                features.contains(CpuFeatures::SMEP),
+            );
+            cr4.set(
+                Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+                features.contains(CpuFeatures::SMAP),
+            );
+            cr4.set(
+                Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION,
+                features.contains(CpuFeatures::UMIP),
+            );
+        });
+    }
+
+    FEATURES.init_once(|| features);
+
+    info!("CPU features: {:?}", features);
+    info!("CR0 -> {:?}", Cr0::read());
+    info!("CR4 -> {:?}", Cr4::read());
+}
+
+/// Features enabled by `init`, empty before it
+pub fn features() -> CpuFeatures {
+    FEATURES
+        .try_get()
+        .copied()
+        .unwrap_or_else(|_| CpuFeatures::empty())
+}
+
+pub fn has(feature: CpuFeatures) -> bool {
+    features().contains(feature)
+}
+
+/// Allow supervisor access to user pages, no-op without SMAP
+#[inline]
+pub fn stac() {
+    if has(CpuFeatures::SMAP) {
+        unsafe { asm!("stac", options(nostack)) }
+    }
+}
+
+/// Forbid supervisor access to user pages again, no-op without SMAP
+#[inline]
+pub fn clac() {
+    if has(CpuFeatures::SMAP) {
+        unsafe { asm!("clac", options(nostack)) }
+    }
+}
+
+/// Run `f` with user pages accessible, e.g. to copy from or to user memory
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    stac();
+    let res = f();
+    clac();
+    res
+}
@@ -1,12 +1,50 @@
+use core::arch::asm;
+
 use lazy_static::lazy_static;
 
 use spin::Mutex as Spinlock;
 use uart_16550::SerialPort;
 
+pub const COM1: u16 = 0x3F8;
+/// Reserved for the GDB stub
+pub const COM2: u16 = 0x2F8;
+
 lazy_static! {
     pub static ref SERIAL1: Spinlock<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Spinlock::new(serial_port)
     };
 }
+
+/// Start of the kernel half, frame pointers outside it end a backtrace
+const KERNEL_HALF: u64 = 0xFFFF_8000_0000_0000;
+/// Frames larger than this are taken for a corrupted chain
+const MAX_FRAME: u64 = 0x10000;
+
+/// Return addresses of the callers, innermost first, returns how many were written
+///
+/// Walks the `rbp` chain, so the kernel must be built with `-Cforce-frame-pointers=yes`.
+#[inline(always)]
+#[cfg_attr(feature = "kasan", no_sanitize(address))]
+pub fn backtrace(out: &mut [u64]) -> usize {
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    let mut n = 0;
+    while n < out.len() && rbp >= KERNEL_HALF && rbp % 8 == 0 {
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if ret == 0 {
+            break;
+        }
+        out[n] = ret;
+        n += 1;
+
+        if next <= rbp || next - rbp > MAX_FRAME {
+            break;
+        }
+        rbp = next;
+    }
+    n
+}
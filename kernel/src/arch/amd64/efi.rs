@@ -0,0 +1,386 @@
+//! UEFI runtime services access
+//!
+//! The firmware was relocated to `PHYS_MAP_OFFSET` by the bootloader, but the physical map is
+//! non-executable, so the runtime services are called with a private copy of the page tables
+//! in which `RUNTIME_SERVICES_CODE` is executable. All calls are serialized by a single lock.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::ptr::NonNull;
+
+use log::{info, warn};
+use uefi::{
+    table::{
+        boot::{MemoryDescriptor, MemoryType},
+        runtime::{ResetType, RuntimeServices, Time, VariableAttributes, VariableVendor},
+        Runtime, SystemTable,
+    },
+    CStr16, Guid, Status,
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, PageSize, PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use boot_lib::PHYS_MAP_OFFSET;
+
+use crate::{
+    arch::PAGE_SIZE,
+    data::{late_init::LateInit, misc::Pointable},
+    mm::alloc::phys::GlobalFrameAllocator,
+    sync::irq_lock::IRQLocked,
+};
+
+const OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 1;
+const MAX_VAR_NAME: usize = 128;
+
+/// Namespace of the variables owned by phobos
+pub const PHOBOS_VENDOR: VariableVendor = VariableVendor(Guid::from_values(
+    0x7068_6f62,
+    0x6f73,
+    0x4b72,
+    0x8e6c,
+    0x0000_6e76_7261,
+));
+
+static RUNTIME: IRQLocked<LateInit<EfiRuntime>> = IRQLocked::new(LateInit::new());
+
+struct EfiRuntime {
+    /// `uefi` only hands out shared references, but `SetTime` needs the table exclusively
+    services: NonNull<RuntimeServices>,
+    /// Physical start and page count of every `RUNTIME_SERVICES_CODE` region
+    code: Vec<(u64, u64)>,
+    pt: RuntimePageTable,
+}
+
+/// Page tables used while calling the firmware
+///
+/// Tables on the path to runtime code are private copies, everything else is shared with the
+/// kernel. The copies are refreshed before each call, so new kernel mappings are visible too.
+struct RuntimePageTable {
+    pml4: PhysFrame,
+    /// Frames holding private tables, reused between rebuilds
+    frames: Vec<PhysFrame>,
+    used: usize,
+}
+
+impl RuntimePageTable {
+    fn new() -> Option<Self> {
+        Some(Self {
+            pml4: GlobalFrameAllocator.allocate_frame()?,
+            frames: Vec::new(),
+            used: 0,
+        })
+    }
+
+    fn next_frame(&mut self) -> Option<PhysFrame> {
+        if self.used == self.frames.len() {
+            self.frames.try_reserve(1).ok()?;
+            self.frames.push(GlobalFrameAllocator.allocate_frame()?);
+        }
+        self.used += 1;
+        Some(self.frames[self.used - 1])
+    }
+
+    fn is_private(&self, frame: PhysFrame) -> bool {
+        self.frames[..self.used].contains(&frame)
+    }
+
+    /// Returns the table `entry` points to, copying it first if it is still shared
+    unsafe fn private_table<'a>(
+        &mut self,
+        entry: &mut PageTableEntry,
+    ) -> Option<&'a mut PageTable> {
+        let frame = PhysFrame::containing_address(entry.addr());
+        if !self.is_private(frame) {
+            let copy = self.next_frame()?;
+            copy.pointer()
+                .as_ptr()
+                .copy_from_nonoverlapping(frame.pointer().as_ptr(), PAGE_SIZE as usize);
+            entry.set_addr(
+                copy.start_address(),
+                entry.flags() - PageTableFlags::NO_EXECUTE,
+            );
+        }
+        Some(entry.addr().pointer().cast::<PageTable>().as_mut())
+    }
+
+    /// Clear `NO_EXECUTE` on the mapping of `addr`
+    ///
+    /// Huge pages become executable as a whole, which is fine since this table is only loaded
+    /// while calling the firmware
+    unsafe fn make_executable(&mut self, addr: VirtAddr) -> Option<()> {
+        let pml4 = self.pml4.pointer().cast::<PageTable>().as_mut();
+        let p3 = self.private_table(&mut pml4[addr.p4_index()])?;
+        let e3 = &mut p3[addr.p3_index()];
+        if e3.flags().contains(PageTableFlags::HUGE_PAGE) {
+            e3.set_flags(e3.flags() - PageTableFlags::NO_EXECUTE);
+            return Some(());
+        }
+
+        let p2 = self.private_table(e3)?;
+        let e2 = &mut p2[addr.p2_index()];
+        if e2.flags().contains(PageTableFlags::HUGE_PAGE) {
+            e2.set_flags(e2.flags() - PageTableFlags::NO_EXECUTE);
+            return Some(());
+        }
+
+        let p1 = self.private_table(e2)?;
+        let e1 = &mut p1[addr.p1_index()];
+        e1.set_flags(e1.flags() - PageTableFlags::NO_EXECUTE);
+        Some(())
+    }
+
+    /// Copy the current kernel page tables and make runtime code executable again
+    ///
+    /// `None` if there is no frame for a private table, the tables must not be loaded then
+    unsafe fn rebuild(&mut self, code: &[(u64, u64)]) -> Option<()> {
+        let (current, _) = Cr3::read();
+        self.pml4
+            .pointer()
+            .as_ptr()
+            .copy_from_nonoverlapping(current.pointer().as_ptr(), PAGE_SIZE as usize);
+        self.used = 0;
+
+        for &(start, pages) in code {
+            for page in 0..pages {
+                self.make_executable(VirtAddr::new(
+                    PHYS_MAP_OFFSET + start + page * Size4KiB::SIZE,
+                ))?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Save the system table and the location of the runtime code
+pub unsafe fn init<'a, T>(table: SystemTable<Runtime>, mmap: T)
+where
+    T: IntoIterator<Item = &'a MemoryDescriptor>,
+{
+    let code = mmap
+        .into_iter()
+        .filter(|d| d.ty == MemoryType::RUNTIME_SERVICES_CODE)
+        .map(|d| (d.phys_start, d.page_count))
+        .collect();
+
+    info!(
+        "UEFI runtime services v{}.{} by {}",
+        table.uefi_revision().major(),
+        table.uefi_revision().minor(),
+        table.firmware_vendor()
+    );
+
+    let pt = match RuntimePageTable::new() {
+        Some(pt) => pt,
+        None => {
+            warn!("No memory for the UEFI runtime page tables");
+            return;
+        }
+    };
+
+    // The table is firmware memory, reached through the physical map like any other
+    let services = VirtAddr::from_ptr(table.runtime_services()).as_mut_ptr();
+    RUNTIME.lock().init(EfiRuntime {
+        services: NonNull::new(services).unwrap(),
+        code,
+        pt,
+    });
+}
+
+/// Run `f` with the runtime page tables loaded and interrupts disabled
+///
+/// Fails with `UNSUPPORTED` if the runtime services are unavailable or already in use. Calls hold
+/// the lock with interrupts disabled, so it is only held here when a firmware call faulted and the
+/// panic path came back in. Waiting would never end. Fails with `OUT_OF_RESOURCES` if there is no
+/// memory for the page tables.
+fn with_runtime<R>(f: impl FnOnce(&mut RuntimeServices) -> Result<R, Status>) -> Result<R, Status> {
+    if RUNTIME.is_locked() {
+        return Err(Status::UNSUPPORTED);
+    }
+
+    let mut guard = RUNTIME.lock();
+    guard.try_get().ok_or(Status::UNSUPPORTED)?;
+    let rt = &mut **guard;
+
+    unsafe {
+        rt.pt.rebuild(&rt.code).ok_or(Status::OUT_OF_RESOURCES)?;
+        let (old, flags) = Cr3::read();
+        Cr3::write(rt.pt.pml4, flags);
+        let res = f(rt.services.as_mut());
+        Cr3::write(old, flags);
+        res
+    }
+}
+
+fn status<T>(res: uefi::Result<T>) -> Result<T, Status> {
+    res.map(|c| c.log()).map_err(|e| e.status())
+}
+
+/// Read the wall-clock time kept by the firmware
+pub fn get_time() -> Result<Time, Status> {
+    with_runtime(|rs| status(rs.get_time()))
+}
+
+/// Set the wall-clock time kept by the firmware
+pub fn set_time(time: &Time) -> Result<(), Status> {
+    with_runtime(|rs| status(unsafe { rs.set_time(time) }))
+}
+
+/// Read the contents and attributes of a variable
+pub fn get_variable(
+    name: &str,
+    vendor: &VariableVendor,
+) -> Result<(Vec<u8>, VariableAttributes), Status> {
+    let mut name_buf = [0u16; MAX_VAR_NAME];
+    let name =
+        CStr16::from_str_with_buf(name, &mut name_buf).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    // Allocate outside of the firmware call
+    let size = with_runtime(|rs| status(rs.get_variable_size(name, vendor)))?;
+    let mut buf = vec![0u8; size];
+
+    let (len, attrs) = with_runtime(|rs| {
+        status(rs.get_variable(name, vendor, &mut buf)).map(|(data, attrs)| (data.len(), attrs))
+    })?;
+
+    buf.truncate(len);
+    Ok((buf, attrs))
+}
+
+/// Create, update or, if `data` is empty, delete a variable
+pub fn set_variable(
+    name: &str,
+    vendor: &VariableVendor,
+    attributes: VariableAttributes,
+    data: &[u8],
+) -> Result<(), Status> {
+    let mut name_buf = [0u16; MAX_VAR_NAME];
+    let name =
+        CStr16::from_str_with_buf(name, &mut name_buf).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    with_runtime(|rs| status(rs.set_variable(name, vendor, attributes, data)))
+}
+
+/// `EFI_RUNTIME_SERVICES` up to `GetNextVariableName`, which `uefi` only wraps in a function
+/// that allocates
+#[repr(C)]
+struct RawRuntimeServices {
+    header: [u64; 3],
+    /// `GetTime` to `GetVariable`
+    _before: [usize; 7],
+    get_next_variable_name: unsafe extern "efiapi" fn(
+        name_size: *mut usize,
+        name: *mut u16,
+        vendor: *mut Guid,
+    ) -> Status,
+}
+
+/// Names and vendors of all variables visible at runtime
+///
+/// The name buffer is only grown between firmware calls, nothing is allocated during one.
+pub fn variable_names() -> Result<Vec<(String, VariableVendor)>, Status> {
+    let mut names = Vec::new();
+    // The empty name asks for the first variable, each call replaces it with the next one
+    let mut name = vec![0u16; MAX_VAR_NAME];
+    let mut vendor = Guid::default();
+    loop {
+        let mut size = name.len() * 2;
+        let status = with_runtime(|rs| unsafe {
+            let raw = &*(rs as *mut RuntimeServices as *const RawRuntimeServices);
+            Ok((raw.get_next_variable_name)(
+                &mut size,
+                name.as_mut_ptr(),
+                &mut vendor,
+            ))
+        })
+        .unwrap_or_else(|e| e);
+
+        match status {
+            Status::SUCCESS => {
+                let chars = name.iter().copied().take_while(|&c| c != 0);
+                if let Ok(decoded) = char::decode_utf16(chars).collect::<Result<String, _>>() {
+                    names.push((decoded, VariableVendor(vendor)));
+                }
+            }
+            // The buffer still holds the previous name, so the call is repeated
+            Status::BUFFER_TOO_SMALL => name.resize(size / 2, 0),
+            Status::NOT_FOUND => return Ok(names),
+            e => return Err(e),
+        }
+    }
+}
+
+/// Read a persistent kernel setting from NVRAM
+pub fn get_setting(name: &str) -> Option<Vec<u8>> {
+    get_variable(name, &PHOBOS_VENDOR)
+        .ok()
+        .map(|(data, _)| data)
+}
+
+/// Persist a kernel setting in NVRAM
+pub fn set_setting(name: &str, data: &[u8]) -> Result<(), Status> {
+    set_variable(
+        name,
+        &PHOBOS_VENDOR,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        data,
+    )
+}
+
+/// Print all variables to the log
+pub fn log_variables() {
+    match variable_names() {
+        Ok(names) => {
+            for (name, vendor) in names {
+                if vendor == VariableVendor::GLOBAL_VARIABLE {
+                    info!("{} (global)", name);
+                } else {
+                    info!("{} ({})", name, vendor.0);
+                }
+            }
+        }
+        Err(e) => warn!("Could not list UEFI variables: {:?}", e),
+    }
+}
+
+fn read_u64_var(name: &str) -> Option<u64> {
+    let (data, _) = get_variable(name, &VariableVendor::GLOBAL_VARIABLE).ok()?;
+    let mut val = [0u8; 8];
+    let len = data.len().min(8);
+    val[..len].copy_from_slice(&data[..len]);
+    Some(u64::from_le_bytes(val))
+}
+
+/// Ask the firmware to stop in its setup UI on the next boot
+///
+/// Returns `false` if the firmware does not support this
+pub fn request_boot_to_firmware() -> bool {
+    let supported = read_u64_var("OsIndicationsSupported").unwrap_or(0);
+    if supported & OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+        return false;
+    }
+
+    let value = read_u64_var("OsIndications").unwrap_or(0) | OS_INDICATIONS_BOOT_TO_FW_UI;
+    set_variable(
+        "OsIndications",
+        &VariableVendor::GLOBAL_VARIABLE,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &value.to_le_bytes(),
+    )
+    .is_ok()
+}
+
+/// Reset the system through `ResetSystem()`
+///
+/// Returns only if the runtime services are unavailable
+pub fn reset(kind: ResetType) {
+    let _: Result<(), _> = with_runtime(|rs| rs.reset(kind, Status::SUCCESS, None));
+    warn!("UEFI ResetSystem() is unavailable");
+}
@@ -0,0 +1,662 @@
+//! GDB remote serial protocol stub on COM2
+//!
+//! Enabled with the `gdb` command line flag, in which case the kernel stops early during boot
+//! and waits for the debugger. `#BP` and `#DB` enter the stub through the trampolines in
+//! `asm.S`, which save every general purpose register so that they can be read and modified.
+//! Memory is accessed through the physical map after walking the current page tables, so
+//! breakpoints can be placed in code regardless of page protection.
+
+use core::{arch::asm, fmt::Write, str};
+
+use arrayvec::{ArrayString, ArrayVec};
+use boot_lib::PHYS_MAP_OFFSET;
+use log::info;
+use uart_16550::SerialPort;
+use x86_64::{instructions::interrupts::int3, structures::paging::Translate, VirtAddr};
+
+use crate::{
+    arch::{debug::COM2, mem::get_pt},
+    sync::irq_lock::IRQLocked,
+};
+
+const MAX_PACKET: usize = 4096;
+const MAX_BREAKPOINTS: usize = 64;
+const NUM_REGS: usize = 24;
+
+const VECTOR_DEBUG: u64 = 1;
+const VECTOR_BREAKPOINT: u64 = 3;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_RF: u64 = 1 << 16;
+const DR6_HIT_MASK: u64 = 0xF;
+
+const INT3: u8 = 0xCC;
+
+static GDB: IRQLocked<Option<Gdb>> = IRQLocked::new(None);
+
+extern "C" {
+    /// Imported from asm.S
+    fn gdb_debug_entry();
+    /// Imported from asm.S
+    fn gdb_breakpoint_entry();
+}
+
+/// Registers saved by the trampolines in `asm.S`
+#[repr(C)]
+pub struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl TrapFrame {
+    /// Register by its number in GDB's amd64 layout
+    ///
+    /// The data segment registers are not saved and read as zero.
+    fn reg(&mut self, n: usize) -> Option<&mut u64> {
+        Some(match n {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18 => &mut self.cs,
+            19 => &mut self.ss,
+            _ => return None,
+        })
+    }
+}
+
+/// Size of a register in bytes, eflags and the segment registers are 32-bit
+fn reg_size(n: usize) -> usize {
+    if n < 17 {
+        8
+    } else {
+        4
+    }
+}
+
+/// Only GPRs, rip and rflags may be changed by the debugger
+fn reg_writable(n: usize) -> bool {
+    n < 18
+}
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: u64,
+    orig: u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum WatchKind {
+    Execute,
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    fn from_packet(ty: u8) -> Option<Self> {
+        match ty {
+            b'1' => Some(Self::Execute),
+            b'2' => Some(Self::Write),
+            b'3' => Some(Self::Read),
+            b'4' => Some(Self::Access),
+            _ => None,
+        }
+    }
+
+    /// The R/W field in DR7, reads can only be trapped together with writes
+    fn dr7_rw(self) -> u64 {
+        match self {
+            Self::Execute => 0b00,
+            Self::Write => 0b01,
+            Self::Read | Self::Access => 0b11,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Watchpoint {
+    addr: u64,
+    len: u64,
+    kind: WatchKind,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Gdb {
+    port: SerialPort,
+    breakpoints: ArrayVec<Breakpoint, MAX_BREAKPOINTS>,
+    watchpoints: [Option<Watchpoint>; 4],
+    /// Breakpoint lifted to execute the instruction under it, restored after one step
+    step_over: Option<u64>,
+    /// The debugger asked for a single step
+    stepping: bool,
+    /// Whether a debugger has talked to us and expects stop replies
+    attached: bool,
+}
+
+fn hex_digit(val: u8) -> u8 {
+    b"0123456789abcdef"[(val & 0xF) as usize]
+}
+
+fn hex_val(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Parse a little-endian register value
+fn parse_le(s: &str, size: usize) -> Option<u64> {
+    let bytes = s.as_bytes();
+    if bytes.len() < size * 2 {
+        return None;
+    }
+    (0..size).try_fold(0u64, |acc, i| {
+        let byte = (hex_val(bytes[i * 2])? << 4) | hex_val(bytes[i * 2 + 1])?;
+        Some(acc | (byte as u64) << (i * 8))
+    })
+}
+
+/// Pointer to `addr` through the physical map, `None` if it is not mapped
+fn phys_ptr(addr: u64) -> Option<*mut u8> {
+    let virt = VirtAddr::try_new(addr).ok()?;
+    get_pt()
+        .translate_addr(virt)
+        .map(|phys| (phys.as_u64() + PHYS_MAP_OFFSET) as *mut u8)
+}
+
+fn read_mem(addr: u64) -> Option<u8> {
+    phys_ptr(addr).map(|ptr| unsafe { ptr.read_volatile() })
+}
+
+fn write_mem(addr: u64, val: u8) -> bool {
+    phys_ptr(addr)
+        .map(|ptr| unsafe { ptr.write_volatile(val) })
+        .is_some()
+}
+
+fn read_dr6() -> u64 {
+    let val;
+    unsafe { asm!("mov {}, dr6", out(reg) val, options(nomem, nostack)) }
+    val
+}
+
+unsafe fn write_debug_regs(addrs: [u64; 4], dr7: u64) {
+    asm!(
+        "mov dr0, {}",
+        "mov dr1, {}",
+        "mov dr2, {}",
+        "mov dr3, {}",
+        "mov dr7, {}",
+        in(reg) addrs[0],
+        in(reg) addrs[1],
+        in(reg) addrs[2],
+        in(reg) addrs[3],
+        in(reg) dr7,
+        options(nomem, nostack)
+    );
+}
+
+impl Gdb {
+    fn new() -> Self {
+        let mut port = unsafe { SerialPort::new(COM2) };
+        port.init();
+        Self {
+            port,
+            breakpoints: ArrayVec::new(),
+            watchpoints: [None; 4],
+            step_over: None,
+            stepping: false,
+            attached: false,
+        }
+    }
+
+    fn read_packet(&mut self, buf: &mut ArrayVec<u8, MAX_PACKET>) {
+        loop {
+            // Acks and interrupt requests outside of packets are ignored
+            while self.port.receive() != b'$' {}
+
+            buf.clear();
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                let c = self.port.receive();
+                if c == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(c);
+                overflow |= buf.try_push(c).is_err();
+            }
+
+            let hi = hex_val(self.port.receive());
+            let lo = hex_val(self.port.receive());
+            match (hi, lo) {
+                (Some(hi), Some(lo)) if (hi << 4 | lo) == sum && !overflow => {
+                    self.port.send(b'+');
+                    self.attached = true;
+                    return;
+                }
+                _ => self.port.send(b'-'),
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        let sum = data.iter().fold(0u8, |acc, c| acc.wrapping_add(*c));
+        loop {
+            self.port.send(b'$');
+            data.iter().for_each(|c| self.port.send(*c));
+            self.port.send(b'#');
+            self.port.send(hex_digit(sum >> 4));
+            self.port.send(hex_digit(sum));
+
+            loop {
+                match self.port.receive() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn breakpoint_at(&self, addr: u64) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|bp| bp.addr == addr)
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> bool {
+        if self.breakpoint_at(addr).is_some() {
+            return true;
+        }
+        match read_mem(addr) {
+            Some(orig) if !self.breakpoints.is_full() && write_mem(addr, INT3) => {
+                self.breakpoints.push(Breakpoint { addr, orig });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        match self.breakpoints.iter().position(|bp| bp.addr == addr) {
+            Some(i) => {
+                let bp = self.breakpoints.remove(i);
+                if self.step_over == Some(addr) {
+                    self.step_over = None;
+                }
+                write_mem(bp.addr, bp.orig)
+            }
+            None => false,
+        }
+    }
+
+    fn sync_debug_regs(&self) {
+        let mut addrs = [0; 4];
+        let mut dr7 = 0;
+        for (i, wp) in self.watchpoints.iter().enumerate() {
+            if let Some(wp) = wp {
+                let len = match wp.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                addrs[i] = wp.addr;
+                dr7 |= 1 << (i * 2) | wp.kind.dr7_rw() << (16 + i * 4) | len << (18 + i * 4);
+            }
+        }
+        unsafe { write_debug_regs(addrs, dr7) }
+    }
+
+    fn insert_watchpoint(&mut self, kind: WatchKind, addr: u64, len: u64) -> bool {
+        let len = if kind == WatchKind::Execute { 1 } else { len };
+        if !matches!(len, 1 | 2 | 4 | 8) || addr % len != 0 {
+            return false;
+        }
+        match self.watchpoints.iter_mut().find(|wp| wp.is_none()) {
+            Some(slot) => {
+                *slot = Some(Watchpoint { addr, len, kind });
+                self.sync_debug_regs();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_watchpoint(&mut self, kind: WatchKind, addr: u64) -> bool {
+        match self
+            .watchpoints
+            .iter_mut()
+            .find(|wp| matches!(wp, Some(wp) if wp.addr == addr && wp.kind == kind))
+        {
+            Some(slot) => {
+                *slot = None;
+                self.sync_debug_regs();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove everything the debugger has set up
+    fn detach(&mut self) {
+        while let Some(bp) = self.breakpoints.pop() {
+            write_mem(bp.addr, bp.orig);
+        }
+        self.watchpoints = [None; 4];
+        self.sync_debug_regs();
+        self.step_over = None;
+        self.stepping = false;
+        self.attached = false;
+    }
+
+    /// Handle one packet, returns how to resume once the debugger lets the kernel go
+    fn handle_packet(
+        &mut self,
+        frame: &mut TrapFrame,
+        stop: &str,
+        packet: &str,
+        out: &mut ArrayString<MAX_PACKET>,
+    ) -> Option<Resume> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        match cmd {
+            "?" => out.push_str(stop),
+            "g" => {
+                for n in 0..NUM_REGS {
+                    let val = frame.reg(n).map(|r| *r).unwrap_or(0);
+                    for byte in &val.to_le_bytes()[..reg_size(n)] {
+                        let _ = write!(out, "{:02x}", byte);
+                    }
+                }
+            }
+            "G" => {
+                let mut rest = args;
+                for n in 0..NUM_REGS {
+                    let size = reg_size(n);
+                    match parse_le(rest, size) {
+                        Some(val) if reg_writable(n) => *frame.reg(n).unwrap() = val,
+                        Some(_) => {}
+                        None => break,
+                    }
+                    rest = &rest[size * 2..];
+                }
+                out.push_str("OK");
+            }
+            "p" => match parse_hex(args) {
+                Some(n) if (n as usize) < NUM_REGS => {
+                    let n = n as usize;
+                    let val = frame.reg(n).map(|r| *r).unwrap_or(0);
+                    for byte in &val.to_le_bytes()[..reg_size(n)] {
+                        let _ = write!(out, "{:02x}", byte);
+                    }
+                }
+                _ => out.push_str("E00"),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, val)| {
+                    let n = parse_hex(n)? as usize;
+                    Some((n, parse_le(val, reg_size(n))?))
+                });
+                match parsed {
+                    Some((n, val)) if reg_writable(n) => {
+                        *frame.reg(n).unwrap() = val;
+                        out.push_str("OK");
+                    }
+                    _ => out.push_str("E00"),
+                }
+            }
+            "m" => {
+                let parsed = args
+                    .split_once(',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+                match parsed {
+                    Some((addr, len)) => {
+                        let len = len.min(MAX_PACKET as u64 / 2);
+                        for i in 0..len {
+                            match read_mem(addr.wrapping_add(i)) {
+                                Some(byte) => {
+                                    let _ = write!(out, "{:02x}", byte);
+                                }
+                                None => break,
+                            }
+                        }
+                        if out.is_empty() {
+                            out.push_str("E14");
+                        }
+                    }
+                    None => out.push_str("E00"),
+                }
+            }
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = range.split_once(',')?;
+                    Some((parse_hex(addr)?, parse_hex(len)?, data.as_bytes()))
+                });
+                match parsed {
+                    Some((addr, len, data)) if data.len() as u64 >= len * 2 => {
+                        let ok = (0..len).all(|i| {
+                            let i = i as usize;
+                            match (hex_val(data[i * 2]), hex_val(data[i * 2 + 1])) {
+                                (Some(hi), Some(lo)) => {
+                                    write_mem(addr.wrapping_add(i as u64), hi << 4 | lo)
+                                }
+                                _ => false,
+                            }
+                        });
+                        out.push_str(if ok { "OK" } else { "E14" });
+                    }
+                    _ => out.push_str("E00"),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                return Some(if cmd == "c" {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            "Z" | "z" => {
+                let mut parts = args.splitn(3, ',');
+                let ty = parts.next().and_then(|t| t.bytes().next());
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex).unwrap_or(1);
+                let ok = match (ty, addr) {
+                    (Some(b'0'), Some(addr)) if cmd == "Z" => self.insert_breakpoint(addr),
+                    (Some(b'0'), Some(addr)) => self.remove_breakpoint(addr),
+                    (Some(ty), Some(addr)) => match WatchKind::from_packet(ty) {
+                        Some(kind) if cmd == "Z" => self.insert_watchpoint(kind, addr, len),
+                        Some(kind) => self.remove_watchpoint(kind, addr),
+                        // Unsupported types get an empty reply
+                        None => return None,
+                    },
+                    _ => false,
+                };
+                out.push_str(if ok { "OK" } else { "E22" });
+            }
+            "D" => {
+                self.detach();
+                self.send_packet(b"OK");
+                return Some(Resume::Continue);
+            }
+            "k" => {
+                self.detach();
+                return Some(Resume::Continue);
+            }
+            "H" | "T" => out.push_str("OK"),
+            "q" => match args {
+                a if a.starts_with("Supported") => out.push_str("PacketSize=f00"),
+                "Attached" => out.push_str("1"),
+                "C" => out.push_str("QC1"),
+                "fThreadInfo" => out.push_str("m1"),
+                "sThreadInfo" => out.push_str("l"),
+                _ => {}
+            },
+            _ => {}
+        }
+        None
+    }
+
+    /// Report the stop and serve the debugger until it resumes execution
+    fn enter(&mut self, frame: &mut TrapFrame) {
+        let mut stop = ArrayString::<64>::new();
+
+        match frame.vector {
+            VECTOR_BREAKPOINT => {
+                // `int3` leaves rip after itself
+                if self.breakpoint_at(frame.rip - 1).is_some() {
+                    frame.rip -= 1;
+                }
+            }
+            VECTOR_DEBUG => {
+                let dr6 = read_dr6();
+                unsafe { asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack)) }
+
+                if let Some(addr) = self.step_over.take() {
+                    write_mem(addr, INT3);
+                    if !self.stepping && dr6 & DR6_HIT_MASK == 0 {
+                        // Stepped over a breakpoint on behalf of `c`, keep going
+                        frame.rflags &= !RFLAGS_TF;
+                        return;
+                    }
+                }
+
+                let hit = (0..4)
+                    .filter(|i| dr6 & (1 << i) != 0)
+                    .find_map(|i| self.watchpoints[i]);
+                if let Some(wp) = hit {
+                    let name = match wp.kind {
+                        WatchKind::Execute => None,
+                        WatchKind::Write => Some("watch"),
+                        WatchKind::Read => Some("rwatch"),
+                        WatchKind::Access => Some("awatch"),
+                    };
+                    if let Some(name) = name {
+                        let _ = write!(stop, "T05{}:{:x};", name, wp.addr);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if stop.is_empty() {
+            stop.push_str("S05");
+        }
+
+        self.stepping = false;
+        if self.attached {
+            self.send_packet(stop.as_bytes());
+        }
+
+        let mut packet = ArrayVec::<u8, MAX_PACKET>::new();
+        let mut out = ArrayString::<MAX_PACKET>::new();
+        let resume = loop {
+            self.read_packet(&mut packet);
+            out.clear();
+            let resume = match str::from_utf8(&packet) {
+                Ok(packet) => self.handle_packet(frame, &stop, packet, &mut out),
+                Err(_) => None,
+            };
+            match resume {
+                Some(resume) => break resume,
+                None => self.send_packet(out.as_bytes()),
+            }
+        };
+
+        frame.rflags &= !RFLAGS_TF;
+        if let Resume::Step = resume {
+            frame.rflags |= RFLAGS_TF;
+            self.stepping = true;
+        }
+
+        // Execute the original instruction under a breakpoint, then put the breakpoint back
+        if let Some(bp) = self.breakpoint_at(frame.rip).copied() {
+            write_mem(bp.addr, bp.orig);
+            self.step_over = Some(bp.addr);
+            frame.rflags |= RFLAGS_TF;
+        }
+
+        // Do not trigger an execute watchpoint on the current instruction again
+        frame.rflags |= RFLAGS_RF;
+    }
+}
+
+/// Called by the trampolines in `asm.S` on `#DB` and `#BP`
+#[no_mangle]
+extern "C" fn gdb_handle_trap(frame: &mut TrapFrame) {
+    let mut gdb = GDB.lock();
+    if let Some(gdb) = gdb.as_mut() {
+        gdb.enter(frame);
+        return;
+    }
+    drop(gdb);
+
+    // Without the stub, leave the machine for an external debugger such as QEMU's
+    if frame.vector == VECTOR_BREAKPOINT {
+        info!("Waiting for debugger");
+        unsafe {
+            asm!("2: jmp 2b");
+        }
+    }
+}
+
+/// Entry point to install into the IDT for `#DB`
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(gdb_debug_entry as usize as u64)
+}
+
+/// Entry point to install into the IDT for `#BP`
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(gdb_breakpoint_entry as usize as u64)
+}
+
+/// Start the stub and wait for a debugger if the `gdb` flag is set
+pub fn init() {
+    if !crate::cmdline::has("gdb") {
+        return;
+    }
+
+    *GDB.lock() = Some(Gdb::new());
+    info!("GDB stub waiting on COM2");
+    int3();
+}
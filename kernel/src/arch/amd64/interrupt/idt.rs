@@ -1,7 +1,4 @@
-use core::{
-    arch::{asm, global_asm},
-    sync::atomic::{AtomicU64, Ordering},
-};
+use core::{arch::global_asm, mem::forget, ptr::addr_of};
 
 use lazy_static::lazy_static;
 use log::{error, info};
@@ -13,26 +10,35 @@ use x86_64::{
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable},
         idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-        paging::{PageSize, Size2MiB},
         tss::TaskStateSegment,
     },
     VirtAddr,
 };
 
 use crate::{
-    arch::interrupt::{IntIdx, IntIdx::Timer, PIC_OFFSET},
+    arch::{
+        apic, gdb,
+        interrupt::{timer, IntIdx, IntIdx::Timer, PIC_OFFSET},
+    },
+    mm::stack,
     sync::irq_lock::IRQLocked,
 };
 
-static TIMER_VAL: AtomicU64 = AtomicU64::new(0);
-
-pub static DOUBLE_FAULT_STACK: [u8; Size2MiB::SIZE as usize] = [0; Size2MiB::SIZE as usize];
+/// The double fault handler must not run on the stack which overflowed
+const DOUBLE_FAULT_IST: u16 = 1;
+const DOUBLE_FAULT_STACK_PAGES: u64 = 8;
+const EARLY_STACK_SIZE: usize = 0x4000;
+/// Used until `init_fault_stacks` allocates a guarded stack
+static mut EARLY_DOUBLE_FAULT_STACK: [u8; EARLY_STACK_SIZE] = [0; EARLY_STACK_SIZE];
 
 lazy_static! {
     static ref TSS: IRQLocked<TaskStateSegment> = {
         let mut tss = TaskStateSegment::new();
 
-        tss.interrupt_stack_table[1] = VirtAddr::new(&DOUBLE_FAULT_STACK as *const _ as u64);
+        // Stacks grow down, so the IST entry is the end of the stack
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST as usize] = unsafe {
+            VirtAddr::from_ptr(addr_of!(EARLY_DOUBLE_FAULT_STACK)) + EARLY_STACK_SIZE
+        };
 
         IRQLocked::new(tss)
     };
@@ -55,12 +61,22 @@ lazy_static! {
         set_general_handler!(&mut idt, general_handler);
 
         idt.page_fault.set_handler_fn(page_fault);
-        idt.double_fault.set_handler_fn(double_fault);
-        idt.breakpoint.set_handler_fn(breakpoint);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault)
+                .set_stack_index(DOUBLE_FAULT_IST);
+        }
+        unsafe {
+            idt.debug.set_handler_addr(gdb::debug_entry());
+            idt.breakpoint.set_handler_addr(gdb::breakpoint_entry());
+        }
         idt.general_protection_fault
             .set_handler_fn(general_protection_fault);
         idt[IntIdx::Timer.as_u8() as _].set_handler_fn(timer);
         idt[IntIdx::Keyboard.as_u8() as _].set_handler_fn(keyboard);
+        idt[IntIdx::Rtc.as_u8() as _].set_handler_fn(rtc);
+        idt[IntIdx::TlbShootdown.as_u8() as _].set_handler_fn(tlb_shootdown);
+        idt[IntIdx::ApicSpurious.as_u8() as _].set_handler_fn(apic_spurious);
         idt
     };
 }
@@ -83,8 +99,6 @@ fn load_gdt() {
     }
 }
 
-const PIC_TERM_COUNT: u16 = 5966; // Should fire roughly each 5 ms
-
 /// Initialize GDT, IDT and PIC
 pub fn init_cpu_structures() {
     load_gdt();
@@ -92,21 +106,35 @@ pub fn init_cpu_structures() {
     unsafe {
         let mut pic = PICs.lock();
         pic.initialize();
-        pic.write_masks(!3, !3);
+        // Timer, keyboard and the cascade on the master, RTC on the slave
+        pic.write_masks(!0b111, !0b1);
     }
-    // FIXME
-    // Configure the PIC
 
-    let mut port1 = Port::new(0x43);
-    let mut port2 = Port::new(0x40);
-    unsafe {
-        port1.write(0b00110100u8);
-        port2.write((PIC_TERM_COUNT & 0xff) as u8);
-        port2.write((PIC_TERM_COUNT >> 8) as u8);
+    timer::init_pit();
+}
+
+/// Move the double fault handler to a guarded stack, after the memory manager is up
+pub fn init_fault_stacks() {
+    let stack = stack::alloc("double fault", DOUBLE_FAULT_STACK_PAGES)
+        .expect("No stack for the double fault handler");
+    TSS.lock().interrupt_stack_table[DOUBLE_FAULT_IST as usize] = stack.top();
+    // Kept until shutdown
+    forget(stack);
+}
+
+/// Report faults on a guard page as an overflow of the stack below it
+fn check_stack_overflow() {
+    if let Some(thread) = stack::guard_owner(Cr2::read()) {
+        panic!("Stack overflow in thread {}", thread);
     }
 }
 
 extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFaultErrorCode) {
+    if crate::mm::fault::handle_page_fault(Cr2::read(), code) {
+        return;
+    }
+    check_stack_overflow();
+
     error!("Page fault occured");
     error!("{:#?}", frame);
     error!("Code: {:?}", code);
@@ -115,30 +143,27 @@ extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFault
 }
 
 extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _code: u64) -> ! {
+    // Usually a page fault which could not push its frame on a full stack
+    check_stack_overflow();
+
     error!("Double fault occured");
     error!("{:#?}", frame);
     panic!("Double Fault!")
 }
 
-extern "x86-interrupt" fn breakpoint(_frame: InterruptStackFrame) {
-    info!("Waiting for debugger");
-    unsafe {
-        asm!("2: jmp 2b");
-    }
-}
-
 extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, flag: u64) {
     info!("General Protection Fault: {:#x}", flag);
     info!("{:#?}", frame);
     panic!("GPF");
 }
 
-/// Timer fires each 5 seconds
+/// Timer fires each 5 milliseconds
 extern "x86-interrupt" fn timer(_frame: InterruptStackFrame) {
-    let old = TIMER_VAL.fetch_add(1, Ordering::SeqCst);
-    if (old + 1) % 200 == 0 {
-        info!("TIMER SECOND {}", (old + 1) / 200);
+    let ticks = timer::tick();
+    if ticks % timer::ticks_per_second() == 0 {
+        info!("TIMER SECOND {}", ticks / timer::ticks_per_second());
     }
+    crate::time::wake_sleepers();
     unsafe {
         PICs.lock().notify_end_of_interrupt(IntIdx::Timer.as_u8());
     }
@@ -153,6 +178,21 @@ extern "x86-interrupt" fn keyboard(_frame: InterruptStackFrame) {
     }
 }
 
+extern "x86-interrupt" fn rtc(_frame: InterruptStackFrame) {
+    crate::device::rtc::handle_interrupt();
+    unsafe {
+        PICs.lock().notify_end_of_interrupt(IntIdx::Rtc.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn tlb_shootdown(_frame: InterruptStackFrame) {
+    crate::mm::tlb::serve_shootdown();
+    apic::eoi();
+}
+
+/// Spurious APIC interrupts take no EOI
+extern "x86-interrupt" fn apic_spurious(_frame: InterruptStackFrame) {}
+
 fn general_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
     info!("irq {} Err: {:?}", index, error_code);
     info!("{:?}", stack_frame);
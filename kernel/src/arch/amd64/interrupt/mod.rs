@@ -10,6 +10,10 @@ pub const PIC_OFFSET: u8 = 32;
 pub enum IntIdx {
     Timer = PIC_OFFSET,
     Keyboard,
+    Rtc = PIC_OFFSET + 8,
+    /// IPI asking to invalidate TLB entries
+    TlbShootdown = 0xF0,
+    ApicSpurious = 0xFF,
 }
 
 impl IntIdx {
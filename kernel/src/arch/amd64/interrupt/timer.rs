@@ -0,0 +1,78 @@
+//! The PIT, which drives the monotonic clock, and the TSC
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::port::Port;
+
+/// Input frequency of the PIT in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// Reload value of channel 0, should fire roughly each 5 ms
+pub const PIT_TERM_COUNT: u16 = 5966;
+
+/// Length of the TSC calibration
+const CALIBRATION_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Program channel 0 as a rate generator
+pub fn init_pit() {
+    let mut command = Port::new(0x43);
+    let mut data = Port::new(0x40);
+    unsafe {
+        command.write(0b00110100u8);
+        data.write((PIT_TERM_COUNT & 0xff) as u8);
+        data.write((PIT_TERM_COUNT >> 8) as u8);
+    }
+}
+
+/// Count a timer interrupt, returns the new tick count
+pub fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Timer interrupts per second, rounded
+pub const fn ticks_per_second() -> u64 {
+    (PIT_FREQUENCY + PIT_TERM_COUNT as u64 / 2) / PIT_TERM_COUNT as u64
+}
+
+/// Nanoseconds since the PIT was started
+pub fn nanos_since_boot() -> u64 {
+    (ticks() as u128 * PIT_TERM_COUNT as u128 * 1_000_000_000 / PIT_FREQUENCY as u128) as u64
+}
+
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// TSC ticks per millisecond, counted over `CALIBRATION_MS` of PIT channel 2
+///
+/// Channel 2 is polled through its output bit, so this works with interrupts disabled.
+pub fn measure_tsc() -> u64 {
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let mut command = Port::<u8>::new(0x43);
+    let mut data = Port::<u8>::new(0x42);
+    let mut gate = Port::<u8>::new(0x61);
+    unsafe {
+        let old = gate.read();
+        // Gate on, speaker off
+        gate.write((old & !0x02) | 0x01);
+        // Interrupt on terminal count, the output goes high when the count runs out
+        command.write(0b10110000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        let start = read_tsc();
+        while gate.read() & 0x20 == 0 {}
+        let end = read_tsc();
+        gate.write(old);
+        (end - start) / CALIBRATION_MS
+    }
+}
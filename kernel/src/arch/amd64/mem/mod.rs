@@ -2,8 +2,15 @@ use crate::arch::bit_ops::USIZE_BITS;
 
 use crate::data::misc::Pointable;
 use boot_lib::PHYS_MAP_OFFSET;
-use x86_64::{registers::control::Cr3, structures::paging::OffsetPageTable, VirtAddr};
+use core::arch::asm;
+use x86_64::{
+    instructions::tlb::Pcid,
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PhysFrame},
+    VirtAddr,
+};
 
+pub mod pat;
 pub mod setup;
 
 pub const PAGE_SHIFT: u64 = 12;
@@ -24,12 +31,91 @@ pub const fn pfn_to_page(pfn: u64) -> u64 {
 #[repr(align(0x1000))]
 pub struct PageRepr;
 
+/// The active page table
 pub fn get_pt() -> OffsetPageTable<'static> {
     let (pml4_frame, _) = Cr3::read();
+    pt_for(pml4_frame)
+}
+
+/// The page table with its PML4 in `pml4`, which need not be active
+pub fn pt_for(pml4: PhysFrame) -> OffsetPageTable<'static> {
     unsafe {
         OffsetPageTable::new(
-            pml4_frame.pointer().cast().as_mut(),
+            pml4.pointer().cast().as_mut(),
             VirtAddr::new(PHYS_MAP_OFFSET as _),
         )
     }
 }
+
+/// Load a page table, keeping the TLB entries tagged with `pcid` if `keep_tlb` is set
+///
+/// PCIDs other than 0 require CR4.PCIDE.
+pub unsafe fn switch_pt(pml4: PhysFrame, pcid: Pcid, keep_tlb: bool) {
+    let noflush = if keep_tlb { 1 << 63 } else { 0 };
+    let cr3 = pml4.start_address().as_u64() | pcid.value() as u64 | noflush;
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+}
+
+/// Zero `len` bytes at `ptr` with non-temporal stores, which bypass the cache
+///
+/// Both must be multiples of 64 and `len` nonzero. `movnti` is part of SSE2, which every x86_64
+/// CPU has.
+pub unsafe fn zero_nt(ptr: *mut u8, len: usize) {
+    debug_assert!(ptr as usize % 64 == 0 && len % 64 == 0 && len != 0);
+    asm!(
+        "2:",
+        "movnti [{ptr}], {zero}",
+        "movnti [{ptr} + 8], {zero}",
+        "movnti [{ptr} + 16], {zero}",
+        "movnti [{ptr} + 24], {zero}",
+        "movnti [{ptr} + 32], {zero}",
+        "movnti [{ptr} + 40], {zero}",
+        "movnti [{ptr} + 48], {zero}",
+        "movnti [{ptr} + 56], {zero}",
+        "add {ptr}, 64",
+        "sub {len}, 64",
+        "jnz 2b",
+        "sfence",
+        ptr = inout(reg) ptr => _,
+        len = inout(reg) len => _,
+        zero = in(reg) 0u64,
+        options(nostack)
+    );
+}
+
+/// Copy `len` bytes from `src` to `dst` with non-temporal stores, e.g. to write-combining memory
+///
+/// `len` must be a multiple of 4. Without SSE the widest stores are 8 bytes, which still fill
+/// whole write-combining buffers before they are flushed.
+pub unsafe fn copy_nt(dst: *mut u8, src: *const u8, len: usize) {
+    debug_assert!(len % 4 == 0);
+    let qwords = len / 8;
+    if qwords != 0 {
+        asm!(
+            "2:",
+            "mov {tmp}, [{src}]",
+            "movnti [{dst}], {tmp}",
+            "add {src}, 8",
+            "add {dst}, 8",
+            "dec {n}",
+            "jnz 2b",
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            n = inout(reg) qwords => _,
+            tmp = out(reg) _,
+            options(nostack)
+        );
+    }
+    if len % 8 != 0 {
+        let tail = len - 4;
+        asm!(
+            "mov {tmp:e}, [{src}]",
+            "movnti [{dst}], {tmp:e}",
+            src = in(reg) src.add(tail),
+            dst = in(reg) dst.add(tail),
+            tmp = out(reg) _,
+            options(nostack)
+        );
+    }
+    asm!("sfence", options(nostack, preserves_flags));
+}
@@ -0,0 +1,60 @@
+//! Page attribute table
+//!
+//! The memory type of a page is picked by the PAT, PCD and PWT bits of its entry, which index the
+//! PAT MSR. The first four entries keep their power-on values, so that PCD and PWT mean the same
+//! as without a PAT, and entry 4 is changed to write-combining.
+
+use x86_64::{
+    instructions::tlb::flush_all, registers::model_specific::Msr,
+    structures::paging::PageTableFlags,
+};
+
+use crate::arch::cpu::{self, CpuFeatures};
+
+const IA32_PAT: u32 = 0x277;
+/// WB, WT, UC-, UC, WC, WT, UC-, UC
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+/// The PAT bit of a 4 KiB page entry, where huge entries have the huge page bit
+pub const PTE_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+/// Every bit selecting the memory type of a 4 KiB page
+pub const PTE_CACHE_MASK: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PTE_PAT.bits() | PageTableFlags::NO_CACHE.bits() | PageTableFlags::WRITE_THROUGH.bits(),
+);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    /// Strong uncacheable, for device registers
+    Uncached,
+    /// Writes are buffered and combined, for framebuffers. Uncached without PAT support.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Bits of a 4 KiB page entry selecting this mode
+    pub fn pte_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if cpu::has(CpuFeatures::PAT) => PTE_PAT,
+            CacheMode::Uncached | CacheMode::WriteCombining => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Program the PAT, after `cpu::init`
+pub fn init() {
+    if !cpu::has(CpuFeatures::PAT) {
+        return;
+    }
+
+    unsafe {
+        // No mapping uses entry 4 yet, so nothing cached has to be written back first
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+    }
+    flush_all();
+}
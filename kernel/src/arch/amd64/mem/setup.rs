@@ -3,15 +3,19 @@ use boot_lib::*;
 use log::info;
 
 use x86_64::{
+    registers::control::Cr3,
     structures::paging::{page::PageRange, Page, PhysFrame, Size4KiB},
     VirtAddr,
 };
 
 use crate::{
-    arch::mem::get_pt,
+    arch::{mem::get_pt, PAGE_SIZE},
     mm::{
-        alloc::{init_liballoc, phys::init_phys_alloc_from_mmap, virt::KERNEL_MAP_OFFSET},
-        mapping::unmap_range,
+        alloc::{
+            phys::{dump_memory_map, init_phys_alloc_from_mmap, reclaim_boot_memory},
+            virt::KERNEL_MAP_OFFSET,
+        },
+        mapping::{table_frames, unmap_range},
     },
 };
 
@@ -45,11 +49,43 @@ pub unsafe fn init(args: &mut KernelArgs) {
         &mut (DummyFrameDeallocator()),
     );
 
-     info!("Initializing physical memory allocator");
+    info!("Initializing physical memory allocator");
 
     init_phys_alloc_from_mmap(args.mmap.iter());
 
-    info!("Initializing liballoc");
+    crate::mm::space::init();
+    crate::mm::zero::init();
+    crate::mm::oom::init();
+    #[cfg(feature = "kasan")]
+    crate::mm::kasan::init();
+    #[cfg(feature = "alloc-trace")]
+    crate::mm::alloc::trace::init();
+
+    #[cfg(debug_assertions)]
+    {
+        crate::mm::alloc::phys::self_test();
+        crate::mm::alloc::slab::self_test();
+        crate::mm::alloc::virt::self_test();
+        crate::mm::space::self_test();
+        crate::mm::tlb::self_test();
+        crate::mm::stack::self_test();
+        crate::mm::mmio::self_test();
+        #[cfg(feature = "kasan")]
+        crate::mm::kasan::self_test();
+        #[cfg(feature = "alloc-trace")]
+        crate::mm::alloc::trace::self_test();
+    }
+}
 
-    init_liballoc();
+/// Give loader and boot services memory to the allocator
+///
+/// Must run after the firmware's GDT, the ACPI tables and the framebuffer info were consumed.
+/// The copied firmware PML4 may still point into boot services memory, so every page table
+/// reachable from the kernel's is kept.
+pub unsafe fn reclaim(args: &KernelArgs) {
+    let keep = table_frames(Cr3::read().0);
+    let frames = reclaim_boot_memory(args.mmap.iter(), &keep);
+    info!("Reclaimed {} KiB of boot memory", frames * PAGE_SIZE >> 10);
+
+    dump_memory_map(args.mmap.iter());
 }
@@ -11,10 +11,16 @@ pub use mem::PAGE_SIZE;
 
 use crate::{diag::reinit_with_fb, kernel_main};
 
+pub mod acpi;
+pub mod apic;
 pub mod bit_ops;
+pub mod cpu;
 pub mod debug;
+pub mod efi;
+pub mod gdb;
 pub mod interrupt;
 pub mod mem;
+pub mod power;
 
 #[no_mangle]
 pub unsafe extern "efiapi" fn _start(args: *mut KernelArgs) -> ! {
@@ -23,18 +29,59 @@ pub unsafe extern "efiapi" fn _start(args: *mut KernelArgs) -> ! {
     info!("phobos kernel v{} on x86_64", env!("CARGO_PKG_VERSION"));
     let args = args.as_mut().unwrap();
 
+    crate::cmdline::init(args.cmdline.as_str());
+
     info!("Initializing arch specific structures");
 
+    cpu::init();
+    mem::pat::init();
     interrupt::idt::init_cpu_structures();
+    gdb::init();
 
     info!("Initializing memory manager");
 
     mem::setup::init(args);
+    interrupt::idt::init_fault_stacks();
+    apic::init();
+
+    info!("Initializing UEFI runtime services");
+
+    acpi::init(args.uefi_rst.config_table());
+    efi::init(core::ptr::read(&args.uefi_rst), args.mmap.iter());
+
+    match efi::get_time() {
+        Ok(time) => info!("UEFI time: {}", time),
+        Err(e) => warn!("Could not read UEFI time: {:?}", e),
+    }
+
+    info!("Initializing clocks");
+
+    crate::device::rtc::init();
+    crate::time::init();
 
     info!("Initializing framebuffer");
 
     reinit_with_fb(NonNull::new(args.fb_addr).unwrap(), args.fb_info);
 
+    info!("Reclaiming boot memory");
+
+    mem::setup::reclaim(args);
+
+    info!("Initializing block devices");
+
+    crate::device::ata::init();
+    crate::mm::swap::init();
+    #[cfg(debug_assertions)]
+    crate::mm::swap::self_test();
+
+    let fb = crate::graphics::fb::flush_stats();
+    info!(
+        "Framebuffer: {} flushes, {} KiB written in {} ms",
+        fb.flushes,
+        fb.bytes >> 10,
+        fb.busy.as_millis()
+    );
+
     info!("phobos v{} running on x86_64", env!("CARGO_PKG_VERSION"));
 
     kernel_main()
@@ -0,0 +1,88 @@
+//! Low-level x86 reset and power-off mechanisms
+
+use core::arch::asm;
+
+use x86_64::{
+    instructions::{
+        hlt, interrupts,
+        port::Port,
+        tables::{lidt, DescriptorTablePointer},
+    },
+    VirtAddr,
+};
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 2;
+const KBC_CMD_PULSE_RESET: u8 = 0xFE;
+
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Exit codes for QEMU's `isa-debug-exit` device
+///
+/// QEMU exits with status `(code << 1) | 1`
+#[derive(Debug, Copy, Clone)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Power-off ports of emulators which do not need AML to shut down
+const EMULATOR_POWEROFF: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // QEMU
+    (0xB004, 0x2000), // Bochs and older QEMU
+    (0x4004, 0x3400), // VirtualBox
+];
+
+/// Pulse the CPU reset line through the 8042 keyboard controller
+pub fn kbc_reset() {
+    let mut status = Port::<u8>::new(KBC_STATUS_PORT);
+    unsafe {
+        for _ in 0..0x10000 {
+            if status.read() & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        status.write(KBC_CMD_PULSE_RESET);
+    }
+    // Give the controller some time
+    for _ in 0..0x10000 {
+        unsafe { asm!("pause") }
+    }
+}
+
+/// Reset the CPU by loading an empty IDT and raising an exception
+pub fn triple_fault() -> ! {
+    unsafe {
+        interrupts::disable();
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        });
+        asm!("int3");
+    }
+    halt()
+}
+
+/// Try the power-off ports of known emulators
+pub fn emulator_poweroff() {
+    for (port, value) in EMULATOR_POWEROFF {
+        unsafe { Port::<u16>::new(port).write(value) }
+    }
+}
+
+/// Exit QEMU through the `isa-debug-exit` device
+///
+/// Halts if the device is not present
+pub fn qemu_exit(code: QemuExitCode) -> ! {
+    unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32) }
+    halt()
+}
+
+/// Stop the CPU forever
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        hlt()
+    }
+}
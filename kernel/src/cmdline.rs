@@ -0,0 +1,32 @@
+use arrayvec::ArrayString;
+use boot_lib::KERNEL_CMDLINE_MAX;
+
+use crate::data::late_init::LateInit;
+
+static CMDLINE: LateInit<ArrayString<KERNEL_CMDLINE_MAX>> = LateInit::new();
+
+/// Save the command line passed by the bootloader
+pub fn init(cmdline: &str) {
+    let mut saved = ArrayString::new();
+    saved.push_str(cmdline);
+    CMDLINE.init(saved);
+}
+
+/// The whole command line, empty if it was not passed yet
+pub fn raw() -> &'static str {
+    CMDLINE.try_get().map(|s| s.as_str()).unwrap_or("")
+}
+
+/// Get the value of a `key=value` option
+pub fn get(key: &str) -> Option<&'static str> {
+    raw().split_whitespace().find_map(|opt| {
+        opt.split_once('=')
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    })
+}
+
+/// Check whether a flag without a value is present
+pub fn has(flag: &str) -> bool {
+    raw().split_whitespace().any(|opt| opt == flag)
+}
@@ -29,6 +29,15 @@ impl<T> LateInit<T> {
             true
         }
     }
+
+    /// Get the value if it has already been initialized
+    pub fn try_get(&self) -> Option<&T> {
+        if self.init {
+            Some(unsafe { self.data.assume_init_ref() })
+        } else {
+            None
+        }
+    }
 }
 
 impl<T> Deref for LateInit<T> {
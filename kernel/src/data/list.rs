@@ -12,35 +12,59 @@ pub struct CDLListHead {
 }
 
 impl CDLListHead {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self { node: None }
     }
 
+    /// Push an entry to the front
     pub unsafe fn push(&mut self, new: NonNull<CDLListNode>) {
+        self.push_back(new);
+        self.node = Some(new)
+    }
+
+    /// Push an entry to the back
+    pub unsafe fn push_back(&mut self, new: NonNull<CDLListNode>) {
         match self.node {
             Some(mut node) => {
                 node.as_mut().push_back(new);
             }
             None => {
                 CDLListNode::init(new.cast());
+                self.node = Some(new)
             }
         }
-        self.node = Some(new)
     }
 
+    /// Pop the front entry
     pub fn pop(&mut self) -> Option<NonNull<()>> {
-        match self.node {
-            None => None,
-            Some(mut a) => {
-                unsafe { a.as_mut().remove() }
-                Some(a.cast())
-            }
+        let node = self.node?;
+        unsafe { self.remove(node) }
+        Some(node.cast())
+    }
+
+    /// Pop the back entry
+    pub fn pop_back(&mut self) -> Option<NonNull<()>> {
+        let node = unsafe { self.node?.as_ref().peek_prev_unchecked() };
+        unsafe { self.remove(node) }
+        Some(node.cast())
+    }
+
+    /// Unlink an entry, which must be in this list
+    pub unsafe fn remove(&mut self, mut node: NonNull<CDLListNode>) {
+        if self.node == Some(node) {
+            let next = node.as_ref().peek_unchecked();
+            self.node = if next == node { None } else { Some(next) };
         }
+        node.as_mut().remove()
     }
 
     pub fn peek(&self) -> Option<NonNull<CDLListNode>> {
         self.node
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.node.is_none()
+    }
 }
 
 /// A circular doubly linked list node
@@ -71,21 +95,23 @@ impl CDLListNode {
 
     /// Push an entry next to itself
     #[inline]
-    pub unsafe fn push_next(&mut self, mut ptr: NonNull<CDLListNode>) {
-        *ptr.as_mut() = CDLListNode {
+    pub unsafe fn push_next(&mut self, ptr: NonNull<CDLListNode>) {
+        ptr.as_ptr().write(CDLListNode {
             next: self.next,
             prev: NonNull::from(&*(self as *const _)),
-        };
+        });
+        self.next.as_mut().prev = ptr;
         self.next = ptr;
     }
 
     /// Push an entry before itself
     #[inline]
-    pub unsafe fn push_back(&mut self, mut ptr: NonNull<CDLListNode>) {
-        *ptr.as_mut() = CDLListNode {
+    pub unsafe fn push_back(&mut self, ptr: NonNull<CDLListNode>) {
+        ptr.as_ptr().write(CDLListNode {
             next: NonNull::from(&*(self as *const _)),
             prev: self.prev,
-        };
+        });
+        self.prev.as_mut().next = ptr;
         self.prev = ptr;
     }
 
@@ -95,6 +121,7 @@ impl CDLListNode {
         let popped = self.next;
         unsafe {
             self.next = self.next.as_ref().next;
+            self.next.as_mut().prev = NonNull::from(&*(self as *const _));
         }
         popped.cast()
     }
@@ -114,6 +141,7 @@ impl CDLListNode {
         let popped = self.prev;
         unsafe {
             self.prev = self.prev.as_ref().prev;
+            self.prev.as_mut().next = NonNull::from(&*(self as *const _));
         }
         popped.cast()
     }
@@ -127,15 +155,18 @@ impl CDLListNode {
         }
     }
 
+    /// Unlink this node and zero it, so that memory which was clean stays clean
     #[inline]
     pub fn remove(&mut self) {
         unsafe {
             self.next.as_mut().prev = self.prev;
             self.prev.as_mut().next = self.next;
-        }
-
-        unsafe {
-            *self = core::mem::zeroed();
+            // `NonNull` has no valid zero value, so the bytes are cleared directly
+            core::ptr::write_bytes(
+                self as *mut Self as *mut u8,
+                0,
+                core::mem::size_of::<Self>(),
+            );
         }
     }
 
@@ -0,0 +1,278 @@
+//! ATA disks through the legacy IDE ports
+//!
+//! Transfers are PIO and polled, interrupts are disabled on both channels. That is slow, but it
+//! needs neither DMA buffers nor an interrupt handler, and it works while the allocator is out of
+//! memory. ATAPI drives are skipped.
+
+use alloc::{boxed::Box, format, string::String};
+
+use log::info;
+use x86_64::instructions::port::Port;
+
+use crate::device::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+
+const PRIMARY: (u16, u16) = (0x1F0, 0x3F6);
+const SECONDARY: (u16, u16) = (0x170, 0x376);
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Disables interrupts from the channel
+const CONTROL_NIEN: u8 = 1 << 1;
+const DRIVE_LBA: u8 = 0xE0;
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_FLUSH: u8 = 0xE7;
+const CMD_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Status polls before a command times out
+const POLL_LIMIT: usize = 10_000_000;
+/// Sectors per LBA28 command, the count is 8 bits wide and 0 stands for 256
+const MAX_TRANSFER: u64 = 256;
+/// Sectors per LBA48 command, the count is 16 bits wide and 0 stands for 65536
+const MAX_TRANSFER_EXT: u64 = 65536;
+
+/// Ports of one IDE channel, shared by its two drives
+struct Channel {
+    data: Port<u16>,
+    features: Port<u8>,
+    count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive: Port<u8>,
+    /// Status when read, command when written
+    command: Port<u8>,
+    /// Alternate status when read, device control when written
+    control: Port<u8>,
+}
+
+impl Channel {
+    fn new((io, control): (u16, u16)) -> Self {
+        Self {
+            data: Port::new(io),
+            features: Port::new(io + 1),
+            count: Port::new(io + 2),
+            lba_low: Port::new(io + 3),
+            lba_mid: Port::new(io + 4),
+            lba_high: Port::new(io + 5),
+            drive: Port::new(io + 6),
+            command: Port::new(io + 7),
+            control: Port::new(control),
+        }
+    }
+
+    /// Select `slave` or the master and wait the 400 ns until its status is valid
+    fn select(&mut self, slave: bool, lba_top: u8) {
+        unsafe {
+            self.drive.write(DRIVE_LBA | (slave as u8) << 4 | lba_top);
+            for _ in 0..4 {
+                self.control.read();
+            }
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    fn wait_idle(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Wait until the drive is ready to transfer a sector
+    fn wait_data(&mut self) -> Result<(), BlockError> {
+        let status = self.wait_idle()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn wait_done(&mut self) -> Result<(), BlockError> {
+        match self.wait_idle()? & (STATUS_ERR | STATUS_DF) {
+            0 => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: Channel,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+}
+
+impl AtaDrive {
+    /// Identify the drive, `None` if there is none or it is not an ATA disk
+    fn probe(ports: (u16, u16), slave: bool, name: String) -> Option<Self> {
+        let mut channel = Channel::new(ports);
+        unsafe { channel.control.write(CONTROL_NIEN) };
+        channel.select(slave, 0);
+        unsafe {
+            channel.count.write(0);
+            channel.lba_low.write(0);
+            channel.lba_mid.write(0);
+            channel.lba_high.write(0);
+            channel.command.write(CMD_IDENTIFY);
+        }
+        // Nothing drives the bus
+        if channel.status() == 0 || channel.status() == 0xFF {
+            return None;
+        }
+        channel.wait_idle().ok()?;
+        // ATAPI and SATA signatures
+        if unsafe { channel.lba_mid.read() != 0 || channel.lba_high.read() != 0 } {
+            return None;
+        }
+        channel.wait_data().ok()?;
+
+        let mut id = [0u16; 256];
+        for word in id.iter_mut() {
+            *word = unsafe { channel.data.read() };
+        }
+        let lba48 = id[83] & 1 << 10 != 0;
+        let sectors = if lba48 {
+            id[100..104]
+                .iter()
+                .rev()
+                .fold(0, |acc, w| acc << 16 | *w as u64)
+        } else {
+            (id[61] as u64) << 16 | id[60] as u64
+        };
+
+        Some(Self {
+            name,
+            channel,
+            slave,
+            lba48,
+            sectors,
+        })
+    }
+
+    /// Issue a read or write of `count` sectors at `lba`
+    fn command(&mut self, lba: u64, count: u64, write: bool) {
+        let c = &mut self.channel;
+        unsafe {
+            if self.lba48 {
+                c.select(self.slave, 0);
+                // High bytes first
+                c.count.write((count >> 8) as u8);
+                c.lba_low.write((lba >> 24) as u8);
+                c.lba_mid.write((lba >> 32) as u8);
+                c.lba_high.write((lba >> 40) as u8);
+            } else {
+                c.select(self.slave, (lba >> 24) as u8 & 0xF);
+            }
+            c.features.write(0);
+            c.count.write(count as u8);
+            c.lba_low.write(lba as u8);
+            c.lba_mid.write((lba >> 8) as u8);
+            c.lba_high.write((lba >> 16) as u8);
+            c.command.write(match (write, self.lba48) {
+                (false, false) => CMD_READ,
+                (false, true) => CMD_READ_EXT,
+                (true, false) => CMD_WRITE,
+                (true, true) => CMD_WRITE_EXT,
+            });
+        }
+    }
+
+    fn max_transfer(&self) -> u64 {
+        if self.lba48 {
+            MAX_TRANSFER_EXT
+        } else {
+            MAX_TRANSFER
+        }
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        let max = if self.lba48 { 1 << 48 } else { 1 << 28 };
+        if lba + count > self.sectors.min(max) {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(count)
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = self.check_range(lba, buf.len())?;
+        let mut sectors = buf.chunks_exact_mut(SECTOR_SIZE);
+        let max = self.max_transfer();
+        for start in (0..count).step_by(max as usize) {
+            let n = (count - start).min(max);
+            self.command(lba + start, n, false);
+            for sector in sectors.by_ref().take(n as usize) {
+                self.channel.wait_data()?;
+                for word in sector.chunks_exact_mut(2) {
+                    word.copy_from_slice(&unsafe { self.channel.data.read() }.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = self.check_range(lba, buf.len())?;
+        let mut sectors = buf.chunks_exact(SECTOR_SIZE);
+        let max = self.max_transfer();
+        for start in (0..count).step_by(max as usize) {
+            let n = (count - start).min(max);
+            self.command(lba + start, n, true);
+            for sector in sectors.by_ref().take(n as usize) {
+                self.channel.wait_data()?;
+                for word in sector.chunks_exact(2) {
+                    unsafe {
+                        self.channel
+                            .data
+                            .write(u16::from_le_bytes([word[0], word[1]]))
+                    };
+                }
+            }
+            self.channel.wait_done()?;
+        }
+
+        let flush = if self.lba48 { CMD_FLUSH_EXT } else { CMD_FLUSH };
+        unsafe { self.channel.command.write(flush) };
+        self.channel.wait_done()
+    }
+}
+
+/// Probe the four drives of the legacy channels and register the disks found
+pub fn init() {
+    let mut found = 0;
+    for (i, ports) in [PRIMARY, SECONDARY].iter().enumerate() {
+        for slave in [false, true] {
+            let name = format!("ata{}", i * 2 + slave as usize);
+            if let Some(drive) = AtaDrive::probe(*ports, slave, name) {
+                block::register(Box::new(drive));
+                found += 1;
+            }
+        }
+    }
+    info!("ATA: {} disks", found);
+}
@@ -0,0 +1,93 @@
+//! Block devices
+//!
+//! Disk drivers register the devices they find here. A user such as swap takes a device out of
+//! the list and owns it from then on, so requests never have to be queued.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::convert::TryInto;
+
+use arrayvec::ArrayVec;
+use log::info;
+
+use crate::sync::irq_lock::IRQLocked;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_TABLE: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device
+    OutOfRange,
+    /// The device reported an error
+    Io,
+    /// The device did not respond in time
+    Timeout,
+}
+
+/// A device addressed in `SECTOR_SIZE` byte sectors
+///
+/// Buffers are a whole number of sectors long. Implementations must not allocate, swap uses them
+/// while the allocator is out of memory.
+pub trait BlockDevice: Send {
+    fn name(&self) -> &str;
+
+    /// Size of the device in sectors
+    fn sectors(&self) -> u64;
+
+    fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Returns once the data reached the medium
+    fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+static DEVICES: IRQLocked<Vec<Box<dyn BlockDevice>>> = IRQLocked::new(Vec::new());
+
+pub fn register(dev: Box<dyn BlockDevice>) {
+    info!(
+        "Block device {}: {} MiB",
+        dev.name(),
+        dev.sectors() * SECTOR_SIZE as u64 >> 20
+    );
+    DEVICES.lock().push(dev);
+}
+
+/// Remove the first device `f` accepts from the list and hand it over
+pub fn take(mut f: impl FnMut(&mut dyn BlockDevice) -> bool) -> Option<Box<dyn BlockDevice>> {
+    let mut devices = DEVICES.lock();
+    let i = devices.iter_mut().position(|dev| f(dev.as_mut()))?;
+    Some(devices.remove(i))
+}
+
+/// A primary partition of an MBR partitioned device
+#[derive(Debug, Copy, Clone)]
+pub struct Partition {
+    pub kind: u8,
+    pub start: u64,
+    pub sectors: u64,
+}
+
+/// The primary partitions of `dev`, empty if it has no MBR
+pub fn partitions(dev: &mut dyn BlockDevice) -> Result<ArrayVec<Partition, 4>, BlockError> {
+    let mut mbr = [0; SECTOR_SIZE];
+    dev.read(0, &mut mbr)?;
+
+    let mut res = ArrayVec::new();
+    if u16::from_le_bytes([mbr[510], mbr[511]]) != MBR_SIGNATURE {
+        return Ok(res);
+    }
+    for entry in mbr[MBR_TABLE..MBR_TABLE + 4 * MBR_ENTRY_SIZE].chunks(MBR_ENTRY_SIZE) {
+        let word = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap()) as u64;
+        let part = Partition {
+            kind: entry[4],
+            start: word(8),
+            sectors: word(12),
+        };
+        if part.kind != 0 && part.sectors != 0 && part.start + part.sectors <= dev.sectors() {
+            res.push(part);
+        }
+    }
+    Ok(res)
+}
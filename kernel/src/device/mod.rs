@@ -1,2 +1,5 @@
 pub mod ahci;
+pub mod ata;
+pub mod block;
 pub mod ps2kb;
+pub mod rtc;
@@ -0,0 +1,212 @@
+//! MC146818 CMOS real-time clock
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+use x86_64::instructions::port::Port;
+
+use crate::{sync::irq_lock::IRQLocked, time};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keep NMIs disabled while a register is selected
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_UPDATE_INT: u8 = 0x10;
+const STATUS_B_PERIODIC_INT: u8 = 0x40;
+const STATUS_C_UPDATE: u8 = 0x10;
+const STATUS_C_PERIODIC: u8 = 0x40;
+const HOUR_PM: u8 = 0x80;
+
+/// Periodic interrupt rate, the frequency is `32768 >> (rate - 1)`, 1024 Hz here
+const PERIODIC_RATE: u8 = 6;
+
+static CMOS: IRQLocked<Cmos> = IRQLocked::new(Cmos::new());
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+
+/// Calendar date and time as stored by the RTC, always UTC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Self {
+            index: Port::new(CMOS_INDEX),
+            data: Port::new(CMOS_DATA),
+        }
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            self.index.write(NMI_DISABLE | reg);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        unsafe {
+            self.index.write(NMI_DISABLE | reg);
+            self.data.write(val);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> [u8; 7] {
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+            self.read(REG_CENTURY),
+        ]
+    }
+
+    /// Read the time, retrying until two consecutive reads outside of an update agree
+    fn read_time(&mut self) -> RtcTime {
+        let raw = loop {
+            while self.update_in_progress() {}
+            let first = self.read_raw();
+            while self.update_in_progress() {}
+            if first == self.read_raw() {
+                break first;
+            }
+        };
+        decode(raw, self.read(REG_STATUS_B))
+    }
+}
+
+fn bcd_to_bin(val: u8) -> u8 {
+    (val & 0x0F) + (val >> 4) * 10
+}
+
+fn decode(raw: [u8; 7], status_b: u8) -> RtcTime {
+    let [mut second, mut minute, raw_hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = raw_hour & HOUR_PM != 0;
+    let mut hour = raw_hour & !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_bin(second);
+        minute = bcd_to_bin(minute);
+        hour = bcd_to_bin(hour);
+        day = bcd_to_bin(day);
+        month = bcd_to_bin(month);
+        year = bcd_to_bin(year);
+        century = bcd_to_bin(century);
+    }
+
+    // 12 AM is 0 and 12 PM is 12
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // The century register is not guaranteed to exist
+    let century = if (19..=99).contains(&century) {
+        century as u16
+    } else if year < 70 {
+        20
+    } else {
+        19
+    };
+
+    RtcTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Read the current date and time from the RTC
+pub fn read_time() -> RtcTime {
+    CMOS.lock().read_time()
+}
+
+/// Periodic interrupts received so far
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Update-ended interrupts, i.e. RTC seconds, received so far
+pub fn updates() -> u64 {
+    UPDATES.load(Ordering::Relaxed)
+}
+
+/// Enable the periodic and update-ended interrupts on IRQ 8
+pub fn init() {
+    let mut cmos = CMOS.lock();
+    let a = cmos.read(REG_STATUS_A);
+    cmos.write(REG_STATUS_A, (a & 0xF0) | PERIODIC_RATE);
+    let b = cmos.read(REG_STATUS_B);
+    cmos.write(
+        REG_STATUS_B,
+        b | STATUS_B_PERIODIC_INT | STATUS_B_UPDATE_INT,
+    );
+    // Acknowledge anything pending so that the next interrupt fires
+    cmos.read(REG_STATUS_C);
+    info!(
+        "CMOS RTC in {} {}-hour mode",
+        if b & STATUS_B_BINARY != 0 {
+            "binary"
+        } else {
+            "BCD"
+        },
+        if b & STATUS_B_24_HOUR != 0 { 24 } else { 12 }
+    );
+}
+
+/// Called by the IRQ 8 handler
+///
+/// Must not block or allocate.
+pub(crate) fn handle_interrupt() {
+    let mut cmos = CMOS.lock();
+    let status = cmos.read(REG_STATUS_C);
+
+    if status & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if status & STATUS_C_UPDATE != 0 {
+        UPDATES.fetch_add(1, Ordering::Relaxed);
+        // Right after an update the registers are stable for almost a second
+        let time = decode(cmos.read_raw(), cmos.read(REG_STATUS_B));
+        time::sync_wall_clock(time);
+    }
+}
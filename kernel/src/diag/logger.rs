@@ -9,6 +9,7 @@ use crate::{
     data::late_init::LateInit,
     graphics::{fb::FbDisplay, fbterm::FbTextRender},
     sync::irq_lock::IRQLocked,
+    time::Instant,
 };
 
 pub static GLOBAL_LOGGER: IRQLocked<DefaultLogger> = IRQLocked::new(DefaultLogger::new());
@@ -26,10 +27,13 @@ impl Log for IRQLocked<DefaultLogger> {
 
     fn log(&self, record: &Record) {
         if let Some(mut serial) = SERIAL1.try_lock() {
+            let uptime = Instant::now().since_boot();
             serial
                 .write_fmt(format_args!(
-                    "[{}] {}\n",
+                    "[{} {:>5}.{:03}] {}\n",
                     record.level().as_str().chars().next().unwrap(),
+                    uptime.as_secs(),
+                    uptime.subsec_millis(),
                     record.args()
                 ))
                 .expect("Could not write log message to serial port");
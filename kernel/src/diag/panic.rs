@@ -5,5 +5,5 @@ use log::error;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("{}", info);
-    loop {}
+    crate::power::on_panic()
 }
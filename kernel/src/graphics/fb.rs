@@ -1,56 +1,168 @@
-use alloc::{vec, vec::Vec};
-use core::ptr::NonNull;
+//! Framebuffer
+//!
+//! Drawing goes to a back buffer in RAM, `flush` copies the rows changed since the last flush to
+//! the framebuffer. The framebuffer is mapped write-combining and written with non-temporal
+//! stores, which do not pollute the cache with memory that is never read.
 
+use core::{
+    cmp::{max, min},
+    ops::Range,
+    ptr::NonNull,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use boot_lib::PHYS_MAP_OFFSET;
 use embedded_graphics_core::{
     pixelcolor::Rgb888,
     prelude::{DrawTarget, IntoStorage, Point, PointsIter, RgbColor, Size},
     primitives::Rectangle,
     Pixel,
 };
+use log::warn;
 use uefi::proto::console::gop::ModeInfo;
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    PhysAddr,
+};
 
-use crate::{data::late_init::LateInit, sync::irq_lock::IRQLocked};
+use crate::{
+    arch::mem::copy_nt,
+    data::late_init::LateInit,
+    mm::{
+        alloc::virt::{VAllocFlags, GLOBAL_VM_ALLOC},
+        mmio::{ioremap, CacheMode, MmioRegion},
+    },
+    sync::irq_lock::IRQLocked,
+    time::Stopwatch,
+};
 
 pub static GLOBAL_FB: IRQLocked<LateInit<FbDisplay>> = IRQLocked::new(LateInit::new());
 
+static FLUSHES: AtomicU64 = AtomicU64::new(0);
+static FLUSHED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FLUSH_NANOS: AtomicU64 = AtomicU64::new(0);
+
 pub struct FbDisplay {
     pub mode: ModeInfo,
-    pub buffer: Vec<u32>,
+    /// Back buffer, mapped with huge pages
+    pub buffer: &'static mut [u32],
     pub base: NonNull<u32>,
     pub size: u64,
+    /// Rows changed since the last flush
+    dirty: Range<usize>,
+    /// Write-combining mapping, `None` if the framebuffer is written through the physical map
+    _mapping: Option<MmioRegion>,
 }
 
 impl FbDisplay {
+    /// Take over the framebuffer at `base` in the physical map
     pub fn new(base: NonNull<u32>, mode: ModeInfo) -> Self {
         let size = mode.resolution().1 * mode.stride();
+        let pages = (size as u64 * 4 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+
+        let phys = PhysAddr::new(base.as_ptr() as u64 - PHYS_MAP_OFFSET);
+        let mapping = match ioremap(phys, size * 4, CacheMode::WriteCombining) {
+            Ok(region) => Some(region),
+            Err(e) => {
+                warn!("Could not map the framebuffer write-combining: {:?}", e);
+                None
+            }
+        };
+        let base = mapping
+            .as_ref()
+            .map_or(base, |m| NonNull::new(m.as_ptr().cast()).unwrap());
+
+        let buffer = GLOBAL_VM_ALLOC
+            .lock()
+            .alloc(
+                pages,
+                VAllocFlags::RESERVE | VAllocFlags::HUGE,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::GLOBAL
+                    | PageTableFlags::NO_EXECUTE,
+            )
+            .expect("No address space for the back buffer");
+
         Self {
             size: size as u64,
             base,
-            buffer: vec![0; size],
+            buffer: unsafe {
+                slice::from_raw_parts_mut(buffer.start.start_address().as_mut_ptr(), size)
+            },
             mode,
+            dirty: 0..0,
+            _mapping: mapping,
         }
     }
 
+    fn mark_all(&mut self) {
+        self.dirty = 0..self.mode.resolution().1;
+    }
+
+    /// Copy the changed rows to the framebuffer
     pub fn flush(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+
+        let start = Stopwatch::start();
+        let stride = self.mode.stride();
+        let offset = self.dirty.start * stride;
+        let len = self.dirty.len() * stride * 4;
         unsafe {
-            self.base
-                .as_ptr()
-                .copy_from_nonoverlapping(self.buffer.as_slice().as_ptr(), self.size as _);
+            copy_nt(
+                self.base.as_ptr().add(offset).cast(),
+                self.buffer.as_ptr().add(offset).cast(),
+                len,
+            );
         }
+        self.dirty = 0..0;
+
+        FLUSHES.fetch_add(1, Ordering::Relaxed);
+        FLUSHED_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+        FLUSH_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
     }
 
     pub fn scroll_up(&mut self, height: usize, bg: Rgb888) {
         let high = self.mode.stride() * height;
         let low = self.mode.stride() * self.mode.resolution().1;
         self.buffer[0..(high - 1)].fill(bg.into_storage());
-        self.buffer.copy_within(high..low, 0)
+        self.buffer.copy_within(high..low, 0);
+        self.mark_all();
     }
 
     pub fn fill(&mut self, color: Rgb888) {
         self.buffer.fill(color.into_storage());
+        self.mark_all();
     }
 
     pub fn write(&mut self, pos: usize, color: Rgb888) {
         self.buffer[pos] = color.into_storage();
+
+        let row = pos / self.mode.stride();
+        self.dirty = if self.dirty.is_empty() {
+            row..row + 1
+        } else {
+            min(self.dirty.start, row)..max(self.dirty.end, row + 1)
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FlushStats {
+    pub flushes: u64,
+    /// Bytes written to the framebuffer
+    pub bytes: u64,
+    pub busy: Duration,
+}
+
+pub fn flush_stats() -> FlushStats {
+    FlushStats {
+        flushes: FLUSHES.load(Ordering::Relaxed),
+        bytes: FLUSHED_BYTES.load(Ordering::Relaxed),
+        busy: Duration::from_nanos(FLUSH_NANOS.load(Ordering::Relaxed)),
     }
 }
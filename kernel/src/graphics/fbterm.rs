@@ -1,8 +1,6 @@
-use alloc::string::{String, ToString};
 use core::{
     default::Default,
     fmt::{Arguments, Write},
-    iter::{repeat, FromIterator},
     ops::{Deref, DerefMut},
 };
 
@@ -47,9 +45,20 @@ impl FbTextRender {
         }
     }
 
+    /// Formats straight to the screen, so that logging works with the heap exhausted
     pub fn write_fmt_colored(&mut self, args: Arguments<'_>, color: Rgb888) -> core::fmt::Result {
-        let str = args.to_string();
-        self.write_str_colored(&str, color)
+        struct Colored<'a>(&'a mut FbTextRender, Rgb888);
+
+        impl Write for Colored<'_> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                s.chars()
+                    .try_for_each(|ch| self.0.write_char_colored_impl(ch, self.1))
+            }
+        }
+
+        Colored(self, color).write_fmt(args)?;
+        GLOBAL_FB.lock().flush();
+        Ok(())
     }
 
     pub fn write_str_colored(&mut self, string: &str, color: Rgb888) -> core::fmt::Result {
@@ -73,10 +82,9 @@ impl FbTextRender {
                 '\n' => self.advance_line(&mut **GLOBAL_FB.lock()),
                 '\r' => self.current_pos.0 = 0,
                 '\t' => {
-                    self.write_str_colored(
-                        &String::from_iter(repeat(' ').take(TAB_SIZE as usize)),
-                        color,
-                    )?;
+                    for _ in 0..TAB_SIZE {
+                        self.draw_char(' ', color)
+                    }
                 }
                 _ => {}
             }
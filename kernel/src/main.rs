@@ -1,10 +1,11 @@
-#![feature(default_alloc_error_handler)]
+#![feature(alloc_error_handler)]
 #![feature(int_log)]
 #![feature(allocator_api)]
 #![feature(abi_efiapi)]
 #![feature(asm_const)]
 #![feature(abi_x86_interrupt)]
 #![feature(exclusive_range_pattern)]
+#![cfg_attr(feature = "kasan", feature(no_sanitize))]
 #![no_std]
 #![no_main]
 
@@ -14,7 +15,7 @@ extern crate core;
 use alloc::string::{String, ToString};
 use core::arch::asm;
 
-use log::info;
+use log::{info, warn};
 
 use crate::{
     device::ps2kb::print_keypresses,
@@ -28,6 +29,8 @@ mod diag;
 mod arch;
 /// Auxillary code, can be useful
 mod aux;
+/// Kernel command line
+mod cmdline;
 /// Data structures
 mod data;
 /// Drivers
@@ -40,15 +43,26 @@ mod graphics;
 mod io;
 /// Memory manager
 mod mm;
+/// Reboot, shutdown and panic policy
+mod power;
 /// Synchronisation primitives
 mod sync;
 /// Async and cooperative multitasking
 mod task;
+/// Wall-clock and monotonic time
+mod time;
 
 pub fn kernel_main() -> ! {
     info!("Starting main kernel loop");
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(print_keypresses()));
+    // The executor is empty, so these cannot fail
+    executor.spawn(Task::new(print_keypresses())).unwrap();
+    executor.spawn(Task::idle(mm::zero::zero_pages())).unwrap();
+    if let Some(period) = mm::info::period() {
+        if let Err(e) = executor.spawn(Task::new(mm::info::log_periodically(period))) {
+            warn!("Not logging memory usage: {:?}", e);
+        }
+    }
     executor.run()
 }
@@ -1,101 +1,81 @@
-use crate::{
-    data::{misc::Pointable},
-    mm::alloc::{
-        setup::{BumpAlloc},
-        virt::{VAllocFlags, GLOBAL_VM_ALLOC},
-    },
-};
-use alloc::vec;
 use core::alloc::{GlobalAlloc, Layout};
-use liballoc::LiballocAllocator;
-use spin::mutex::{SpinMutex, SpinMutexGuard};
-use x86_64::{
-    structures::paging::{frame::PhysFrameRange, PageTableFlags, PhysFrame, Size4KiB},
-    VirtAddr,
-};
-
-use crate::{data::misc::Global, sync::irq_lock::IRQLocked};
 
 pub mod phys;
 pub mod setup;
-mod stack;
+pub mod slab;
+#[cfg(feature = "alloc-trace")]
+pub mod trace;
 pub mod virt;
 
-unsafe trait SinglePageAllocator {
-    fn alloc_frame(&mut self) -> PhysFrame<Size4KiB>;
-    unsafe fn free_page(&mut self, ptr: PhysFrame<Size4KiB>);
-    unsafe fn init(&mut self, range: PhysFrameRange, exclude: &[PhysFrameRange]);
-}
-
-enum AllocState {
-    None,
-    BootMem(BumpAlloc),
-    Default,
-}
-
-#[derive(Debug)]
+/// Physical memory managed by the allocator, in pages
+#[derive(Debug, Default, Copy, Clone)]
 pub struct AllocatorStats {
     pub total: usize,
     pub allocated: usize,
     pub user: usize,
 }
 
-impl AllocatorStats {
-    pub fn new() -> AllocatorStats {
-        AllocatorStats {
-            total: 0,
-            allocated: 0,
-            user: 0,
-        }
-    }
-}
-
 #[global_allocator]
-static GLOBAL_ALLOC: IRQLocked<GlobalAllocator> =
-    IRQLocked::new(GlobalAllocator(Global::new(AllocState::None)));
+static GLOBAL_ALLOC: GlobalAllocator = GlobalAllocator;
 
-pub struct GlobalAllocator(Global<AllocState>);
+/// Forwards to the slab allocator, or to the address sanitizer which wraps it
+///
+/// With `alloc-trace` every live allocation is also recorded in `trace`.
+pub struct GlobalAllocator;
 
-static GLOBAL_LIB_ALLOC_LOCK: SpinMutex<()> = SpinMutex::new(());
+unsafe impl GlobalAlloc for GlobalAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = backend::alloc(layout, false);
+        #[cfg(feature = "alloc-trace")]
+        trace::record(ptr, layout.size());
+        ptr
+    }
 
-pub unsafe fn init_liballoc() {
-    // TODO AllocType
-    liballoc::init(
-        || {
-            SpinMutexGuard::leak(GLOBAL_LIB_ALLOC_LOCK.lock());
-            true
-        },
-        || {
-            GLOBAL_LIB_ALLOC_LOCK.force_unlock();
-            true
-        },
-        move |count| {
-            GLOBAL_VM_ALLOC
-                .lock()
-                .alloc(
-                    count as _,
-                    VAllocFlags::COMMIT,
-                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                )
-                .map(|x| x.start.pointer())
-        },
-        |ptr, count| {
-            GLOBAL_VM_ALLOC
-                .lock()
-                .free(VirtAddr::from_pointer(ptr), count as _);
-            true
-        },
-    );
-    // test
-    let _ = vec![0u32; 100];
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-trace")]
+        trace::forget(ptr);
+        backend::free(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = backend::alloc(layout, true);
+        #[cfg(feature = "alloc-trace")]
+        trace::record(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = backend::realloc(ptr, layout, new_size);
+        #[cfg(feature = "alloc-trace")]
+        if !new.is_null() {
+            trace::forget(ptr);
+            trace::record(new, new_size);
+        }
+        new
+    }
 }
 
-unsafe impl GlobalAlloc for IRQLocked<GlobalAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        LiballocAllocator.alloc(layout)
+#[cfg(not(feature = "kasan"))]
+mod backend {
+    use super::slab;
+    use core::alloc::Layout;
+
+    pub unsafe fn alloc(layout: Layout, zeroed: bool) -> *mut u8 {
+        if zeroed {
+            slab::kmalloc_zeroed(layout)
+        } else {
+            slab::kmalloc(layout)
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        LiballocAllocator.dealloc(ptr, layout)
+    pub unsafe fn free(ptr: *mut u8, layout: Layout) {
+        slab::kfree(ptr, layout)
+    }
+
+    pub unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        slab::krealloc(ptr, layout, new_size)
     }
 }
+
+#[cfg(feature = "kasan")]
+use crate::mm::kasan as backend;
@@ -1,88 +1,695 @@
+//! Physical memory allocation
+//!
+//! Frames are managed by a buddy allocator, split into zones by physical address because some
+//! devices can only reach low memory. Free blocks are linked through the physical map and
+//! described in the frame database. Blocks known to be zeroed are kept at the front of each free
+//! list and dirty ones at the back, the clean ones are refilled in the background by `mm::zero`.
+
+#[cfg(feature = "kasan")]
+use crate::mm::kasan;
 use crate::{
     arch::{
+        mem::{page_to_pfn, pfn_to_page, PAGE_SHIFT},
         PAGE_SIZE,
     },
     data::{
-        list::{SLListNode},
+        list::{CDLListHead, CDLListNode},
         misc::Pointable,
     },
+    mm::{
+        frame::{self, FrameFlags, FrameInfo},
+        oom, zero,
+    },
     sync::irq_lock::IRQLocked,
 };
-use boot_lib::PHYS_MAP_OFFSET;
-use core::ptr::NonNull;
-use log::info;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use boot_lib::{
+    KERNEL_ARGS_MEM_TYPE, KERNEL_MEM_TYPE_RANGE_START, KERNEL_RO_MEM_TYPE, KERNEL_RWX_MEM_TYPE,
+    KERNEL_RW_MEM_TYPE, KERNEL_RX_MEM_TYPE, KERNEL_STACK_MEM_TYPE, PTE_MEM_TYPE,
+};
+use core::{
+    cmp::min,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::{debug, info};
 
 use uefi::table::boot::{MemoryDescriptor, MemoryType};
-use x86_64::structures::paging::{
-    FrameAllocator, PageSize, PhysFrame, Size4KiB,
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
 };
 
-pub static GLOBAL_PHYS_ALLOC: IRQLocked<LinkedListAllocator> =
-    IRQLocked::new(LinkedListAllocator::new());
+/// Blocks are at most `2^(MAX_ORDER - 1)` pages, i.e. 4 MiB
+pub const MAX_ORDER: usize = 11;
+
+pub static GLOBAL_PHYS_ALLOC: IRQLocked<BuddyAllocator> = IRQLocked::new(BuddyAllocator::new());
+
+/// Physical address ranges, each a superset of the previous one
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16 MiB, for ISA DMA
+    Dma,
+    /// Below 4 GiB, for 32-bit DMA
+    Dma32,
+    /// Anywhere
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// First frame past the zone, zone boundaries are aligned to the largest block
+    const fn end_pfn(self) -> u64 {
+        match self {
+            Zone::Dma => (16 << 20) >> PAGE_SHIFT,
+            Zone::Dma32 => (4 << 30) >> PAGE_SHIFT,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    fn of(pfn: u64) -> Zone {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|z| pfn < z.end_pfn())
+            .unwrap_or(Zone::Normal)
+    }
+}
+
+struct FreeArea {
+    list: CDLListHead,
+    count: u64,
+}
+
+impl FreeArea {
+    const EMPTY: FreeArea = FreeArea {
+        list: CDLListHead::new(),
+        count: 0,
+    };
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ZoneStats {
+    pub total: u64,
+    pub free: u64,
+    pub clean: u64,
+}
+
+struct ZoneAlloc {
+    free: [FreeArea; MAX_ORDER],
+    stats: ZoneStats,
+}
+
+impl ZoneAlloc {
+    const EMPTY: ZoneAlloc = ZoneAlloc {
+        free: [FreeArea::EMPTY; MAX_ORDER],
+        stats: ZoneStats {
+            total: 0,
+            free: 0,
+            clean: 0,
+        },
+    };
+}
+
+pub struct BuddyAllocator {
+    zones: [ZoneAlloc; 3],
+}
 
-unsafe impl Send for LinkedListAllocator {}
+fn block_node(pfn: u64) -> NonNull<CDLListNode> {
+    NonNull::new(pfn_to_page(pfn) as *mut CDLListNode).unwrap()
+}
 
-pub struct LinkedListAllocator {
-    pub dirty: SLListNode,
-    pub clean: SLListNode,
+fn frame_of(pfn: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(pfn << PAGE_SHIFT))
 }
 
-impl LinkedListAllocator {
+impl BuddyAllocator {
     pub const fn new() -> Self {
         Self {
-            dirty: SLListNode { next: None },
-            clean: SLListNode { next: None },
+            zones: [ZoneAlloc::EMPTY; 3],
         }
     }
 
-    pub fn get_clean(&mut self) -> Option<NonNull<u8>> {
-        if let Some(clean) = self.clean.pop() {
-            return Some(clean);
+    fn info(pfn: u64) -> &'static FrameInfo {
+        frame::get(pfn).unwrap_or_else(|| panic!("Frame {:#x} is not managed", pfn << PAGE_SHIFT))
+    }
+
+    unsafe fn push_block(&mut self, pfn: u64, order: usize, clean: bool) {
+        let info = Self::info(pfn);
+        info.set_flags(if clean {
+            FrameFlags::FREE | FrameFlags::ZERO
+        } else {
+            FrameFlags::FREE
+        });
+        info.set_order(order);
+        info.set_refcount(0);
+
+        #[cfg(feature = "kasan")]
+        kasan::poison_free_block(pfn << PAGE_SHIFT, order);
+
+        let zone = &mut self.zones[Zone::of(pfn) as usize];
+        let area = &mut zone.free[order];
+        if clean {
+            area.list.push(block_node(pfn));
+            zone.stats.clean += 1 << order;
+        } else {
+            area.list.push_back(block_node(pfn));
+        }
+        area.count += 1;
+        zone.stats.free += 1 << order;
+    }
+
+    /// Take a free block off its list, the list node in it is zeroed
+    unsafe fn unlink_block(&mut self, pfn: u64, order: usize) -> bool {
+        let info = Self::info(pfn);
+        let clean = info.flags().contains(FrameFlags::ZERO);
+        info.set_flags(FrameFlags::empty());
+
+        let zone = &mut self.zones[Zone::of(pfn) as usize];
+        let area = &mut zone.free[order];
+        area.list.remove(block_node(pfn));
+        area.count -= 1;
+        zone.stats.free -= 1 << order;
+        if clean {
+            zone.stats.clean -= 1 << order;
+        }
+        clean
+    }
+
+    /// Pop a block of exactly `order`, preferring clean ones if `clean` is set
+    fn take_block(&mut self, zone: Zone, order: usize, clean: bool) -> Option<(u64, bool)> {
+        let list = &self.zones[zone as usize].free[order].list;
+        let node = list.peek()?;
+        let node = if clean {
+            node
+        } else {
+            unsafe { node.as_ref().peek_prev_unchecked() }
+        };
+
+        let pfn = page_to_pfn(node.as_ptr() as u64);
+        let is_clean = unsafe { self.unlink_block(pfn, order) };
+        Some((pfn, is_clean))
+    }
+
+    /// Allocate `2^order` contiguous frames aligned to their size from `zone` or a lower one
+    pub fn alloc(&mut self, order: usize, zone: Zone, zeroed: bool) -> Option<PhysFrame> {
+        if order >= MAX_ORDER {
+            return None;
+        }
+
+        for &z in Zone::ALL[..=zone as usize].iter().rev() {
+            for o in order..MAX_ORDER {
+                if let Some((pfn, clean)) = self.take_block(z, o, zeroed) {
+                    // Give back the upper halves until the block has the right size
+                    for split in (order..o).rev() {
+                        unsafe { self.push_block(pfn + (1 << split), split, clean) }
+                    }
+
+                    #[cfg(feature = "kasan")]
+                    kasan::unpoison_block(pfn << PAGE_SHIFT, order);
+
+                    if zeroed && !clean {
+                        unsafe {
+                            (pfn_to_page(pfn) as *mut u8)
+                                .write_bytes(0, (PAGE_SIZE as usize) << order)
+                        }
+                        zero::note_sync(1 << order);
+                    }
+
+                    for p in pfn..pfn + (1 << order) {
+                        Self::info(p).reset();
+                    }
+
+                    if self.clean_pages() < zero::low_watermark() {
+                        zero::kick();
+                    }
+
+                    return Some(frame_of(pfn));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Free a block allocated with `order`, `clean` if it is known to be zeroed
+    pub unsafe fn free(&mut self, frame: PhysFrame, order: usize, clean: bool) {
+        let mut pfn = page_to_pfn(frame.start_address().as_u64());
+        let mut order = order;
+        let mut clean = clean;
+
+        let flags = Self::info(pfn).flags();
+        assert!(
+            !flags.contains(FrameFlags::FREE),
+            "Double free of frame {:#x}",
+            frame.start_address().as_u64()
+        );
+        assert!(
+            !flags.contains(FrameFlags::PINNED),
+            "Free of pinned frame {:#x}",
+            frame.start_address().as_u64()
+        );
+
+        for p in pfn..pfn + (1 << order) {
+            let info = Self::info(p);
+            info.set_flags(FrameFlags::empty());
+            info.set_refcount(0);
+        }
+
+        // Zones are aligned to the largest block, so buddies are always in the same zone
+        while order < MAX_ORDER - 1 {
+            let buddy = pfn ^ (1 << order);
+            let entry = match frame::get(buddy) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if !entry.flags().contains(FrameFlags::FREE) || entry.order() != order {
+                break;
+            }
+
+            clean &= self.unlink_block(buddy, order);
+            pfn &= !(1 << order);
+            order += 1;
+        }
+
+        self.push_block(pfn, order, clean);
+    }
+
+    /// Free every frame in `[start, end)` as the largest possible blocks
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut pfn = start;
+        while pfn < end {
+            let mut order = min(pfn.trailing_zeros() as usize, MAX_ORDER - 1);
+            while pfn + (1 << order) > end {
+                order -= 1;
+            }
+
+            self.zones[Zone::of(pfn) as usize].stats.total += 1 << order;
+            self.free(frame_of(pfn), order, false);
+            pfn += 1 << order;
         }
-        if let Some(dirty) = self.dirty.pop() {
-            unsafe {
-                clear_page(dirty);
+    }
+
+    /// Take the largest dirty free block of at most `max_order` out of the allocator
+    ///
+    /// The block is not marked as allocated, it must be given back with `free`.
+    pub fn take_dirty(&mut self, max_order: usize) -> Option<(PhysFrame, usize)> {
+        for &z in Zone::ALL.iter().rev() {
+            for order in (0..=max_order.min(MAX_ORDER - 1)).rev() {
+                let node = match self.zones[z as usize].free[order].list.peek() {
+                    Some(node) => node,
+                    None => continue,
+                };
+                let last = unsafe { node.as_ref().peek_prev_unchecked() };
+                let pfn = page_to_pfn(last.as_ptr() as u64);
+                if !Self::info(pfn).flags().contains(FrameFlags::ZERO) {
+                    unsafe { self.unlink_block(pfn, order) };
+                    return Some((frame_of(pfn), order));
+                }
             }
-            return Some(dirty);
         }
+
         None
     }
+
+    /// Free pages known to be zeroed, in all zones
+    pub fn clean_pages(&self) -> u64 {
+        self.zones.iter().map(|z| z.stats.clean).sum()
+    }
+
+    /// Free pages in all zones
+    pub fn free_pages(&self) -> u64 {
+        self.zones.iter().map(|z| z.stats.free).sum()
+    }
+
+    /// A zeroed page in the physical map
+    pub fn get_clean(&mut self) -> Option<NonNull<u8>> {
+        self.alloc(0, Zone::Normal, true).map(|f| f.pointer())
+    }
+
+    pub unsafe fn free_page(&mut self, frame: PhysFrame) {
+        self.free(frame, 0, false)
+    }
+
+    /// Free a single frame whose refcount has dropped to zero
+    pub unsafe fn free_unreferenced(&mut self, frame: PhysFrame) {
+        let info = frame::of(frame);
+        assert_eq!(info.map_count(), 0, "Freeing a frame which is still mapped");
+        let clean = info.flags().contains(FrameFlags::ZERO);
+        self.free(frame, 0, clean)
+    }
+
+    pub fn stats(&self, zone: Zone) -> ZoneStats {
+        self.zones[zone as usize].stats
+    }
+
+    /// Free blocks of each order in `zone`
+    pub fn free_blocks(&self, zone: Zone) -> [u64; MAX_ORDER] {
+        let mut res = [0; MAX_ORDER];
+        for (i, area) in self.zones[zone as usize].free.iter().enumerate() {
+            res[i] = area.count;
+        }
+        res
+    }
+}
+
+static RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+pub const MAX_MEMORY_TYPES: usize = 32;
+
+/// Pages of each memory type in the firmware's map
+static PAGES_BY_TYPE: IRQLocked<ArrayVec<(MemoryType, u64), MAX_MEMORY_TYPES>> =
+    IRQLocked::new(ArrayVec::new_const());
+
+/// As many as the bootloader passes memory map entries
+const MAX_RAM_RANGES: usize = 512;
+
+/// Frames of RAM in the firmware's map as `(first pfn, end pfn)`, adjacent ranges merged
+static RAM_RANGES: IRQLocked<ArrayVec<(u64, u64), MAX_RAM_RANGES>> =
+    IRQLocked::new(ArrayVec::new_const());
+
+/// RAM which the allocator eventually manages
+fn is_usable(ty: MemoryType) -> bool {
+    ty == MemoryType::CONVENTIONAL || is_reclaimable(ty)
+}
+
+/// Still in use during early boot, freed by `reclaim_boot_memory`
+fn is_reclaimable(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+    )
+}
+
+/// RAM owned by the kernel, now or once boot memory is reclaimed
+fn is_ram(ty: MemoryType) -> bool {
+    is_usable(ty) || ty.0 >= KERNEL_MEM_TYPE_RANGE_START
+}
+
+/// Whether a frame in `[first, end)` is RAM by its type in the firmware's memory map
+///
+/// Unlike the frame database this also covers loader and boot services memory before it is
+/// reclaimed, which is reserved until then.
+pub fn contains_ram(first: u64, end: u64) -> bool {
+    RAM_RANGES.lock().iter().any(|&(s, e)| s < end && first < e)
 }
 
 pub fn init_phys_alloc_from_mmap<'a, T>(mmap: T)
+where
+    T: IntoIterator<Item = &'a MemoryDescriptor> + Clone,
+{
+    let mut g_all = GLOBAL_PHYS_ALLOC.lock();
+
+    let max_pfn = mmap
+        .clone()
+        .into_iter()
+        .filter(|d| is_usable(d.ty))
+        .map(|d| page_to_pfn(d.phys_start) + d.page_count)
+        .max()
+        .expect("No usable memory");
+    let map_pages = frame::db_pages(max_pfn);
+
+    let map_start = mmap
+        .clone()
+        .into_iter()
+        .find(|d| d.ty == MemoryType::CONVENTIONAL && d.page_count >= map_pages)
+        .expect("No room for the frame database")
+        .phys_start;
+
+    unsafe { frame::init(PhysAddr::new(map_start), max_pfn) };
+
+    let mut by_type = PAGES_BY_TYPE.lock();
+    for d in mmap.clone() {
+        match by_type.iter_mut().find(|(ty, _)| *ty == d.ty) {
+            Some(entry) => entry.1 += d.page_count,
+            None => {
+                let _ = by_type.try_push((d.ty, d.page_count));
+            }
+        }
+    }
+    drop(by_type);
+
+    let mut ram = RAM_RANGES.lock();
+    for d in mmap.clone().into_iter().filter(|d| is_ram(d.ty)) {
+        let start = page_to_pfn(d.phys_start);
+        let end = start + d.page_count;
+        match ram.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ram.push((start, end)),
+        }
+    }
+    drop(ram);
+
+    let map_start_pfn = page_to_pfn(map_start);
+    let map_end_pfn = map_start_pfn + map_pages;
+
+    // Loader and boot services memory stays reserved until `reclaim_boot_memory`
+    for d in mmap
+        .into_iter()
+        .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+    {
+        let start = page_to_pfn(d.phys_start);
+        let end = start + d.page_count;
+        unsafe {
+            if start < map_start_pfn.min(end) {
+                g_all.add_range(start, map_start_pfn.min(end));
+            }
+            if map_end_pfn.max(start) < end {
+                g_all.add_range(map_end_pfn.max(start), end);
+            }
+        }
+    }
+
+    for zone in Zone::ALL {
+        let stats = g_all.stats(zone);
+        info!(
+            "Zone {:?}: {} MiB, {} MiB free",
+            zone,
+            stats.total * PAGE_SIZE >> 20,
+            stats.free * PAGE_SIZE >> 20
+        );
+    }
+}
+
+/// Free loader and boot services memory, returns the number of frames reclaimed
+///
+/// Call once the firmware tables and everything the bootloader left behind are no longer used.
+/// Frames in `keep` stay reserved, e.g. firmware page tables the kernel still walks through.
+pub unsafe fn reclaim_boot_memory<'a, T>(mmap: T, keep: &[PhysFrame]) -> u64
 where
     T: IntoIterator<Item = &'a MemoryDescriptor>,
 {
+    assert!(
+        !RECLAIMED.swap(true, Ordering::AcqRel),
+        "Boot memory reclaimed twice"
+    );
+
+    let mut keep: Vec<u64> = keep
+        .iter()
+        .map(|f| page_to_pfn(f.start_address().as_u64()))
+        .collect();
+    keep.sort_unstable();
+
     let mut g_all = GLOBAL_PHYS_ALLOC.lock();
-    for i in mmap {
-        match i.ty {
-            MemoryType::CONVENTIONAL
-            | MemoryType::BOOT_SERVICES_CODE
-            | MemoryType::BOOT_SERVICES_DATA
-            | MemoryType::LOADER_CODE
-            | MemoryType::LOADER_DATA => unsafe {
-                for j in 0..i.page_count {
-                    g_all.dirty.push(NonNull::new_unchecked(
-                        (PHYS_MAP_OFFSET + i.phys_start + j * Size4KiB::SIZE) as *mut _,
-                    ))
-                }
-            },
-            _ => {}
+    let mut reclaimed = 0;
+
+    for d in mmap.into_iter().filter(|d| is_reclaimable(d.ty)) {
+        let start = page_to_pfn(d.phys_start);
+        let end = start + d.page_count;
+
+        let mut pfn = start;
+        for &k in keep.iter().filter(|&&k| start <= k && k < end) {
+            if pfn < k {
+                g_all.add_range(pfn, k);
+                reclaimed += k - pfn;
+            }
+            pfn = pfn.max(k + 1);
+        }
+        if pfn < end {
+            g_all.add_range(pfn, end);
+            reclaimed += end - pfn;
         }
     }
+
+    reclaimed
+}
+
+fn describe(ty: MemoryType) -> &'static str {
+    match ty {
+        MemoryType::CONVENTIONAL => "free",
+        t if is_reclaimable(t) => {
+            if RECLAIMED.load(Ordering::Acquire) {
+                "reclaimed"
+            } else {
+                "boot"
+            }
+        }
+        MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => "runtime",
+        MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => "acpi",
+        MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => "mmio",
+        MemoryType(KERNEL_RX_MEM_TYPE) => "kernel code",
+        MemoryType(KERNEL_RW_MEM_TYPE) => "kernel data",
+        MemoryType(KERNEL_RO_MEM_TYPE) => "kernel rodata",
+        MemoryType(KERNEL_RWX_MEM_TYPE) => "kernel rwx",
+        MemoryType(KERNEL_STACK_MEM_TYPE) => "kernel stack",
+        MemoryType(PTE_MEM_TYPE) => "page tables",
+        MemoryType(KERNEL_ARGS_MEM_TYPE) => "kernel args",
+        _ => "reserved",
+    }
+}
+
+/// Pages of each type in the memory map passed by the bootloader
+pub fn pages_by_type() -> ArrayVec<(MemoryType, u64), MAX_MEMORY_TYPES> {
+    PAGES_BY_TYPE.lock().clone()
+}
+
+/// Log every region of the memory map and how it is used
+pub fn dump_memory_map<'a, T>(mmap: T)
+where
+    T: IntoIterator<Item = &'a MemoryDescriptor>,
+{
+    let mut by_kind: [(&str, u64); 6] = [
+        ("free", 0),
+        ("reclaimed", 0),
+        ("boot", 0),
+        ("runtime", 0),
+        ("acpi", 0),
+        ("other", 0),
+    ];
+
+    for d in mmap {
+        let kind = describe(d.ty);
+        debug!(
+            "{:#014x}-{:#014x} {:>8} pages {:<14} {:?}",
+            d.phys_start,
+            d.phys_start + d.page_count * PAGE_SIZE,
+            d.page_count,
+            kind,
+            d.ty
+        );
+
+        let slot = by_kind.len() - 1;
+        let slot = by_kind.iter().position(|(k, _)| *k == kind).unwrap_or(slot);
+        by_kind[slot].1 += d.page_count;
+    }
+
+    for (kind, pages) in by_kind.iter().filter(|(_, pages)| *pages != 0) {
+        info!("Memory map: {} KiB {}", pages * PAGE_SIZE >> 10, kind);
+    }
+}
+
+/// Allocate `2^order` contiguous frames from `zone` or below
+///
+/// If none are free the shrinkers are run and the allocation is tried once more.
+pub fn alloc_frames(order: usize, zone: Zone, zeroed: bool) -> Option<PhysFrame> {
+    let frame = GLOBAL_PHYS_ALLOC.lock().alloc(order, zone, zeroed);
+    if frame.is_some() || !oom::reclaim(1 << order) {
+        return frame;
+    }
+    GLOBAL_PHYS_ALLOC.lock().alloc(order, zone, zeroed)
+}
+
+pub unsafe fn free_frames(frame: PhysFrame, order: usize) {
+    GLOBAL_PHYS_ALLOC.lock().free(frame, order, false)
 }
 
 pub unsafe fn clear_page(va: NonNull<u8>) {
     unsafe { va.as_ptr().write_bytes(0, PAGE_SIZE as usize) };
 }
 
+/// Frames for page tables, which are accounted as such in the frame database
 pub struct GlobalFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        GLOBAL_PHYS_ALLOC
-            .lock()
-            .get_clean()
-            .map(PhysFrame::from_pointer)
+        let frame = alloc_frames(0, Zone::Normal, true)?;
+        frame::of(frame).insert_flags(FrameFlags::PAGE_TABLE);
+        Some(frame)
     }
 }
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        GLOBAL_PHYS_ALLOC.lock().free_page(frame)
+    }
+}
+
+/// Check zone limits, alignment and coalescing
+pub fn self_test() {
+    let mut phys = GLOBAL_PHYS_ALLOC.lock();
+    let before = Zone::ALL.map(|z| phys.stats(z).free);
+
+    let huge = phys
+        .alloc(9, Zone::Dma32, true)
+        .expect("Phys self-test: no 2 MiB block below 4 GiB");
+    assert!(huge.start_address().is_aligned(2u64 << 20));
+    assert!(huge.start_address().as_u64() < 4 << 30);
+    assert!(unsafe {
+        core::slice::from_raw_parts(huge.pointer().as_ptr(), 2 << 20)
+            .iter()
+            .all(|b| *b == 0)
+    });
+
+    let low = phys
+        .alloc(0, Zone::Dma, false)
+        .expect("Phys self-test: no frame below 16 MiB");
+    assert!(low.start_address().as_u64() < 16 << 20);
+
+    let pages: [PhysFrame; 8] = [(); 8].map(|_| phys.alloc(0, Zone::Normal, false).unwrap());
+    for page in pages {
+        let info = frame::of(page);
+        assert_eq!(info.refcount(), 1);
+        assert!(info.flags().contains(FrameFlags::KERNEL));
+    }
+
+    unsafe {
+        phys.free(huge, 9, true);
+        phys.free(low, 0, false);
+        for page in pages {
+            phys.free_page(page);
+        }
+    }
+
+    // Freeing both halves of a clean block merges them, unlinking the first half's list node
+    let pair = phys.alloc(1, Zone::Normal, true).unwrap();
+    unsafe {
+        phys.free(pair, 0, true);
+        phys.free(pair + 1, 0, true);
+    }
+    assert!(
+        !frame::of(pair + 1).flags().contains(FrameFlags::FREE),
+        "Phys self-test: buddies not merged"
+    );
+    let clean = phys.alloc(1, Zone::Normal, true).unwrap();
+    assert!(
+        unsafe {
+            core::slice::from_raw_parts(clean.pointer().as_ptr(), 2 * PAGE_SIZE as usize)
+                .iter()
+                .all(|b| *b == 0)
+        },
+        "Phys self-test: clean block is not zeroed"
+    );
+    unsafe { phys.free(clean, 1, false) };
+
+    let after = Zone::ALL.map(|z| phys.stats(z).free);
+    assert_eq!(before, after, "Phys self-test: frames leaked");
+
+    // A shared frame is only freed with its last reference
+    let shared = phys.alloc(0, Zone::Normal, false).unwrap();
+    drop(phys);
+    assert_eq!(frame::get_frame(shared), 2);
+    unsafe { frame::put_frame(shared) };
+    assert!(!frame::of(shared).flags().contains(FrameFlags::FREE));
+    unsafe { frame::put_frame(shared) };
+    assert_eq!(frame::of(shared).refcount(), 0);
+    assert!(!frame::of(shared).flags().contains(FrameFlags::KERNEL));
+
+    let after = Zone::ALL.map(|z| GLOBAL_PHYS_ALLOC.lock().stats(z).free);
+    assert_eq!(before, after, "Phys self-test: shared frame leaked");
+    info!("Physical allocator self-test passed");
+}
@@ -1,8 +1,7 @@
 use alloc::boxed::Box;
 use core::{
     alloc::Layout,
-    mem::{align_of, size_of},
-    ptr::{slice_from_raw_parts_mut, NonNull},
+    ptr::{null_mut, slice_from_raw_parts_mut, NonNull},
 };
 
 /// This very basic allocator is needed for bootstrapping paging and another better allocator
@@ -21,7 +20,8 @@ impl BumpAlloc {
         }
     }
 
-    pub fn alloc_fallible(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+    /// `None` once the region is used up
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
         let offset = self.curr.align_offset(layout.align());
         let new = self.curr as usize + offset + layout.size();
         if new > self.end as usize {
@@ -33,35 +33,32 @@ impl BumpAlloc {
         }
     }
 
-    pub fn alloc(&mut self, layout: Layout) -> NonNull<u8> {
-        self.alloc_fallible(layout).unwrap()
-    }
-
-    pub fn alloc_zeroed(&mut self, layout: Layout) -> NonNull<u8> {
-        let addr = self.alloc(layout);
+    pub fn alloc_zeroed(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let addr = self.alloc(layout)?;
         unsafe { addr.as_ptr().write_bytes(0, layout.size()) };
-        addr
+        Some(addr)
     }
 }
 
 /// A temporary allocator which cannot free
 pub unsafe trait BootMemAllocator {
+    /// Zeroed memory, null if there is none left
     fn alloc_raw(&mut self, layout: Layout) -> *mut u8;
 }
 
-pub fn alloc_slice<T>(alloc: &mut dyn BootMemAllocator, count: usize) -> Box<[T]> {
-    unsafe {
-        Box::from_raw(slice_from_raw_parts_mut(
-            alloc.alloc_raw(
-                Layout::from_size_align(count * size_of::<T>(), align_of::<T>()).unwrap(),
-            ) as _,
-            count,
-        ))
+/// A zeroed slice, `None` if the allocator is exhausted or the size overflows
+pub fn alloc_slice<T>(alloc: &mut dyn BootMemAllocator, count: usize) -> Option<Box<[T]>> {
+    let layout = Layout::array::<T>(count).ok()?;
+    let ptr = alloc.alloc_raw(layout);
+    if ptr.is_null() {
+        return None;
     }
+    unsafe { Some(Box::from_raw(slice_from_raw_parts_mut(ptr as _, count))) }
 }
 
 unsafe impl BootMemAllocator for BumpAlloc {
     fn alloc_raw(&mut self, layout: Layout) -> *mut u8 {
-        self.alloc_zeroed(layout).as_ptr()
+        self.alloc_zeroed(layout)
+            .map_or(null_mut(), NonNull::as_ptr)
     }
 }
@@ -0,0 +1,652 @@
+//! Slab allocator
+//!
+//! Small objects are served from caches of equally sized objects. A slab is a block of frames
+//! from the buddy allocator, accessed through the physical map, with its free objects linked
+//! through themselves and a header at the end. Allocations too large for the biggest size class
+//! go straight to the buddy allocator, or to the heap arena if they exceed the largest block.
+
+use core::{
+    alloc::Layout,
+    cmp::{max, min},
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use log::info;
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+
+#[cfg(feature = "slab-magazines")]
+use crate::arch::cpu::{this_cpu, MAX_CPUS};
+use crate::{
+    arch::PAGE_SIZE,
+    data::{
+        list::{CDLListHead, CDLListNode, SLListNode},
+        misc::Pointable,
+    },
+    mm::alloc::{
+        phys::{alloc_frames, free_frames, Zone, MAX_ORDER},
+        virt::HEAP_ARENA,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+/// Largest slab, 32 KiB
+const MAX_SLAB_ORDER: usize = 3;
+/// A slab is made bigger until it fits this many objects
+const MIN_OBJECTS: usize = 8;
+/// Smallest object, a free object must hold a list link
+const MIN_OBJECT: usize = size_of::<SLListNode>();
+
+#[cfg(feature = "slab-magazines")]
+const MAGAZINE_SIZE: usize = 16;
+
+/// Stored at the end of every slab
+#[repr(C)]
+struct Slab {
+    /// Link in the partial list, must be the first field
+    node: MaybeUninit<CDLListNode>,
+    free: SLListNode,
+    inuse: usize,
+}
+
+const SLAB_HEADER: usize = size_of::<Slab>();
+
+#[derive(Debug, Copy, Clone)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// Slabs owned by the cache, including the spare one
+    pub slabs: usize,
+    /// Objects handed out, objects held in magazines count as allocated
+    pub allocated: usize,
+    pub capacity: usize,
+}
+
+/// The slab lists of a cache
+struct RawCache {
+    size: usize,
+    order: usize,
+    per_slab: usize,
+    /// Slabs with both free and allocated objects
+    partial: CDLListHead,
+    /// An empty slab kept around to avoid thrashing the buddy allocator
+    spare: Option<NonNull<Slab>>,
+    slabs: usize,
+    allocated: usize,
+}
+
+unsafe impl Send for RawCache {}
+
+impl RawCache {
+    const fn new(size: usize) -> Self {
+        let mut order = 0;
+        while order < MAX_SLAB_ORDER && Self::fits(size, order) < MIN_OBJECTS {
+            order += 1;
+        }
+
+        Self {
+            size,
+            order,
+            per_slab: Self::fits(size, order),
+            partial: CDLListHead::new(),
+            spare: None,
+            slabs: 0,
+            allocated: 0,
+        }
+    }
+
+    const fn fits(size: usize, order: usize) -> usize {
+        (((PAGE_SIZE as usize) << order) - SLAB_HEADER) / size
+    }
+
+    fn slab_bytes(&self) -> usize {
+        (PAGE_SIZE as usize) << self.order
+    }
+
+    /// Slabs are aligned to their size, so the header is found from any object
+    fn slab_of(&self, obj: NonNull<u8>) -> NonNull<Slab> {
+        let base = obj.as_ptr() as usize & !(self.slab_bytes() - 1);
+        debug_assert_eq!(
+            (obj.as_ptr() as usize - base) % self.size,
+            0,
+            "Freeing a pointer which is not an object"
+        );
+        NonNull::new((base + self.slab_bytes() - SLAB_HEADER) as *mut Slab).unwrap()
+    }
+
+    fn base_of(&self, slab: NonNull<Slab>) -> NonNull<u8> {
+        NonNull::new((slab.as_ptr() as usize + SLAB_HEADER - self.slab_bytes()) as *mut u8).unwrap()
+    }
+
+    fn grow(&mut self) -> Option<NonNull<Slab>> {
+        let base = alloc_frames(self.order, Zone::Normal, false)?.pointer();
+        let slab =
+            NonNull::new((base.as_ptr() as usize + self.slab_bytes() - SLAB_HEADER) as *mut Slab)
+                .unwrap();
+
+        unsafe {
+            slab.as_ptr().write(Slab {
+                node: MaybeUninit::uninit(),
+                free: SLListNode { next: None },
+                inuse: 0,
+            });
+            let free = &mut (*slab.as_ptr()).free;
+            // Pushed in reverse so that objects are handed out in address order
+            for i in (0..self.per_slab).rev() {
+                free.push(NonNull::new_unchecked(base.as_ptr().add(i * self.size)));
+            }
+        }
+
+        self.slabs += 1;
+        Some(slab)
+    }
+
+    unsafe fn release(&mut self, slab: NonNull<Slab>) {
+        let base = self.base_of(slab);
+        free_frames(PhysFrame::from_pointer(base), self.order);
+        self.slabs -= 1;
+    }
+
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        let slab = match self.partial.peek() {
+            Some(node) => node.cast::<Slab>(),
+            None => {
+                let slab = match self.spare.take() {
+                    Some(slab) => slab,
+                    None => self.grow()?,
+                };
+                unsafe { self.partial.push(slab.cast()) };
+                slab
+            }
+        };
+
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        let obj = slab_ref
+            .free
+            .pop()
+            .expect("Partial slab without free objects");
+        slab_ref.inuse += 1;
+        if slab_ref.inuse == self.per_slab {
+            unsafe { self.partial.remove(slab.cast()) }
+        }
+
+        self.allocated += 1;
+        Some(obj)
+    }
+
+    unsafe fn free(&mut self, obj: NonNull<u8>) {
+        let slab = self.slab_of(obj);
+        let slab_ref = &mut *slab.as_ptr();
+        assert!(slab_ref.inuse > 0, "Slab double free");
+
+        if slab_ref.inuse == self.per_slab {
+            self.partial.push(slab.cast());
+        }
+        slab_ref.free.push(obj);
+        slab_ref.inuse -= 1;
+        self.allocated -= 1;
+
+        if slab_ref.inuse == 0 {
+            self.partial.remove(slab.cast());
+            match self.spare {
+                None => self.spare = Some(slab),
+                Some(_) => self.release(slab),
+            }
+        }
+    }
+
+    fn shrink(&mut self) {
+        if let Some(slab) = self.spare.take() {
+            unsafe { self.release(slab) }
+        }
+    }
+}
+
+/// Free objects cached per CPU in front of the slab lists
+#[cfg(feature = "slab-magazines")]
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+#[cfg(feature = "slab-magazines")]
+unsafe impl Send for Magazine {}
+
+#[cfg(feature = "slab-magazines")]
+impl Magazine {
+    const EMPTY: IRQLocked<Magazine> = IRQLocked::new(Magazine {
+        objects: [null_mut(); MAGAZINE_SIZE],
+        count: 0,
+    });
+}
+
+/// A cache of untyped objects of one size
+pub struct SlabCache {
+    name: &'static str,
+    /// Object size after rounding, fixed at creation
+    size: usize,
+    depot: IRQLocked<RawCache>,
+    #[cfg(feature = "slab-magazines")]
+    magazines: [IRQLocked<Magazine>; MAX_CPUS],
+}
+
+impl SlabCache {
+    /// A cache for objects of `size` bytes aligned to `align`
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let size = if size < MIN_OBJECT { MIN_OBJECT } else { size };
+        let size = (size + align - 1) / align * align;
+        Self {
+            name,
+            size,
+            depot: IRQLocked::new(RawCache::new(size)),
+            #[cfg(feature = "slab-magazines")]
+            magazines: [Magazine::EMPTY; MAX_CPUS],
+        }
+    }
+
+    #[cfg(not(feature = "slab-magazines"))]
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        self.depot.lock().alloc()
+    }
+
+    #[cfg(feature = "slab-magazines")]
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        let mut mag = self.magazines[this_cpu()].lock();
+        if mag.count == 0 {
+            // Refill half of the magazine at once
+            let mut depot = self.depot.lock();
+            while mag.count < MAGAZINE_SIZE / 2 {
+                match depot.alloc() {
+                    Some(obj) => {
+                        let count = mag.count;
+                        mag.objects[count] = obj.as_ptr();
+                        mag.count += 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        if mag.count == 0 {
+            return None;
+        }
+        mag.count -= 1;
+        NonNull::new(mag.objects[mag.count])
+    }
+
+    #[cfg(not(feature = "slab-magazines"))]
+    pub unsafe fn free(&self, obj: NonNull<u8>) {
+        self.depot.lock().free(obj)
+    }
+
+    #[cfg(feature = "slab-magazines")]
+    pub unsafe fn free(&self, obj: NonNull<u8>) {
+        let mut mag = self.magazines[this_cpu()].lock();
+        if mag.count == MAGAZINE_SIZE {
+            let mut depot = self.depot.lock();
+            while mag.count > MAGAZINE_SIZE / 2 {
+                mag.count -= 1;
+                depot.free(NonNull::new_unchecked(mag.objects[mag.count]));
+            }
+        }
+
+        let count = mag.count;
+        mag.objects[count] = obj.as_ptr();
+        mag.count += 1;
+    }
+
+    /// Whether an allocation or free is in progress on this cache, possibly one out of memory
+    fn is_busy(&self) -> bool {
+        #[cfg(feature = "slab-magazines")]
+        if self.magazines.iter().any(|mag| mag.is_locked()) {
+            return true;
+        }
+        self.depot.is_locked()
+    }
+
+    /// Give cached memory back to the buddy allocator
+    pub fn shrink(&self) {
+        #[cfg(feature = "slab-magazines")]
+        for mag in self.magazines.iter() {
+            let mut mag = mag.lock();
+            let mut depot = self.depot.lock();
+            while mag.count > 0 {
+                mag.count -= 1;
+                unsafe { depot.free(NonNull::new_unchecked(mag.objects[mag.count])) };
+            }
+        }
+
+        self.depot.lock().shrink()
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let depot = self.depot.lock();
+        CacheStats {
+            name: self.name,
+            object_size: depot.size,
+            slabs: depot.slabs,
+            allocated: depot.allocated,
+            capacity: depot.slabs * depot.per_slab,
+        }
+    }
+}
+
+/// A cache of objects of type `T`
+pub struct KmemCache<T> {
+    cache: SlabCache,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            cache: SlabCache::new(name, size_of::<T>(), align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `val` into a new object
+    pub fn alloc(&self, val: T) -> Option<NonNull<T>> {
+        let obj = self.cache.alloc()?.cast::<T>();
+        unsafe { obj.as_ptr().write(val) };
+        Some(obj)
+    }
+
+    /// Drop an object and free it, it must have been allocated from this cache
+    pub unsafe fn free(&self, obj: NonNull<T>) {
+        obj.as_ptr().drop_in_place();
+        self.cache.free(obj.cast())
+    }
+
+    pub fn shrink(&self) {
+        self.cache.shrink()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+pub const NUM_SIZE_CLASSES: usize = 13;
+
+/// General purpose caches, all sizes are multiples of 16 except the first one
+static SIZE_CLASSES: [SlabCache; NUM_SIZE_CLASSES] = [
+    SlabCache::new("kmalloc-8", 8, 8),
+    SlabCache::new("kmalloc-16", 16, 16),
+    SlabCache::new("kmalloc-32", 32, 16),
+    SlabCache::new("kmalloc-48", 48, 16),
+    SlabCache::new("kmalloc-64", 64, 16),
+    SlabCache::new("kmalloc-96", 96, 16),
+    SlabCache::new("kmalloc-128", 128, 16),
+    SlabCache::new("kmalloc-192", 192, 16),
+    SlabCache::new("kmalloc-256", 256, 16),
+    SlabCache::new("kmalloc-384", 384, 16),
+    SlabCache::new("kmalloc-512", 512, 16),
+    SlabCache::new("kmalloc-1024", 1024, 16),
+    SlabCache::new("kmalloc-2048", 2048, 16),
+];
+
+/// Allocations larger than the biggest size class
+static LARGE_ALLOCS: AtomicU64 = AtomicU64::new(0);
+static LARGE_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Where an allocation with a given layout lives
+#[derive(Copy, Clone)]
+enum Backing {
+    Slab(&'static SlabCache),
+    /// A buddy block of this order
+    Block(usize),
+    /// This many pages in the heap arena with an alignment
+    Arena(u64, u64),
+}
+
+impl PartialEq for Backing {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Backing::Slab(a), Backing::Slab(b)) => core::ptr::eq(*a, *b),
+            (Backing::Block(a), Backing::Block(b)) => a == b,
+            (Backing::Arena(a, x), Backing::Arena(b, y)) => a == b && x == y,
+            _ => false,
+        }
+    }
+}
+
+/// Objects in a slab are aligned to the largest power of two dividing their size
+fn backing(layout: Layout) -> Backing {
+    let size = max(layout.size(), 1);
+    if let Some(cache) = SIZE_CLASSES
+        .iter()
+        .find(|c| c.object_size() >= size && c.object_size() % layout.align() == 0)
+    {
+        return Backing::Slab(cache);
+    }
+
+    // Buddy blocks are aligned to their size
+    let pages = (max(size, layout.align()) as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+    let order = pages.next_power_of_two().trailing_zeros() as usize;
+    if order < MAX_ORDER {
+        Backing::Block(order)
+    } else {
+        Backing::Arena(
+            (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE,
+            layout.align() as u64,
+        )
+    }
+}
+
+impl Backing {
+    /// Pages taken by a large allocation, 0 for slab objects
+    fn pages(&self) -> u64 {
+        match *self {
+            Backing::Slab(_) => 0,
+            Backing::Block(order) => 1 << order,
+            Backing::Arena(pages, _) => pages,
+        }
+    }
+}
+
+fn count_large_alloc(backing: Backing) {
+    if let Backing::Slab(_) = backing {
+        return;
+    }
+    LARGE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+    LARGE_PAGES.fetch_add(backing.pages(), Ordering::Relaxed);
+}
+
+fn count_large_free(backing: Backing) {
+    if let Backing::Slab(_) = backing {
+        return;
+    }
+    LARGE_ALLOCS.fetch_sub(1, Ordering::Relaxed);
+    LARGE_PAGES.fetch_sub(backing.pages(), Ordering::Relaxed);
+}
+
+fn alloc_backed(backing: Backing, zeroed: bool) -> *mut u8 {
+    let ptr = match backing {
+        Backing::Slab(cache) => cache.alloc().map(|p| {
+            if zeroed {
+                unsafe { p.as_ptr().write_bytes(0, cache.object_size()) }
+            }
+            p
+        }),
+        Backing::Block(order) => alloc_frames(order, Zone::Normal, zeroed).map(|f| f.pointer()),
+        // Arena pages are always mapped from clean frames
+        Backing::Arena(pages, align) => HEAP_ARENA
+            .lock()
+            .alloc_aligned(pages, align)
+            .map(|a| a.pointer()),
+    };
+    if ptr.is_some() {
+        count_large_alloc(backing);
+    }
+    ptr.map_or(null_mut(), |p| p.as_ptr())
+}
+
+/// Allocate memory for `layout`, null if out of memory
+pub fn kmalloc(layout: Layout) -> *mut u8 {
+    alloc_backed(backing(layout), false)
+}
+
+/// Allocate zeroed memory, large allocations take clean frames and are not cleared again
+pub fn kmalloc_zeroed(layout: Layout) -> *mut u8 {
+    alloc_backed(backing(layout), true)
+}
+
+/// Resize an allocation, in place if the new size has the same backing
+pub unsafe fn krealloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+        Ok(l) => l,
+        Err(_) => return null_mut(),
+    };
+    let new_backing = backing(new_layout);
+    if backing(layout) == new_backing {
+        return ptr;
+    }
+
+    let new = alloc_backed(new_backing, false);
+    if !new.is_null() {
+        new.copy_from_nonoverlapping(ptr, min(layout.size(), new_size));
+        kfree(ptr, layout);
+    }
+    new
+}
+
+/// Free memory from `kmalloc`, `layout` must be the one it was allocated with
+pub unsafe fn kfree(ptr: *mut u8, layout: Layout) {
+    let ptr = NonNull::new(ptr).expect("Freeing a null pointer");
+    let backing = backing(layout);
+    count_large_free(backing);
+    match backing {
+        Backing::Slab(cache) => cache.free(ptr),
+        Backing::Block(order) => free_frames(PhysFrame::from_pointer(ptr), order),
+        Backing::Arena(pages, _) => HEAP_ARENA.lock().free(VirtAddr::from_pointer(ptr), pages),
+    }
+}
+
+/// Statistics of the general purpose caches
+pub fn size_class_stats() -> impl Iterator<Item = CacheStats> {
+    SIZE_CLASSES.iter().map(|c| c.stats())
+}
+
+/// Number of allocations larger than the biggest size class and the pages they take
+pub fn large_stats() -> (u64, u64) {
+    (
+        LARGE_ALLOCS.load(Ordering::Relaxed),
+        LARGE_PAGES.load(Ordering::Relaxed),
+    )
+}
+
+/// Release the spare slabs of all general purpose caches
+///
+/// Caches in use are skipped, this runs as a shrinker when an allocation from one of them needs
+/// a new slab.
+pub fn shrink_all() {
+    for cache in SIZE_CLASSES.iter().filter(|c| !c.is_busy()) {
+        cache.shrink()
+    }
+}
+
+/// Check slab reuse, typed caches, alignment and resizing
+pub fn self_test() {
+    struct Node {
+        val: u64,
+        _pad: [u8; 40],
+    }
+    static NODES: KmemCache<Node> = KmemCache::new("self-test");
+
+    let objs: [NonNull<Node>; 64] = [(); 64].map(|_| {
+        NODES
+            .alloc(Node {
+                val: 0x5A5A,
+                _pad: [0; 40],
+            })
+            .expect("Slab self-test: out of memory")
+    });
+    for (i, a) in objs.iter().enumerate() {
+        assert_eq!(a.as_ptr() as usize % align_of::<Node>(), 0);
+        assert_eq!(unsafe { a.as_ref().val }, 0x5A5A);
+        for b in &objs[i + 1..] {
+            assert_ne!(a, b, "Slab self-test: object handed out twice");
+        }
+    }
+    for obj in objs {
+        unsafe { NODES.free(obj) }
+    }
+    NODES.shrink();
+    assert_eq!(NODES.stats().slabs, 0, "Slab self-test: slabs leaked");
+
+    // Filling a slab takes it off the partial list, a free puts it back and emptying removes it
+    let node = || Node {
+        val: 0,
+        _pad: [0; 40],
+    };
+    let mut objs = Vec::new();
+    while NODES.stats().slabs < 2 {
+        objs.push(NODES.alloc(node()).expect("Slab self-test: out of memory"));
+    }
+    unsafe { NODES.free(objs[0]) };
+    let again = NODES.alloc(node()).expect("Slab self-test: out of memory");
+    assert_eq!(again, objs[0], "Slab self-test: freed object not reused");
+    for obj in objs {
+        unsafe { NODES.free(obj) }
+    }
+    NODES.shrink();
+    assert_eq!(NODES.stats().slabs, 0, "Slab self-test: full slab leaked");
+
+    let big = Layout::from_size_align(64 << 10, 8).unwrap();
+    let ptr = kmalloc(big);
+    assert!(!ptr.is_null());
+    unsafe {
+        ptr.write_bytes(0xAA, big.size());
+        kfree(ptr, big);
+    }
+
+    // Every power of two alignment from 1 byte to 2 MiB, with small and large sizes
+    for shift in 0..=21 {
+        let align = 1usize << shift;
+        for size in [1, 24, align, align + 8] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = kmalloc_zeroed(layout);
+            assert!(!ptr.is_null(), "Slab self-test: out of memory");
+            assert_eq!(
+                ptr as usize % align,
+                0,
+                "Slab self-test: misaligned {:?}",
+                layout
+            );
+            unsafe {
+                let bytes = core::slice::from_raw_parts_mut(ptr, size);
+                assert!(bytes.iter().all(|b| *b == 0), "Slab self-test: not zeroed");
+                bytes.fill(0xCC);
+                kfree(ptr, layout);
+            }
+        }
+    }
+
+    // Growing across size classes keeps the contents
+    let mut layout = Layout::from_size_align(40, 8).unwrap();
+    let mut ptr = kmalloc(layout);
+    unsafe {
+        ptr.write_bytes(0x42, 40);
+        for new_size in [48, 300, 5000, 40] {
+            ptr = krealloc(ptr, layout, new_size);
+            assert!(!ptr.is_null());
+            layout = Layout::from_size_align(new_size, 8).unwrap();
+            assert!(core::slice::from_raw_parts(ptr, 40)
+                .iter()
+                .all(|b| *b == 0x42));
+        }
+        kfree(ptr, layout);
+    }
+
+    info!("Slab allocator self-test passed");
+}
@@ -0,0 +1,363 @@
+//! Allocation tracking
+//!
+//! With the `alloc-trace` feature the global allocator records every live allocation in a side
+//! table: its size, when it was made and the call stack that made it. `snapshot` groups the live
+//! set by call stack, and `log_diff` logs how two snapshots differ, largest sites first, which
+//! shows whether a workload returned to its baseline. Call stacks need frame pointers, which the
+//! `alloc-trace` recipe enables.
+//!
+//! The table is an open addressing hash table keyed by address, in a block taken from the buddy
+//! allocator so that recording never allocates. Allocations made before `init` or while the
+//! table is full are not tracked.
+
+use alloc::boxed::Box;
+use core::{cmp::Reverse, fmt, time::Duration};
+
+use arrayvec::ArrayVec;
+use log::info;
+
+use crate::{
+    arch::{debug::backtrace, PAGE_SIZE},
+    data::misc::Pointable,
+    mm::alloc::phys::{alloc_frames, Zone},
+    sync::irq_lock::IRQLocked,
+    time::Instant,
+};
+
+/// Return addresses recorded per allocation
+pub const TRACE_DEPTH: usize = 6;
+/// Frames of the global allocator itself, left out of the traces
+const SKIP_FRAMES: usize = 2;
+/// The table takes a 2 MiB block
+const TABLE_ORDER: usize = 9;
+/// Distinct call stacks a snapshot keeps apart, the rest are counted together
+const MAX_SITES: usize = 128;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    /// 0 for a free slot
+    ptr: u64,
+    size: u64,
+    /// Nanoseconds since boot
+    time: u64,
+    trace: [u64; TRACE_DEPTH],
+}
+
+struct Table {
+    entries: *mut Entry,
+    /// A power of two
+    capacity: usize,
+    live: usize,
+    /// Allocations which were not recorded because the table was full
+    dropped: u64,
+}
+
+unsafe impl Send for Table {}
+
+static TABLE: IRQLocked<Table> = IRQLocked::new(Table {
+    entries: core::ptr::null_mut(),
+    capacity: 0,
+    live: 0,
+    dropped: 0,
+});
+
+impl Table {
+    fn slot(&self, i: usize) -> &mut Entry {
+        unsafe { &mut *self.entries.add(i) }
+    }
+
+    fn home(&self, ptr: u64) -> usize {
+        ((ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize & (self.capacity - 1)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        // Probes stay short below three quarters full
+        if self.live >= self.capacity / 4 * 3 {
+            self.dropped += 1;
+            return;
+        }
+        let mut i = self.home(entry.ptr);
+        while self.slot(i).ptr != 0 {
+            i = (i + 1) & (self.capacity - 1);
+        }
+        *self.slot(i) = entry;
+        self.live += 1;
+    }
+
+    fn remove(&mut self, ptr: u64) {
+        let mask = self.capacity - 1;
+        let mut i = self.home(ptr);
+        loop {
+            match self.slot(i).ptr {
+                0 => return,
+                p if p == ptr => break,
+                _ => i = (i + 1) & mask,
+            }
+        }
+        self.live -= 1;
+
+        // Move later entries of the probe sequence into the hole, so no tombstones are needed
+        let mut j = i;
+        loop {
+            self.slot(i).ptr = 0;
+            loop {
+                j = (j + 1) & mask;
+                let next = self.slot(j).ptr;
+                if next == 0 {
+                    return;
+                }
+                let k = self.home(next);
+                let stays = if i <= j {
+                    i < k && k <= j
+                } else {
+                    i < k || k <= j
+                };
+                if !stays {
+                    break;
+                }
+            }
+            *self.slot(i) = *self.slot(j);
+            i = j;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Entry> {
+        (0..self.capacity)
+            .map(move |i| &*self.slot(i))
+            .filter(|e| e.ptr != 0)
+    }
+}
+
+/// Allocate the table, after the physical allocator is set up
+pub fn init() {
+    let block =
+        alloc_frames(TABLE_ORDER, Zone::Normal, true).expect("No memory to trace allocations");
+    let slots = ((PAGE_SIZE as usize) << TABLE_ORDER) / core::mem::size_of::<Entry>();
+    // Rounded down to a power of two for masking
+    let capacity = 1 << (usize::BITS - 1 - slots.leading_zeros());
+
+    let mut table = TABLE.lock();
+    table.entries = block.pointer().cast().as_ptr();
+    table.capacity = capacity;
+    info!("Tracing allocations in a table of {} entries", capacity);
+}
+
+/// Note an allocation made by the global allocator
+#[inline(never)]
+pub fn record(ptr: *mut u8, size: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let mut stack = [0; TRACE_DEPTH + SKIP_FRAMES];
+    backtrace(&mut stack);
+    let mut trace = [0; TRACE_DEPTH];
+    trace.copy_from_slice(&stack[SKIP_FRAMES..]);
+
+    let mut table = TABLE.lock();
+    if table.capacity != 0 {
+        table.insert(Entry {
+            ptr: ptr as u64,
+            size: size as u64,
+            time: Instant::now().since_boot().as_nanos() as u64,
+            trace,
+        });
+    }
+}
+
+/// Note that an allocation was freed
+pub fn forget(ptr: *mut u8) {
+    let mut table = TABLE.lock();
+    if table.capacity != 0 {
+        table.remove(ptr as u64);
+    }
+}
+
+/// Live allocations made with one call stack
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    pub trace: [u64; TRACE_DEPTH],
+    pub count: u64,
+    pub bytes: u64,
+    /// When the oldest of them was made, since boot
+    pub oldest: Duration,
+}
+
+/// The live set grouped by call stack
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub taken: Duration,
+    pub count: u64,
+    pub bytes: u64,
+    pub sites: ArrayVec<Site, MAX_SITES>,
+    /// Allocations from call stacks which did not fit in `sites`
+    pub other: Site,
+    /// Allocations never recorded because the table was full
+    pub dropped: u64,
+}
+
+/// Group the live allocations by call stack
+pub fn snapshot() -> Box<Snapshot> {
+    let empty = Site {
+        trace: [0; TRACE_DEPTH],
+        count: 0,
+        bytes: 0,
+        oldest: Duration::MAX,
+    };
+    // Allocated before the table is locked, this allocation is traced too
+    let mut snap = Box::new(Snapshot {
+        taken: Duration::ZERO,
+        count: 0,
+        bytes: 0,
+        sites: ArrayVec::new(),
+        other: empty,
+        dropped: 0,
+    });
+
+    let table = TABLE.lock();
+    for e in table.iter() {
+        let i = match snap.sites.iter().position(|s| s.trace == e.trace) {
+            Some(i) => Some(i),
+            None => snap
+                .sites
+                .try_push(Site {
+                    trace: e.trace,
+                    ..empty
+                })
+                .ok()
+                .map(|_| snap.sites.len() - 1),
+        };
+        let site = match i {
+            Some(i) => &mut snap.sites[i],
+            None => &mut snap.other,
+        };
+        site.count += 1;
+        site.bytes += e.size;
+        site.oldest = site.oldest.min(Duration::from_nanos(e.time));
+    }
+    snap.count = table.live as u64;
+    snap.bytes = table.iter().map(|e| e.size).sum();
+    snap.dropped = table.dropped;
+    drop(table);
+
+    snap.taken = Instant::now().since_boot();
+    snap.sites.sort_unstable_by_key(|s| Reverse(s.bytes));
+    snap
+}
+
+/// Return addresses of a trace, innermost first
+struct Trace<'a>(&'a [u64; TRACE_DEPTH]);
+
+impl fmt::Display for Trace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, ip) in self.0.iter().take_while(|ip| **ip != 0).enumerate() {
+            if i > 0 {
+                f.write_str(" <- ")?;
+            }
+            write!(f, "{:#x}", ip)?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// Log the `top` sites holding the most memory
+    pub fn log(&self, top: usize) {
+        info!(
+            "Live allocations at {} ms: {} taking {} bytes, {} untracked",
+            self.taken.as_millis(),
+            self.count,
+            self.bytes,
+            self.dropped
+        );
+        for s in self.sites.iter().take(top) {
+            info!(
+                "  {} bytes in {} allocations since {} ms at {}",
+                s.bytes,
+                s.count,
+                s.oldest.as_millis(),
+                Trace(&s.trace)
+            );
+        }
+        if self.other.count != 0 {
+            info!(
+                "  {} bytes in {} allocations at other sites",
+                self.other.bytes, self.other.count
+            );
+        }
+    }
+
+    fn site(&self, trace: &[u64; TRACE_DEPTH]) -> (i64, i64) {
+        self.sites
+            .iter()
+            .find(|s| s.trace == *trace)
+            .map_or((0, 0), |s| (s.bytes as i64, s.count as i64))
+    }
+}
+
+/// Log the `top` sites which grew or shrank the most from `before` to `after`
+///
+/// Returns whether the live set is back to its size at `before`.
+pub fn log_diff(before: &Snapshot, after: &Snapshot, top: usize) -> bool {
+    let mut deltas = ArrayVec::<([u64; TRACE_DEPTH], i64, i64), { MAX_SITES * 2 }>::new();
+    let traces = after
+        .sites
+        .iter()
+        .chain(before.sites.iter())
+        .map(|s| s.trace);
+    for trace in traces {
+        if deltas.iter().any(|d| d.0 == trace) {
+            continue;
+        }
+        let (bytes_after, count_after) = after.site(&trace);
+        let (bytes_before, count_before) = before.site(&trace);
+        if bytes_after != bytes_before || count_after != count_before {
+            deltas.push((
+                trace,
+                bytes_after - bytes_before,
+                count_after - count_before,
+            ));
+        }
+    }
+    deltas.sort_unstable_by_key(|d| Reverse(d.1.abs()));
+
+    let bytes = after.bytes as i64 - before.bytes as i64;
+    let count = after.count as i64 - before.count as i64;
+    info!(
+        "Allocations from {} to {} ms: {:+} bytes in {:+} allocations",
+        before.taken.as_millis(),
+        after.taken.as_millis(),
+        bytes,
+        count
+    );
+    for (trace, bytes, count) in deltas.iter().take(top) {
+        info!(
+            "  {:+} bytes in {:+} allocations at {}",
+            bytes,
+            count,
+            Trace(trace)
+        );
+    }
+    bytes == 0 && count == 0
+}
+
+/// Check that an allocation shows up in a diff and disappears with its free
+pub fn self_test() {
+    let before = snapshot();
+    let data = alloc::vec![0u8; 4000];
+    let during = snapshot();
+    drop(data);
+    let after = snapshot();
+
+    // Each snapshot is a live allocation itself, counted from its own snapshot on
+    let own = core::mem::size_of::<Snapshot>() as u64;
+    assert!(
+        during.bytes >= before.bytes + own + 4000,
+        "Alloc trace self-test: allocation not recorded"
+    );
+    assert_eq!(
+        after.bytes,
+        before.bytes + 2 * own,
+        "Alloc trace self-test: free not recorded"
+    );
+    info!("Allocation trace self-test passed");
+}
@@ -1,25 +1,34 @@
 //! The virtual memory manager is responsible for managing pages, etc.
 
 use crate::{
-    arch::mem::get_pt,
+    arch::mem::{get_pt, pt_for},
     data::misc::Pointable,
-    mm::alloc::phys::{GlobalFrameAllocator, GLOBAL_PHYS_ALLOC},
+    mm::{
+        alloc::phys::{alloc_frames, free_frames, GlobalFrameAllocator, Zone, GLOBAL_PHYS_ALLOC},
+        frame::{self, FrameFlags},
+        mapping::{leaf_entry, split_huge_page, split_huge_page_into},
+        space, swap,
+        tlb::{TlbFlush, MAX_SINGLE},
+    },
     sync::irq_lock::IRQLocked,
 };
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
 use bitflags::bitflags;
-use boot_lib::PHYS_MAP_OFFSET;
 use core::ptr::NonNull;
 use log::info;
 
-use crate::mm::mapping::V_ADDR_MASK;
 use memrange::Range;
 use theban_interval_tree::IntervalTree;
 use x86_64::{
     align_down, align_up,
     structures::paging::{
-        page::PageRange, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        page::PageRange,
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+        Translate,
     },
-    PhysAddr, VirtAddr,
+    VirtAddr,
 };
 
 pub const VAD_ALIGN: u64 = 0x10000;
@@ -29,109 +38,485 @@ pub const USER_VIRT_SPACE_END: u64 = 0x00007FFFFFFF0000;
 pub const KERNEL_VIRT_SPACE_END: u64 = 0xFFFFFFFFFFFFF000;
 pub const KERNEL_MAP_OFFSET: u64 = 0xFFFFFFE000000000;
 
-// pub const GLOBAL_VM_ALLOC: Locked<KernelVASpace> = Locked::new(KernelVASpace::new());
-pub static GLOBAL_VM_ALLOC: IRQLocked<SimpleVaSpace> = IRQLocked::new(SimpleVaSpace::new(
-    1,
-    (KERNEL_MAP_OFFSET & V_ADDR_MASK) / Size4KiB::SIZE,
+/// The kernel heap gets the first 64 GiB of the kernel space
+pub const HEAP_ARENA_START: u64 = KERNEL_VIRT_SPACE_START;
+pub const HEAP_ARENA_END: u64 = HEAP_ARENA_START + 0x10_0000_0000;
+/// Kernel stacks and their guard pages, managed by `mm::stack`
+pub const STACK_REGION_START: u64 = HEAP_ARENA_END;
+pub const STACK_REGION_END: u64 = STACK_REGION_START + 0x4000_0000;
+
+/// Pages in one VAD alignment unit
+const VAD_ALIGN_PAGES: u64 = VAD_ALIGN / Size4KiB::SIZE;
+/// Pages in a 2 MiB page
+const HUGE_PAGES: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+/// Buddy order of a 2 MiB page
+const HUGE_ORDER: usize = 9;
+const HEAP_ARENA_MAX_RUNS: usize = 256;
+
+pub static GLOBAL_VM_ALLOC: IRQLocked<KernelVASpace> = IRQLocked::new(KernelVASpace::new(
+    STACK_REGION_END / Size4KiB::SIZE,
+    KERNEL_MAP_OFFSET / Size4KiB::SIZE,
 ));
 
-enum VAllocError {
+pub static HEAP_ARENA: IRQLocked<HeapArena> = IRQLocked::new(HeapArena::new());
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VAllocError {
     NotEnoughSpace,
     CannotCommit,
     BadParameter,
+    AlreadyReserved,
 }
 
 bitflags! {
     pub struct VAllocFlags: u32 {
         const RESERVE = 1;
         const COMMIT = 2;
+        /// Committed with 2 MiB pages, the region is 2 MiB aligned and rounded up to them
+        const HUGE = 4;
+        /// Mapped to device memory by `ioremap`, never backed by RAM
+        const MMIO = 8;
+    }
+}
+
+/// A region of kernel address space
+#[derive(Debug, Copy, Clone)]
+pub struct VAddrDescriptor {
+    pub flags: VAllocFlags,
+    pub prot: PageTableFlags,
+}
+
+fn to_page_range(r: Range) -> PageRange {
+    PageRange {
+        start: Page::containing_address(VirtAddr::new_truncate(r.min * Size4KiB::SIZE)),
+        end: Page::containing_address(VirtAddr::new_truncate((r.max + 1) * Size4KiB::SIZE)),
     }
 }
 
-struct VAddrDescriptor();
+fn page_index(addr: VirtAddr) -> u64 {
+    addr.as_u64() / Size4KiB::SIZE
+}
 
-struct KernelVASpace {
+/// Kernel address space allocator, a VAD tree keyed by page numbers
+///
+/// Regions start on a `VAD_ALIGN` boundary and never overlap. Free space is everything not in
+/// the tree, so freeing a region merges it with its free neighbours. User address spaces use
+/// the same allocator with their own page table.
+pub struct KernelVASpace {
     // TODO Non-paged & Paged kernel pools
     tree: IntervalTree<VAddrDescriptor>,
-    // TODO This should have a non-locking allocator
+    /// No free space below this page
     hint: u64,
+    start: u64,
+    end: u64,
+    /// PML4 the regions are mapped in, `None` for the active one
+    root: Option<PhysFrame>,
 }
 
 impl KernelVASpace {
-    pub const fn new() -> Self {
+    pub const fn new(start: u64, end: u64) -> Self {
         Self {
             tree: IntervalTree::new(),
-            hint: 0,
+            hint: start,
+            start,
+            end,
+            root: None,
         }
     }
 
+    /// An allocator for pages `start..end` mapped in the page table at `root`
+    pub const fn with_root(start: u64, end: u64, root: PhysFrame) -> Self {
+        Self {
+            tree: IntervalTree::new(),
+            hint: start,
+            start,
+            end,
+            root: Some(root),
+        }
+    }
+
+    /// A copy of the regions for the page table at `root`, without mapping any pages
+    pub fn duplicate(&self, root: PhysFrame) -> Self {
+        let mut copy = Self::with_root(self.start, self.end, root);
+        copy.hint = self.hint;
+        for (r, vad) in self.tree.iter() {
+            copy.tree.insert(r, *vad);
+        }
+        copy
+    }
+
+    /// All regions in address order
+    pub fn regions(&self) -> Vec<(PageRange, VAddrDescriptor)> {
+        self.tree
+            .iter()
+            .map(|(r, vad)| (to_page_range(r), *vad))
+            .collect()
+    }
+
+    fn page_table(&self) -> OffsetPageTable<'static> {
+        match self.root {
+            Some(root) => pt_for(root),
+            None => get_pt(),
+        }
+    }
+
+    /// Reserve `pages` anywhere, committing them if `COMMIT` is set
     pub fn alloc(
         &mut self,
         pages: u64,
         flags: VAllocFlags,
         prot: PageTableFlags,
     ) -> Result<PageRange, VAllocError> {
-        if let Some(space) = self.find_free_space(pages) {
-            let vad = VAddrDescriptor();
-
-            let range = PageRange {
-                start: Page::containing_address(VirtAddr::new(
-                    space.min * Size4KiB::SIZE + KERNEL_VIRT_SPACE_START,
-                )),
-                end: Page::containing_address(VirtAddr::new(
-                    space.max * Size4KiB::SIZE + KERNEL_VIRT_SPACE_START,
-                )),
-            };
+        if pages == 0 {
+            return Err(VAllocError::BadParameter);
+        }
+
+        let (pages, align, flags) = if flags.contains(VAllocFlags::HUGE) {
+            (
+                align_up(pages, HUGE_PAGES),
+                HUGE_PAGES,
+                flags | VAllocFlags::COMMIT,
+            )
+        } else {
+            (pages, VAD_ALIGN_PAGES, flags)
+        };
+
+        let start = self
+            .find_free_space(pages, align)
+            .ok_or(VAllocError::NotEnoughSpace)?;
+        self.insert(Range::new(start, start + pages - 1), flags, prot)
+    }
+
+    /// Reserve the region at `base`, which is rounded down to `VAD_ALIGN`
+    pub fn alloc_at(
+        &mut self,
+        base: VirtAddr,
+        pages: u64,
+        flags: VAllocFlags,
+        prot: PageTableFlags,
+    ) -> Result<PageRange, VAllocError> {
+        let start = page_index(base.align_down(VAD_ALIGN));
+        let end = page_index(base) + pages;
+        if pages == 0 || start < self.start || end > self.end {
+            return Err(VAllocError::BadParameter);
+        }
+        if self.tree.range(start, end - 1).next().is_some() {
+            return Err(VAllocError::AlreadyReserved);
+        }
+
+        self.insert(Range::new(start, end - 1), flags, prot)
+    }
+
+    fn insert(
+        &mut self,
+        range: Range,
+        flags: VAllocFlags,
+        prot: PageTableFlags,
+    ) -> Result<PageRange, VAllocError> {
+        let pages = to_page_range(range);
+        // Reserved pages are mapped on first access by the page fault handler
+        if flags.contains(VAllocFlags::COMMIT) {
+            alloc_and_map_in(
+                &mut self.page_table(),
+                pages.start.start_address(),
+                pages.count() as u64,
+                prot,
+            )?
+        }
+
+        self.tree.insert(range, VAddrDescriptor { flags, prot });
+        Ok(pages)
+    }
 
-            if flags.contains(VAllocFlags::COMMIT) {
-                // TODO Demand paging
-                alloc_and_map_at_range(range, prot)
+    /// First fit at or above the hint, `align` in pages
+    fn find_free_space(&mut self, pages: u64, align: u64) -> Option<u64> {
+        let first = align_up(self.hint, align);
+        let mut candidate = first;
+        for (r, _) in self.tree.range(candidate, u64::MAX) {
+            if r.min >= candidate + pages {
+                break;
             }
+            candidate = align_up(r.max + 1, align);
+        }
 
-            self.tree.insert(space, vad);
+        if candidate + pages > self.end {
+            return None;
+        }
 
-            Ok(range)
-        } else {
-            Err(VAllocError::NotEnoughSpace)
+        // Holes skipped on the way may still fit smaller regions
+        if candidate == align_up(self.hint, VAD_ALIGN_PAGES) {
+            self.hint = candidate + pages;
         }
+
+        Some(candidate)
     }
 
-    pub fn alloc_at(&mut self, mut base: NonNull<u8>, mut pages: u64) -> Option<PageRange> {
-        base = NonNull::new(align_down(base.as_ptr() as _, VAD_ALIGN) as _)?;
-        pages = align_up(pages, 16);
+    /// Free the whole region starting at `addr`
+    pub fn free(&mut self, addr: VirtAddr) -> Result<(), VAllocError> {
+        let idx = page_index(addr);
+        match self.tree.range(idx, idx).next().map(|(r, _)| r) {
+            Some(r) if r.min == idx => {
+                self.free_range(to_page_range(r));
+                Ok(())
+            }
+            _ => Err(VAllocError::BadParameter),
+        }
+    }
 
-        todo!()
+    /// Free every page in `range`, splitting regions that are only partially covered
+    pub fn free_range(&mut self, range: PageRange) {
+        if range.is_empty() {
+            return;
+        }
+
+        let min = page_index(range.start.start_address());
+        let max = page_index(range.end.start_address()) - 1;
+        let overlapping: Vec<_> = self
+            .tree
+            .range(min, max)
+            .map(|(r, vad)| (r, *vad))
+            .collect();
+
+        let mut pt = self.page_table();
+        for (r, vad) in overlapping {
+            self.tree.delete(r);
+
+            let freed = Range::new(r.min.max(min), r.max.min(max));
+            unsafe { unmap_and_free_in(&mut pt, to_page_range(freed)) };
+
+            if r.min < freed.min {
+                self.tree.insert(Range::new(r.min, freed.min - 1), vad);
+            }
+            if r.max > freed.max {
+                // The remainder keeps working, but its start is no longer aligned
+                self.tree.insert(Range::new(freed.max + 1, r.max), vad);
+            }
+        }
+
+        self.hint = self
+            .hint
+            .min(align_down(min, VAD_ALIGN_PAGES))
+            .max(self.start);
     }
 
-    fn find_free_space(&mut self, pages: u64) -> Option<memrange::Range> {
-        let size = align_up(pages, 16); // Align to 64K boundary
-        if self.tree.empty() {
-            if KERNEL_VIRT_SPACE_START + size * Size4KiB::SIZE > KERNEL_VIRT_SPACE_END {
-                return None;
+    /// The region containing `addr`
+    pub fn find(&self, addr: VirtAddr) -> Option<(PageRange, VAddrDescriptor)> {
+        let idx = page_index(addr);
+        self.tree
+            .range(idx, idx)
+            .next()
+            .map(|(r, vad)| (to_page_range(r), *vad))
+    }
+
+    /// Change the protection of the region starting at `addr` and of its mapped pages
+    pub fn protect(&mut self, addr: VirtAddr, prot: PageTableFlags) -> Result<(), VAllocError> {
+        let idx = page_index(addr);
+        let (r, mut vad) = match self.tree.range(idx, idx).next() {
+            Some((r, vad)) if r.min == idx => (r, *vad),
+            _ => return Err(VAllocError::BadParameter),
+        };
+
+        let mut pt = self.page_table();
+        let range = to_page_range(r);
+        // Huge pages crossing the edges are split first, so running out of memory for their
+        // tables leaves the protection unchanged
+        for (edge, inside) in [(range.start, range.start), (range.end, range.end - 1)] {
+            if !edge.start_address().is_aligned(Size2MiB::SIZE) {
+                unsafe { split_huge_page(&mut pt, inside.start_address())? };
             }
-            self.hint = size;
-            Some(Range::new(0, size - 1))
-        } else {
-            let mut latest = self.hint;
-            for (r, _) in self.tree.range(self.hint, u64::MAX) {
-                if r.min - latest >= size {
-                    self.hint = latest + size;
-                    return Some(Range::new(latest, latest + size - 1));
+        }
+        let mut tlb = TlbFlush::for_table(&mut pt);
+        let global = prot.contains(PageTableFlags::GLOBAL);
+        let mut page = range.start;
+        while page < range.end {
+            let addr = page.start_address();
+            match pt.translate(addr) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    flags,
+                    ..
+                } if addr.is_aligned(Size2MiB::SIZE) && page + HUGE_PAGES <= range.end => {
+                    let huge = Page::<Size2MiB>::containing_address(addr);
+                    unsafe { pt.update_flags(huge, prot) }.unwrap().ignore();
+                    tlb.add(addr, global || flags.contains(PageTableFlags::GLOBAL));
+                    page += HUGE_PAGES;
+                }
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_) | MappedFrame::Size1GiB(_),
+                    ..
+                } => {
+                    // Only part of it changes, try again with smaller pages
+                    unsafe { split_huge_page(&mut pt, addr)? };
+                }
+                TranslateResult::Mapped { flags, .. } => {
+                    // Shared pages stay read-only until they are written to
+                    let prot =
+                        if flags.contains(space::COW) && prot.contains(PageTableFlags::WRITABLE) {
+                            (prot - PageTableFlags::WRITABLE) | space::COW
+                        } else {
+                            prot
+                        };
+                    unsafe { pt.update_flags(page, prot) }.unwrap().ignore();
+                    tlb.add(addr, global || flags.contains(PageTableFlags::GLOBAL));
+                    page += 1;
+                }
+                _ => page += 1,
+            }
+        }
+        tlb.sync();
+        if addr.as_u64() >= HEAP_ARENA_END {
+            space::kernel_mappings_changed();
+        }
+
+        vad.prot = prot;
+        self.tree.insert(r, vad);
+        Ok(())
+    }
+
+    /// Number of regions and reserved pages
+    pub fn usage(&self) -> (usize, u64) {
+        self.tree.iter().fold((0, 0), |(n, pages), (r, _)| {
+            (n + 1, pages + r.max - r.min + 1)
+        })
+    }
+}
+
+unsafe impl Send for KernelVASpace {}
+
+/// Address space for the kernel heap
+///
+/// The VAD tree allocates its nodes on the heap, so the heap cannot get its pages from the
+/// tree. Instead it owns a fixed range below the tree's space and tracks freed runs in a
+/// fixed-size list. Heap pages are always committed.
+pub struct HeapArena {
+    /// Lowest address never handed out
+    next: u64,
+    /// Freed runs as `(start, pages)`, sorted and coalesced
+    free: ArrayVec<(u64, u64), HEAP_ARENA_MAX_RUNS>,
+}
+
+impl HeapArena {
+    pub const fn new() -> Self {
+        Self {
+            next: HEAP_ARENA_START,
+            free: ArrayVec::new_const(),
+        }
+    }
+
+    pub fn alloc(&mut self, pages: u64) -> Option<VirtAddr> {
+        let addr = self.reserve(pages)?;
+        self.commit(addr, pages)
+    }
+
+    /// Allocate pages aligned to `align` bytes, a power of two
+    pub fn alloc_aligned(&mut self, pages: u64, align: u64) -> Option<VirtAddr> {
+        if align <= Size4KiB::SIZE {
+            return self.alloc(pages);
+        }
+
+        let slack = align / Size4KiB::SIZE - 1;
+        let start = self.reserve(pages + slack)?;
+        let addr = (start + align - 1) & !(align - 1);
+        let lead = (addr - start) / Size4KiB::SIZE;
+        if lead > 0 {
+            self.release(start, lead);
+        }
+        if slack > lead {
+            self.release(addr + pages * Size4KiB::SIZE, slack - lead);
+        }
+
+        self.commit(addr, pages)
+    }
+
+    fn reserve(&mut self, pages: u64) -> Option<u64> {
+        let size = pages * Size4KiB::SIZE;
+        let addr = match self.free.iter().position(|(_, p)| *p >= pages) {
+            Some(i) => {
+                let (start, free_pages) = self.free[i];
+                if free_pages == pages {
+                    self.free.remove(i);
                 } else {
-                    latest = r.max + 1;
+                    self.free[i] = (start + size, free_pages - pages);
                 }
+                start
             }
-            if KERNEL_VIRT_SPACE_END - KERNEL_VIRT_SPACE_START - latest >= size {
-                self.hint = latest + size;
-                return Some(Range::new(latest, latest + size - 1));
+            None if self.next + size <= HEAP_ARENA_END => {
+                self.next += size;
+                self.next - size
             }
+            None => return None,
+        };
+        Some(addr)
+    }
 
-            None
+    /// Back reserved pages, the reservation is given back if there is no memory for them
+    fn commit(&mut self, addr: u64, pages: u64) -> Option<VirtAddr> {
+        let res = alloc_and_map_at(
+            VirtAddr::new(addr),
+            pages,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::GLOBAL
+                | PageTableFlags::NO_EXECUTE,
+        );
+        if res.is_err() {
+            self.release(addr, pages);
+            return None;
+        }
+
+        #[cfg(feature = "kasan")]
+        unsafe {
+            crate::mm::kasan::populate(addr, pages * Size4KiB::SIZE)
+        };
+        Some(VirtAddr::new(addr))
+    }
+
+    pub unsafe fn free(&mut self, addr: VirtAddr, pages: u64) {
+        unmap_and_free(PageRange {
+            start: Page::containing_address(addr),
+            end: Page::containing_address(addr + pages * Size4KiB::SIZE),
+        });
+        self.release(addr.as_u64(), pages);
+    }
+
+    /// End of the address space handed out so far
+    pub fn top(&self) -> u64 {
+        self.next
+    }
+
+    /// Pages currently handed out
+    pub fn used_pages(&self) -> u64 {
+        let free: u64 = self.free.iter().map(|(_, pages)| pages).sum();
+        (self.next - HEAP_ARENA_START) / Size4KiB::SIZE - free
+    }
+
+    /// Return unmapped address space to the free list
+    fn release(&mut self, start: u64, pages: u64) {
+        let size = pages * Size4KiB::SIZE;
+        let i = self.free.partition_point(|(s, _)| *s < start);
+        let mut run = (start, pages);
+
+        if i < self.free.len() && start + size == self.free[i].0 {
+            run.1 += self.free.remove(i).1;
+        }
+        let i = if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 * Size4KiB::SIZE == start {
+            let prev = self.free.remove(i - 1);
+            run = (prev.0, prev.1 + run.1);
+            i - 1
+        } else {
+            i
+        };
+
+        if run.0 + run.1 * Size4KiB::SIZE == self.next {
+            self.next = run.0;
+        } else {
+            // If the list is full the address space is leaked, there is plenty of it
+            let _ = self.free.try_insert(i, run);
         }
     }
 }
 
-pub fn alloc_and_map_at_range(range: PageRange, flags: PageTableFlags) {
+pub fn alloc_and_map_at_range(range: PageRange, flags: PageTableFlags) -> Result<(), VAllocError> {
     alloc_and_map_at(
         range.start.start_address(),
         (range.end.start_address() - range.start.start_address()) / Size4KiB::SIZE,
@@ -139,101 +524,347 @@ pub fn alloc_and_map_at_range(range: PageRange, flags: PageTableFlags) {
     )
 }
 
-pub fn alloc_and_map_at(virt: VirtAddr, pages: u64, flags: PageTableFlags) {
+pub fn alloc_and_map_at(
+    virt: VirtAddr,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), VAllocError> {
+    alloc_and_map_in(&mut get_pt(), virt, pages, flags)
+}
+
+/// Back `pages` pages at `virt` with zeroed frames
+///
+/// Kernel mappings use 2 MiB pages where the range is aligned and contiguous memory is free.
+/// Fails with `CannotCommit` once reclaim cannot find a frame, nothing stays mapped then.
+pub fn alloc_and_map_in(
+    pt: &mut OffsetPageTable,
+    virt: VirtAddr,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<(), VAllocError> {
     assert!(virt.is_aligned(Size4KiB::SIZE));
-    for page in 0..pages {
-        if let Some(frame) = {
-            let mut alloc = GLOBAL_PHYS_ALLOC.lock();
-            let res = alloc.get_clean();
-            drop(alloc);
-            res
-        } {
+    let mut done = 0;
+    while done < pages {
+        let addr = virt + done * Size4KiB::SIZE;
+        if addr.is_aligned(Size2MiB::SIZE)
+            && pages - done >= HUGE_PAGES
+            && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && map_clean_huge_page_in(pt, Page::containing_address(addr), flags)
+        {
+            done += HUGE_PAGES;
+            continue;
+        }
+
+        if !map_clean_page_in(pt, Page::containing_address(addr), flags) {
             unsafe {
-                get_pt()
-                    .map_to_with_table_flags(
-                        Page::<Size4KiB>::containing_address(virt + page * Size4KiB::SIZE),
-                        PhysFrame::containing_address(PhysAddr::new(
-                            frame.as_ptr() as u64 - PHYS_MAP_OFFSET,
-                        )),
-                        flags,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        &mut GlobalFrameAllocator,
-                    )
-                    .expect("Mapping failed")
-                    .flush()
+                unmap_and_free_in(
+                    pt,
+                    PageRange {
+                        start: Page::containing_address(virt),
+                        end: Page::containing_address(addr),
+                    },
+                )
             };
-        } else {
-            panic!("Physical OOM"); // TODO Swap
+            return Err(VAllocError::CannotCommit);
         }
+        done += 1;
     }
+    Ok(())
 }
 
-unsafe impl Send for KernelVASpace {}
+/// Back a 2 MiB page with a zeroed block, returns `false` if there is none
+pub fn map_clean_huge_page_in(
+    pt: &mut OffsetPageTable,
+    page: Page<Size2MiB>,
+    flags: PageTableFlags,
+) -> bool {
+    // Small pages are the fallback, so there is no point in reclaiming for a huge one
+    let block = match GLOBAL_PHYS_ALLOC
+        .lock()
+        .alloc(HUGE_ORDER, Zone::Normal, true)
+    {
+        Some(block) => block,
+        None => return false,
+    };
 
-pub struct SimpleVaSpace {
-    curr: u64,
-    end: u64,
+    let res = unsafe {
+        pt.map_to_with_table_flags(
+            page,
+            PhysFrame::<Size2MiB>::containing_address(block.start_address()),
+            flags,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            &mut GlobalFrameAllocator,
+        )
+    };
+    match res {
+        // The page was not present, so it cannot be cached
+        Ok(flush) => flush.ignore(),
+        Err(MapToError::FrameAllocationFailed) => {
+            unsafe { free_frames(block, HUGE_ORDER) };
+            return false;
+        }
+        Err(e) => panic!("Mapping failed: {:?}", e),
+    }
+
+    for frame in PhysFrame::range(block, block + HUGE_PAGES) {
+        frame::of(frame).inc_map_count();
+    }
+    true
 }
 
-impl SimpleVaSpace {
-    // TODO Rewrite this abomination
-    pub const fn new(curr: u64, end: u64) -> Self {
-        Self { curr, end }
+/// Back `page` with a zeroed frame, returns `false` if there is no physical memory left
+pub fn map_clean_page(page: Page, flags: PageTableFlags) -> bool {
+    map_clean_page_in(&mut get_pt(), page, flags)
+}
+
+/// Like `map_clean_page`, in the page table `pt`
+pub fn map_clean_page_in(pt: &mut OffsetPageTable, page: Page, flags: PageTableFlags) -> bool {
+    // User pages need user access on every level
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    let frame = match alloc_frames(0, Zone::Normal, true) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let res = unsafe {
+        pt.map_to_with_table_flags(page, frame, flags, table_flags, &mut GlobalFrameAllocator)
+    };
+    match res {
+        Ok(flush) => flush.ignore(),
+        // No frame for a page table
+        Err(MapToError::FrameAllocationFailed) => {
+            unsafe { free_frames(frame, 0) };
+            return false;
+        }
+        Err(e) => panic!("Mapping failed: {:?}", e),
     }
 
-    pub fn alloc(
-        &mut self,
-        pages: u64,
-        flags: VAllocFlags,
-        prot: PageTableFlags,
-    ) -> Option<PageRange> {
-        self.alloc_aligned(pages, 1, flags, prot)
+    let info = frame::of(frame);
+    info.inc_map_count();
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        info.insert_flags(FrameFlags::USER);
     }
+    true
+}
 
-    pub fn alloc_aligned(
-        &mut self,
-        pages: u64,
-        align_pages: u64,
-        flags: VAllocFlags,
-        prot: PageTableFlags,
-    ) -> Option<PageRange> {
-        self.curr = align_up(self.curr, align_pages);
-        if self.curr + pages >= self.end {
-            None
-        } else {
-            let old = self.curr;
-            self.curr += pages;
-            let range = PageRange {
-                start: Page::containing_address(VirtAddr::new(old * Size4KiB::SIZE)),
-                end: Page::containing_address(VirtAddr::new(self.curr * Size4KiB::SIZE)),
-            };
-            if flags.contains(VAllocFlags::COMMIT) {
-                // TODO Demand paging
-                alloc_and_map_at_range(range, prot)
+/// Unmap the pages in `range` and drop their references to their frames
+///
+/// Pages which are not mapped are skipped, swapped out ones lose their swap slot
+pub unsafe fn unmap_and_free(range: PageRange) {
+    unmap_and_free_in(&mut get_pt(), range)
+}
+
+/// Like `unmap_and_free`, in the page table `pt`
+pub unsafe fn unmap_and_free_in(pt: &mut OffsetPageTable, range: PageRange) {
+    // Heap pages are global, invlpg removes them from every PCID
+    if range.start.start_address().as_u64() >= HEAP_ARENA_END {
+        space::kernel_mappings_changed();
+    }
+    let mut tlb = TlbFlush::for_table(pt);
+    let pml4 = PhysFrame::from_pointer(NonNull::from(pt.level_4_table()).cast());
+    // Frames are released once no TLB can reach them anymore, as `(first, count)`
+    let mut unmapped = ArrayVec::<(PhysFrame, u64), MAX_SINGLE>::new();
+    let mut page = range.start;
+    while page < range.end {
+        let addr = page.start_address();
+        match pt.translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                flags,
+                ..
+            } if addr.is_aligned(Size2MiB::SIZE) && page + HUGE_PAGES <= range.end => {
+                if unmapped.is_full() {
+                    release_unmapped(&mut tlb, &mut unmapped);
+                }
+                let (block, flush) = pt
+                    .unmap(Page::<Size2MiB>::containing_address(addr))
+                    .unwrap();
+                flush.ignore();
+                tlb.add(addr, flags.contains(PageTableFlags::GLOBAL));
+                unmapped.push((
+                    PhysFrame::containing_address(block.start_address()),
+                    HUGE_PAGES,
+                ));
+                page += HUGE_PAGES;
             }
-            Some(range)
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(block),
+                ..
+            } => {
+                // Only part of it is unmapped, try again with smaller pages
+                if split_huge_page(pt, addr).is_err() {
+                    // The page at `addr` is freed anyway, so its frame becomes the table and is
+                    // released with it
+                    let offset = addr.as_u64() & (Size2MiB::SIZE - 1);
+                    let table = PhysFrame::containing_address(block.start_address() + offset);
+                    split_huge_page_into(pt, addr, table);
+                    tlb.add(addr, true);
+                    page += 1;
+                }
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => unreachable!("Only the physical map has 1 GiB pages and it is never freed"),
+            TranslateResult::Mapped { flags, .. } => {
+                if unmapped.is_full() {
+                    release_unmapped(&mut tlb, &mut unmapped);
+                }
+                let (frame, flush) = pt.unmap(page).unwrap();
+                flush.ignore();
+                tlb.add(addr, flags.contains(PageTableFlags::GLOBAL));
+                unmapped.push((frame, 1));
+                page += 1;
+            }
+            TranslateResult::NotMapped if addr.as_u64() < USER_VIRT_SPACE_END => {
+                if let Some(entry) = leaf_entry(pml4, addr) {
+                    swap::discard(entry);
+                }
+                page += 1;
+            }
+            _ => page += 1,
         }
     }
+    release_unmapped(&mut tlb, &mut unmapped);
+}
 
-    pub unsafe fn free(&mut self, addr: VirtAddr, count: u64) {
-        self.free_range(PageRange {
-            start: Page::containing_address(addr),
-            end: Page::containing_address(addr + Size4KiB::SIZE * count),
-        })
+/// Invalidate the unmapped pages, then drop their frames' mappings
+unsafe fn release_unmapped(
+    tlb: &mut TlbFlush,
+    unmapped: &mut ArrayVec<(PhysFrame, u64), MAX_SINGLE>,
+) {
+    tlb.sync();
+    for (first, count) in unmapped.drain(..) {
+        for frame in PhysFrame::range(first, first + count) {
+            release_frame(frame);
+        }
     }
+}
+
+/// Drop a mapping of `frame`, shared frames are only freed with their last mapping
+unsafe fn release_frame(frame: PhysFrame) {
+    frame::of(frame).dec_map_count();
+    frame::put_frame(frame);
+}
+
+/// Check that freed address space gets reused and that reserved regions are demand paged
+pub fn self_test() {
+    let prot = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut vm = GLOBAL_VM_ALLOC.lock();
+    let (regions_before, _) = vm.usage();
 
-    pub unsafe fn free_range(&mut self, range: PageRange) {
-        let mut pt = get_pt();
-        for page in range {
-            GLOBAL_PHYS_ALLOC.lock().dirty.push(
-                pt.translate_page(page)
-                    .expect("Bad virtual address freed")
-                    .start_address()
-                    .pointer(),
+    let mut regions = Vec::new();
+    for round in 0..4 {
+        for i in 0..64u64 {
+            let pages = 1 + (i * 7 + round) % 40;
+            let flags = if i % 4 == 0 {
+                VAllocFlags::RESERVE | VAllocFlags::COMMIT
+            } else {
+                VAllocFlags::RESERVE
+            };
+            regions.push(
+                vm.alloc(pages, flags, prot)
+                    .expect("VA self-test: alloc failed"),
             );
-            if let Ok((_, fl)) = pt.unmap(page) {
-                fl.flush()
-            }
+        }
+
+        // Free every other region, then the rest, so that holes have to be merged
+        for r in regions.iter().step_by(2) {
+            vm.free(r.start.start_address()).unwrap();
+        }
+        for r in regions.iter().skip(1).step_by(2) {
+            vm.free(r.start.start_address()).unwrap();
+        }
+
+        let again = vm
+            .alloc(16, VAllocFlags::RESERVE, prot)
+            .expect("VA self-test: alloc failed");
+        assert_eq!(
+            again.start, regions[0].start,
+            "VA self-test: freed address space was not reused"
+        );
+        vm.free(again.start.start_address()).unwrap();
+        regions.clear();
+    }
+
+    // Only touched pages of a reserved region get backed
+    let sparse = vm
+        .alloc(1024, VAllocFlags::RESERVE, prot)
+        .expect("VA self-test: alloc failed");
+    drop(vm);
+    let pt = get_pt();
+    for i in [0, 511, 1023] {
+        let ptr = (sparse.start + i).start_address().as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(
+                ptr.read_volatile(),
+                0,
+                "VA self-test: demand page not zeroed"
+            );
+            ptr.write_volatile(i);
         }
     }
+    let mapped = sparse.filter(|p| pt.translate_page(*p).is_ok()).count();
+    assert_eq!(mapped, 3, "VA self-test: untouched pages were committed");
+    let mut vm = GLOBAL_VM_ALLOC.lock();
+    vm.free(sparse.start.start_address()).unwrap();
+
+    let fixed = VirtAddr::new(STACK_REGION_END + 0x100 * VAD_ALIGN);
+    vm.alloc_at(fixed, 32, VAllocFlags::RESERVE, prot).unwrap();
+    assert_eq!(
+        vm.alloc_at(fixed + VAD_ALIGN, 1, VAllocFlags::RESERVE, prot),
+        Err(VAllocError::AlreadyReserved)
+    );
+    vm.free_range(PageRange {
+        start: Page::containing_address(fixed + 8 * Size4KiB::SIZE),
+        end: Page::containing_address(fixed + 16 * Size4KiB::SIZE),
+    });
+    assert!(vm.find(fixed + 8 * Size4KiB::SIZE).is_none());
+    assert!(vm.find(fixed + 16 * Size4KiB::SIZE).is_some());
+    vm.free_range(PageRange {
+        start: Page::containing_address(fixed),
+        end: Page::containing_address(fixed + 32 * Size4KiB::SIZE),
+    });
+
+    // Huge regions are mapped with 2 MiB pages, which are split when partially unmapped
+    let huge = vm
+        .alloc(1000, VAllocFlags::RESERVE | VAllocFlags::HUGE, prot)
+        .expect("VA self-test: alloc failed");
+    assert!(huge.start.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(huge.count() as u64, 2 * HUGE_PAGES);
+    if let TranslateResult::Mapped { frame, .. } = pt.translate(huge.start.start_address()) {
+        assert!(
+            matches!(frame, MappedFrame::Size2MiB(_)),
+            "VA self-test: no huge page"
+        );
+    }
+    let marker = (huge.start + 700).start_address().as_mut_ptr::<u64>();
+    unsafe { marker.write_volatile(0x1234) };
+
+    vm.free_range(PageRange {
+        start: huge.start + 600,
+        end: huge.start + 616,
+    });
+    assert!(pt
+        .translate_addr((huge.start + 608).start_address())
+        .is_none());
+    assert!(matches!(
+        pt.translate((huge.start + 700).start_address()),
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(_),
+            ..
+        }
+    ));
+    assert_eq!(
+        unsafe { marker.read_volatile() },
+        0x1234,
+        "VA self-test: split lost data"
+    );
+    vm.free_range(huge);
+
+    assert_eq!(vm.usage().0, regions_before, "VA self-test: regions leaked");
+    info!("VA allocator self-test passed");
 }
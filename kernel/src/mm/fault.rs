@@ -0,0 +1,85 @@
+//! Page fault resolution
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Page, PageTableFlags},
+    },
+    VirtAddr,
+};
+
+use crate::{
+    mm::{
+        alloc::virt::{
+            map_clean_page, KernelVASpace, VAddrDescriptor, VAllocFlags, GLOBAL_VM_ALLOC,
+            KERNEL_VIRT_SPACE_START,
+        },
+        space,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+/// Try to resolve a page fault, returns `false` if it is a genuine access violation
+pub fn handle_page_fault(addr: VirtAddr, code: PageFaultErrorCode) -> bool {
+    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // Writes to copy-on-write pages
+        if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && addr.as_u64() < KERNEL_VIRT_SPACE_START
+        {
+            return space::active().map_or(false, |space| space.handle_cow(addr));
+        }
+        return false;
+    }
+
+    if addr.as_u64() < KERNEL_VIRT_SPACE_START {
+        return match space::active() {
+            Some(space) => space.handle_fault(addr, code),
+            None => false,
+        };
+    }
+    if code.contains(PageFaultErrorCode::USER_MODE) {
+        return false;
+    }
+
+    demand_page(&GLOBAL_VM_ALLOC, addr, code)
+}
+
+/// Map a zeroed page at `addr` if it is in a reserved region of `vas`
+///
+/// `vas` must describe the active page table.
+pub fn demand_page(
+    vas: &IRQLocked<KernelVASpace>,
+    addr: VirtAddr,
+    code: PageFaultErrorCode,
+) -> bool {
+    // Faulted while allocating address space
+    if vas.is_locked() {
+        return false;
+    }
+
+    let vad = match vas.lock().find(addr) {
+        Some((_, vad)) => vad,
+        None => return false,
+    };
+
+    if vad
+        .flags
+        .intersects(VAllocFlags::COMMIT | VAllocFlags::MMIO)
+        || !access_allowed(&vad, code)
+    {
+        return false;
+    }
+
+    map_clean_page(
+        Page::containing_address(addr),
+        vad.prot | PageTableFlags::PRESENT,
+    )
+}
+
+/// Whether the protection of `vad` allows the access which faulted
+pub fn access_allowed(vad: &VAddrDescriptor, code: PageFaultErrorCode) -> bool {
+    !(code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !vad.prot.contains(PageTableFlags::WRITABLE))
+        && !(code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && vad.prot.contains(PageTableFlags::NO_EXECUTE))
+}
@@ -0,0 +1,255 @@
+//! Page frame database
+//!
+//! One `FrameInfo` per physical frame, indexed by PFN. The array lives in physical memory taken
+//! from the memory map during boot and is never freed.
+
+use core::{
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+
+use bitflags::bitflags;
+use log::info;
+use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+use crate::{
+    arch::{mem::page_to_pfn, PAGE_SIZE},
+    data::{late_init::LateInit, misc::Pointable},
+};
+
+static FRAMES: LateInit<&'static [FrameInfo]> = LateInit::new();
+
+bitflags! {
+    pub struct FrameFlags: u16 {
+        /// In the buddy allocator, `order` is valid on the first frame of the block
+        const FREE = 1 << 0;
+        /// Used by the kernel
+        const KERNEL = 1 << 1;
+        /// Mapped into user space
+        const USER = 1 << 2;
+        /// Must not be moved or swapped out
+        const PINNED = 1 << 3;
+        /// Modified since it was last written back
+        const DIRTY = 1 << 4;
+        /// Known to contain only zeroes
+        const ZERO = 1 << 5;
+        /// Not usable RAM, e.g. firmware or MMIO
+        const RESERVED = 1 << 6;
+        /// Holds a page table
+        const PAGE_TABLE = 1 << 7;
+    }
+}
+
+/// Per-frame metadata
+///
+/// Everything is atomic so that entries can be shared freely, the buddy allocator serializes
+/// changes to free frames.
+#[repr(C)]
+pub struct FrameInfo {
+    refcount: AtomicU32,
+    map_count: AtomicU32,
+    flags: AtomicU16,
+    order: AtomicU8,
+    owner: AtomicU64,
+}
+
+impl FrameInfo {
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_truncate(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn set_flags(&self, flags: FrameFlags) {
+        self.flags.store(flags.bits(), Ordering::Release)
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::AcqRel);
+    }
+
+    pub fn order(&self) -> usize {
+        self.order.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn set_order(&self, order: usize) {
+        self.order.store(order as u8, Ordering::Relaxed)
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn set_refcount(&self, count: u32) {
+        self.refcount.store(count, Ordering::Release)
+    }
+
+    /// Take a reference, returns the new count
+    pub fn get(&self) -> u32 {
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Drop a reference, returns the new count
+    pub fn put(&self) -> u32 {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old != 0, "Frame refcount underflow");
+        old - 1
+    }
+
+    /// Number of page table entries pointing to this frame
+    pub fn map_count(&self) -> u32 {
+        self.map_count.load(Ordering::Acquire)
+    }
+
+    pub fn inc_map_count(&self) -> u32 {
+        self.map_count.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub fn dec_map_count(&self) -> u32 {
+        let old = self.map_count.fetch_sub(1, Ordering::AcqRel);
+        assert!(old != 0, "Frame map count underflow");
+        old - 1
+    }
+
+    /// Opaque owner tag, e.g. an address space ID, 0 if none
+    pub fn owner(&self) -> u64 {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    pub fn set_owner(&self, owner: u64) {
+        self.owner.store(owner, Ordering::Relaxed)
+    }
+
+    /// Reset to the state of a freshly allocated kernel frame
+    pub fn reset(&self) {
+        self.set_refcount(1);
+        self.map_count.store(0, Ordering::Release);
+        self.set_flags(FrameFlags::KERNEL);
+        self.set_order(0);
+        self.set_owner(0);
+    }
+}
+
+/// Pages needed for the database of `frames` frames
+pub const fn db_pages(frames: u64) -> u64 {
+    (frames * size_of::<FrameInfo>() as u64 + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Set up the database for frames below `max_pfn` at `storage`
+///
+/// All frames start out reserved, the physical allocator marks the usable ones free.
+pub unsafe fn init(storage: PhysAddr, max_pfn: u64) {
+    let ptr = storage.pointer().cast::<FrameInfo>().as_ptr();
+    ptr.write_bytes(0, max_pfn as usize);
+    let frames = slice::from_raw_parts(ptr, max_pfn as usize);
+
+    for frame in frames {
+        frame.set_flags(FrameFlags::RESERVED);
+    }
+
+    let db_start = page_to_pfn(storage.as_u64());
+    for frame in &frames[db_start as usize..(db_start + db_pages(max_pfn)) as usize] {
+        frame.reset();
+        frame.insert_flags(FrameFlags::PINNED);
+    }
+
+    FRAMES.init(frames);
+
+    info!(
+        "Frame database: {} frames, {} KiB",
+        max_pfn,
+        db_pages(max_pfn) * PAGE_SIZE >> 10
+    );
+}
+
+/// Metadata of the frame with number `pfn`, `None` if it is not tracked
+pub fn get(pfn: u64) -> Option<&'static FrameInfo> {
+    FRAMES.try_get()?.get(pfn as usize)
+}
+
+/// Metadata of `frame`, panics if it is not tracked
+pub fn of(frame: PhysFrame) -> &'static FrameInfo {
+    get(page_to_pfn(frame.start_address().as_u64())).expect("Frame is not in the database")
+}
+
+/// Number of frames tracked
+pub fn count() -> u64 {
+    FRAMES.try_get().map_or(0, |f| f.len() as u64)
+}
+
+/// Take a reference to a frame shared between several owners
+pub fn get_frame(frame: PhysFrame) -> u32 {
+    of(frame).get()
+}
+
+/// Drop a reference and free the frame once it is unused
+pub unsafe fn put_frame(frame: PhysFrame) {
+    if of(frame).put() == 0 {
+        crate::mm::alloc::phys::GLOBAL_PHYS_ALLOC
+            .lock()
+            .free_unreferenced(frame);
+    }
+}
+
+/// Frames by state, for memory accounting
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FrameCounts {
+    pub free: u64,
+    pub zero: u64,
+    pub kernel: u64,
+    pub user: u64,
+    pub pinned: u64,
+    pub page_tables: u64,
+    pub shared: u64,
+    pub reserved: u64,
+}
+
+/// Walk the whole database, O(N) to memory size
+pub fn counts() -> FrameCounts {
+    let mut res = FrameCounts::default();
+    let frames = match FRAMES.try_get() {
+        Some(frames) => frames,
+        None => return res,
+    };
+
+    let mut pfn = 0;
+    while pfn < frames.len() {
+        let frame = &frames[pfn];
+        let flags = frame.flags();
+        if flags.contains(FrameFlags::FREE) {
+            // Only the first frame of a free block is updated
+            let pages = 1 << frame.order();
+            res.free += pages;
+            if flags.contains(FrameFlags::ZERO) {
+                res.zero += pages;
+            }
+            pfn += pages as usize;
+            continue;
+        }
+
+        if flags.contains(FrameFlags::RESERVED) {
+            res.reserved += 1;
+        }
+        if flags.contains(FrameFlags::KERNEL) {
+            res.kernel += 1;
+        }
+        if flags.contains(FrameFlags::USER) {
+            res.user += 1;
+        }
+        if flags.contains(FrameFlags::PINNED) {
+            res.pinned += 1;
+        }
+        if flags.contains(FrameFlags::PAGE_TABLE) {
+            res.page_tables += 1;
+        }
+        if frame.refcount() > 1 {
+            res.shared += 1;
+        }
+        pfn += 1;
+    }
+
+    res
+}
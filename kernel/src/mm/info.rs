@@ -0,0 +1,175 @@
+//! Memory accounting
+//!
+//! `meminfo` takes a snapshot of every allocator and `dump` logs it. With `meminfo=<seconds>` on
+//! the command line the snapshot is also logged periodically, to spot leaks in long runs.
+
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use uefi::table::boot::MemoryType;
+
+use crate::{
+    arch::PAGE_SIZE,
+    cmdline,
+    mm::{
+        alloc::{
+            phys::{self, Zone, ZoneStats, GLOBAL_PHYS_ALLOC, MAX_MEMORY_TYPES},
+            slab::{self, CacheStats, NUM_SIZE_CLASSES},
+            virt::{GLOBAL_VM_ALLOC, HEAP_ARENA},
+            AllocatorStats,
+        },
+        frame::{self, FrameCounts},
+        oom::{self, OomStats},
+        swap::{self, SwapStats},
+        zero::{self, ZeroStats},
+    },
+    time::sleep,
+};
+
+#[derive(Debug, Clone)]
+pub struct MemInfo {
+    pub alloc: AllocatorStats,
+    pub frames: FrameCounts,
+    pub zones: [ZoneStats; 3],
+    pub by_type: ArrayVec<(MemoryType, u64), MAX_MEMORY_TYPES>,
+    pub zero: ZeroStats,
+    pub size_classes: ArrayVec<CacheStats, NUM_SIZE_CLASSES>,
+    /// Allocations larger than the biggest size class
+    pub large_allocs: u64,
+    pub large_pages: u64,
+    /// Kernel address space regions and the pages they reserve
+    pub va_regions: usize,
+    pub va_pages: u64,
+    pub heap_arena_pages: u64,
+    pub oom: OomStats,
+    pub swap: SwapStats,
+}
+
+/// A snapshot of all memory counters, walks the frame database
+pub fn meminfo() -> MemInfo {
+    let frames = frame::counts();
+    let zones = {
+        let phys = GLOBAL_PHYS_ALLOC.lock();
+        Zone::ALL.map(|z| phys.stats(z))
+    };
+    let total: u64 = zones.iter().map(|z| z.total).sum();
+    let free: u64 = zones.iter().map(|z| z.free).sum();
+    let (va_regions, va_pages) = GLOBAL_VM_ALLOC.lock().usage();
+    let (large_allocs, large_pages) = slab::large_stats();
+
+    MemInfo {
+        alloc: AllocatorStats {
+            total: total as usize,
+            allocated: (total - free) as usize,
+            user: frames.user as usize,
+        },
+        frames,
+        zones,
+        by_type: phys::pages_by_type(),
+        zero: zero::stats(),
+        size_classes: slab::size_class_stats().collect(),
+        large_allocs,
+        large_pages,
+        va_regions,
+        va_pages,
+        heap_arena_pages: HEAP_ARENA.lock().used_pages(),
+        oom: oom::stats(),
+        swap: swap::stats(),
+    }
+}
+
+fn kib(pages: u64) -> u64 {
+    pages * PAGE_SIZE >> 10
+}
+
+/// Log a snapshot
+pub fn dump() {
+    let m = meminfo();
+
+    info!(
+        "Memory: {} KiB total, {} KiB used, {} KiB user",
+        kib(m.alloc.total as u64),
+        kib(m.alloc.allocated as u64),
+        kib(m.alloc.user as u64)
+    );
+    info!(
+        "Free: {} KiB, {} KiB clean, {} KiB dirty, {} KiB zeroed in the background at {} KiB/s",
+        kib(m.frames.free),
+        kib(m.zero.clean),
+        kib(m.zero.dirty),
+        kib(m.zero.zeroed),
+        m.zero.throughput() >> 10
+    );
+    info!(
+        "Frames: {} kernel, {} user, {} pinned, {} page tables, {} shared, {} reserved",
+        m.frames.kernel,
+        m.frames.user,
+        m.frames.pinned,
+        m.frames.page_tables,
+        m.frames.shared,
+        m.frames.reserved
+    );
+    for (zone, stats) in Zone::ALL.iter().zip(m.zones.iter()) {
+        info!(
+            "Zone {:?}: {} KiB, {} KiB free",
+            zone,
+            kib(stats.total),
+            kib(stats.free)
+        );
+    }
+    for (ty, pages) in m.by_type.iter() {
+        info!("Memory type {:?}: {} KiB", ty, kib(*pages));
+    }
+
+    for c in m.size_classes.iter().filter(|c| c.slabs != 0) {
+        info!(
+            "{}: {}/{} objects in {} slabs",
+            c.name, c.allocated, c.capacity, c.slabs
+        );
+    }
+    info!(
+        "Large allocations: {}, {} KiB",
+        m.large_allocs,
+        kib(m.large_pages)
+    );
+    info!(
+        "Kernel VA: {} regions, {} KiB reserved, {} KiB heap arena",
+        m.va_regions,
+        kib(m.va_pages),
+        kib(m.heap_arena_pages)
+    );
+    info!(
+        "Reclaim: {} runs, {} KiB freed, {} runs freed nothing",
+        m.oom.reclaims,
+        kib(m.oom.reclaimed),
+        m.oom.failures
+    );
+    info!(
+        "Swap: {} KiB total, {} KiB used, {} pages out, {} pages in, {} write errors",
+        kib(m.swap.total),
+        kib(m.swap.used),
+        m.swap.swapped_out,
+        m.swap.swapped_in,
+        m.swap.errors
+    );
+}
+
+/// Period of the meminfo log, if enabled on the command line
+pub fn period() -> Option<Duration> {
+    match cmdline::get("meminfo")?.parse::<u64>() {
+        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+        _ => {
+            warn!("Invalid meminfo period, not logging memory usage");
+            None
+        }
+    }
+}
+
+/// Dump the memory counters every `period`
+pub async fn log_periodically(period: Duration) {
+    loop {
+        sleep(period).await;
+        dump();
+    }
+}
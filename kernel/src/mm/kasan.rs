@@ -0,0 +1,722 @@
+//! Kernel address sanitizer
+//!
+//! Every 8 bytes of the kernel half have a shadow byte at `(addr >> 3) + SHADOW_OFFSET`: 0 if
+//! all of them are accessible, 1 to 7 if only that many leading bytes are, and a poison value
+//! otherwise. The whole shadow is first mapped read-only to one zero page through shared zero
+//! tables, only the shadow of the physical map and of the heap arena gets memory of its own.
+//!
+//! Heap allocations get a header with their call sites in a left redzone and a poisoned right
+//! redzone. Freed ones are poisoned and quarantined for a while before they are reused, and free
+//! frames are poisoned by the buddy allocator. Accesses are checked by code built with
+//! `-Zsanitizer=kernel-address`, without it only redzone overwrites and bad frees are found.
+
+use core::{
+    alloc::Layout,
+    cmp::{max, min},
+    mem::size_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use boot_lib::PHYS_MAP_OFFSET;
+use log::{error, info};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    arch::{debug::backtrace, PAGE_SIZE},
+    cmdline,
+    data::{late_init::LateInit, misc::Pointable},
+    mm::{
+        alloc::{
+            phys::{alloc_frames, Zone},
+            slab,
+            virt::{VAllocFlags, GLOBAL_VM_ALLOC, HEAP_ARENA, HEAP_ARENA_END, HEAP_ARENA_START},
+        },
+        frame::{self, FrameFlags},
+        tlb::TlbFlush,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+/// Shadow address of 0, the default of LLVM's kernel-address mapping on x86_64
+const SHADOW_OFFSET: u64 = 0xDFFF_FC00_0000_0000;
+const KERNEL_HALF: u64 = 0xFFFF_8000_0000_0000;
+/// Shadow of the kernel half
+const SHADOW_START: u64 = 0xFFFF_EC00_0000_0000;
+const SHADOW_END: u64 = 0xFFFF_FC00_0000_0000;
+
+/// A free buddy block, except for its list node
+pub const PAGE_FREE: u8 = 0xFF;
+pub const HEAP_LEFT: u8 = 0xFA;
+pub const HEAP_FREE: u8 = 0xFB;
+pub const HEAP_RIGHT: u8 = 0xFC;
+
+const GRANULE: u64 = 8;
+const RIGHT_REDZONE: usize = 32;
+/// Written to the right redzone, checked on free
+const REDZONE_PATTERN: u8 = 0xCC;
+const TRACE_DEPTH: usize = 6;
+const MAGIC: u32 = 0x6b61_736e;
+/// Objects are looked up by scanning the shadow back for their left redzone this far
+const MAX_SCAN: u64 = 64 << 10;
+
+const QUARANTINE_LEN: usize = 1024;
+const QUARANTINE_BYTES: usize = 4 << 20;
+
+static READY: AtomicBool = AtomicBool::new(false);
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+static ZERO_PAGE: LateInit<PhysFrame> = LateInit::new();
+static ZERO_PT: LateInit<PhysFrame> = LateInit::new();
+static ZERO_PD: LateInit<PhysFrame> = LateInit::new();
+
+/// Serializes shadow population
+static SHADOW_LOCK: IRQLocked<()> = IRQLocked::new(());
+static QUARANTINE: IRQLocked<Quarantine> = IRQLocked::new(Quarantine::new());
+
+const fn shadow(addr: u64) -> u64 {
+    (addr >> 3).wrapping_add(SHADOW_OFFSET)
+}
+
+const fn round_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) & !(align - 1)
+}
+
+/// Stored right before every heap object
+#[repr(C)]
+struct Meta {
+    magic: u32,
+    quarantined: bool,
+    size: usize,
+    /// Size of the left redzone, the block starts this far before the object
+    offset: usize,
+    align: usize,
+    alloc_trace: [u64; TRACE_DEPTH],
+    free_trace: [u64; TRACE_DEPTH],
+}
+
+/// Freed blocks as `(base, size, align)`, oldest first
+struct Quarantine {
+    entries: [(usize, usize, usize); QUARANTINE_LEN],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            entries: [(0, 0, 0); QUARANTINE_LEN],
+            head: 0,
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, entry: (usize, usize, usize)) {
+        self.entries[(self.head + self.len) % QUARANTINE_LEN] = entry;
+        self.len += 1;
+        self.bytes += entry.1;
+    }
+
+    fn pop(&mut self) -> Option<(usize, usize, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.entries[self.head];
+        self.head = (self.head + 1) % QUARANTINE_LEN;
+        self.len -= 1;
+        self.bytes -= entry.1;
+        Some(entry)
+    }
+
+    fn is_full(&self, size: usize) -> bool {
+        self.len == QUARANTINE_LEN || (self.len != 0 && self.bytes + size > QUARANTINE_BYTES)
+    }
+}
+
+fn alloc_table() -> PhysFrame {
+    let frame = alloc_frames(0, Zone::Normal, true).expect("No memory for the KASAN shadow");
+    frame::of(frame).insert_flags(FrameFlags::PAGE_TABLE | FrameFlags::PINNED);
+    frame
+}
+
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    frame.pointer().cast::<PageTable>().as_mut()
+}
+
+/// Map the shadow and poison the free frames, after the physical allocator and `space::init`
+#[no_sanitize(address)]
+pub fn init() {
+    GLOBAL_VM_ALLOC
+        .lock()
+        .alloc_at(
+            VirtAddr::new(SHADOW_START),
+            (SHADOW_END - SHADOW_START) / PAGE_SIZE,
+            VAllocFlags::RESERVE,
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("KASAN shadow overlaps kernel mappings");
+
+    let zero_page = alloc_table();
+    let zero_pt = alloc_table();
+    let zero_pd = alloc_table();
+    let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    unsafe {
+        for entry in table(zero_pt).iter_mut() {
+            entry.set_addr(
+                zero_page.start_address(),
+                PageTableFlags::PRESENT | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE,
+            );
+        }
+        for entry in table(zero_pd).iter_mut() {
+            entry.set_addr(zero_pt.start_address(), parent);
+        }
+
+        // `space::init` allocated every kernel PML4 entry, so the PDPTs are shared
+        let pml4 = table(Cr3::read().0);
+        for i in (SHADOW_START >> 39) as usize & 511..(SHADOW_END >> 39) as usize & 511 {
+            let pdpt = table(PhysFrame::containing_address(pml4[i].addr()));
+            for entry in pdpt.iter_mut().filter(|e| e.is_unused()) {
+                entry.set_addr(zero_pd.start_address(), parent);
+            }
+        }
+    }
+
+    ZERO_PAGE.init(zero_page);
+    ZERO_PT.init(zero_pt);
+    ZERO_PD.init(zero_pd);
+
+    let phys_len = frame::count() * PAGE_SIZE;
+    let arena_len = HEAP_ARENA.lock().top() - HEAP_ARENA_START;
+    unsafe {
+        populate(PHYS_MAP_OFFSET, phys_len);
+        populate(HEAP_ARENA_START, arena_len);
+    }
+
+    READY.store(true, Ordering::Release);
+
+    // Only blocks freed from now on are poisoned by the allocator
+    let mut pfn = 0;
+    while pfn < frame::count() {
+        let info = frame::get(pfn).unwrap();
+        if info.flags().contains(FrameFlags::FREE) {
+            poison_free_block(pfn << 12, info.order());
+            pfn += 1 << info.order();
+        } else {
+            pfn += 1;
+        }
+    }
+
+    info!(
+        "KASAN: shadow at {:#x}, {} KiB populated",
+        SHADOW_START,
+        (phys_len + arena_len) / GRANULE >> 10
+    );
+}
+
+/// Give the shadow of `[addr, addr + size)` memory of its own
+#[no_sanitize(address)]
+pub unsafe fn populate(addr: u64, size: u64) {
+    if size == 0 || ZERO_PAGE.try_get().is_none() {
+        return;
+    }
+
+    let _guard = SHADOW_LOCK.lock();
+    let start = shadow(addr) & !(PAGE_SIZE - 1);
+    let end = round_up(shadow(addr + size - 1) + 1, PAGE_SIZE);
+    let mut flush = TlbFlush::active();
+    for page in (start..end).step_by(PAGE_SIZE as usize) {
+        populate_page(page, &mut flush);
+    }
+    flush.flush();
+}
+
+#[no_sanitize(address)]
+unsafe fn populate_page(va: u64, flush: &mut TlbFlush) {
+    let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut frame = Cr3::read().0;
+
+    // Replace the shared zero tables on the way down with private copies
+    for (shift, zero) in [(39, None), (30, Some(*ZERO_PD)), (21, Some(*ZERO_PT))] {
+        let entry = &mut table(frame)[(va >> shift) as usize & 511];
+        if Some(PhysFrame::containing_address(entry.addr())) == zero {
+            let copy = alloc_table();
+            table(copy).clone_from(table(zero.unwrap()));
+            entry.set_addr(copy.start_address(), parent);
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+
+    let entry = &mut table(frame)[(va >> 12) as usize & 511];
+    if PhysFrame::containing_address(entry.addr()) == *ZERO_PAGE {
+        let page = alloc_frames(0, Zone::Normal, true).expect("No memory for the KASAN shadow");
+        entry.set_addr(
+            page.start_address(),
+            parent | PageTableFlags::GLOBAL | PageTableFlags::NO_EXECUTE,
+        );
+        // The zero page was mapped global
+        flush.add(VirtAddr::new(va), true);
+    }
+}
+
+/// Start of the range with a writable shadow containing `addr`
+fn shadow_base(addr: u64) -> Option<u64> {
+    if addr >= PHYS_MAP_OFFSET && addr < PHYS_MAP_OFFSET + frame::count() * PAGE_SIZE {
+        Some(PHYS_MAP_OFFSET)
+    } else if addr >= HEAP_ARENA_START && addr < HEAP_ARENA_END {
+        Some(HEAP_ARENA_START)
+    } else {
+        None
+    }
+}
+
+/// Whether the shadow of `addr` is writable
+fn has_shadow(addr: u64) -> bool {
+    shadow_base(addr).is_some()
+}
+
+/// Mark `size` bytes at `addr` inaccessible, `addr` must be 8-byte aligned
+#[no_sanitize(address)]
+pub fn poison(addr: u64, size: u64, value: u8) {
+    if !READY.load(Ordering::Acquire) || size == 0 || !has_shadow(addr) {
+        return;
+    }
+    debug_assert!(addr % GRANULE == 0);
+    unsafe {
+        (shadow(addr) as *mut u8).write_bytes(value, (round_up(size, GRANULE) / GRANULE) as usize)
+    };
+}
+
+/// Mark `size` bytes at `addr` accessible, `addr` must be 8-byte aligned
+#[no_sanitize(address)]
+pub fn unpoison(addr: u64, size: u64) {
+    if !READY.load(Ordering::Acquire) || size == 0 || !has_shadow(addr) {
+        return;
+    }
+    debug_assert!(addr % GRANULE == 0);
+    unsafe {
+        let s = shadow(addr) as *mut u8;
+        s.write_bytes(0, (size / GRANULE) as usize);
+        if size % GRANULE != 0 {
+            *s.add((size / GRANULE) as usize) = (size % GRANULE) as u8;
+        }
+    }
+}
+
+/// Poison a free buddy block in the physical map, except for its list node
+pub fn poison_free_block(phys: u64, order: usize) {
+    let node = 2 * GRANULE;
+    unpoison(PHYS_MAP_OFFSET + phys, node);
+    poison(
+        PHYS_MAP_OFFSET + phys + node,
+        (PAGE_SIZE << order) - node,
+        PAGE_FREE,
+    );
+}
+
+pub fn unpoison_block(phys: u64, order: usize) {
+    unpoison(PHYS_MAP_OFFSET + phys, PAGE_SIZE << order);
+}
+
+/// The first inaccessible byte in `[addr, addr + size)`
+#[no_sanitize(address)]
+fn first_bad(addr: u64, size: u64) -> Option<u64> {
+    let end = addr.checked_add(size)?;
+    let mut a = addr;
+    while a < end {
+        let s = unsafe { *(shadow(a) as *const i8) };
+        if s == 0 {
+            a = (a & !(GRANULE - 1)) + GRANULE;
+        } else if (a & (GRANULE - 1)) as i8 >= s {
+            return Some(a);
+        } else {
+            a += 1;
+        }
+    }
+    None
+}
+
+#[no_sanitize(address)]
+fn check(addr: u64, size: u64, write: bool) {
+    if !READY.load(Ordering::Relaxed)
+        || REPORTING.load(Ordering::Relaxed)
+        || addr < KERNEL_HALF
+        || (SHADOW_START..SHADOW_END).contains(&addr)
+    {
+        return;
+    }
+
+    if let Some(bad) = first_bad(addr, size) {
+        report(bad, addr, size, write);
+    }
+}
+
+/// Find the heap object whose redzones or freed memory contain `addr`
+#[no_sanitize(address)]
+fn find_meta(addr: u64) -> Option<&'static Meta> {
+    let shadow_at = |a: u64| unsafe { *(shadow(a) as *const u8) };
+    // No object starts below the range, whose shadow may not be mapped there
+    let base = shadow_base(addr)?;
+    let mut g = addr & !(GRANULE - 1);
+
+    if shadow_at(g) == HEAP_LEFT {
+        // Underflow, the object is after the left redzone
+        while shadow_at(g) == HEAP_LEFT && g - addr < MAX_SCAN {
+            g += GRANULE;
+        }
+    } else {
+        while g > base && shadow_at(g - GRANULE) != HEAP_LEFT {
+            g -= GRANULE;
+            if addr - g > MAX_SCAN {
+                return None;
+            }
+        }
+    }
+    if g - base < size_of::<Meta>() as u64 {
+        return None;
+    }
+
+    let meta = unsafe { &*((g - size_of::<Meta>() as u64) as *const Meta) };
+    let end = g + round_up(meta.size as u64, GRANULE) + RIGHT_REDZONE as u64;
+    if meta.magic == MAGIC && addr < end {
+        Some(meta)
+    } else {
+        None
+    }
+}
+
+fn log_trace(title: &str, trace: &[u64]) {
+    error!("{}:", title);
+    for ip in trace.iter().take_while(|ip| **ip != 0) {
+        error!("  {:#x}", ip);
+    }
+}
+
+/// What the poison at the inaccessible byte `bad` tells about the access
+#[no_sanitize(address)]
+fn kind(bad: u64) -> &'static str {
+    let mut value = unsafe { *(shadow(bad) as *const u8) };
+    if value < GRANULE as u8 {
+        value = unsafe { *(shadow(bad + GRANULE) as *const u8) };
+    }
+    match value {
+        HEAP_LEFT | HEAP_RIGHT => "heap-out-of-bounds",
+        HEAP_FREE => "use-after-free",
+        PAGE_FREE => "use-after-free of a page",
+        _ => "wild-memory-access",
+    }
+}
+
+#[no_sanitize(address)]
+fn report(bad: u64, addr: u64, size: u64, write: bool) {
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let kind = kind(bad);
+    let mut trace = [0; TRACE_DEPTH * 2];
+    backtrace(&mut trace);
+
+    error!("==================================================================");
+    error!("KASAN: {} at {:#x}", kind, bad);
+    error!(
+        "{} of size {} at {:#x}",
+        if write { "Write" } else { "Read" },
+        size,
+        addr
+    );
+    log_trace("Call trace", &trace);
+    if let Some(meta) = find_meta(bad) {
+        error!(
+            "The object of size {} is at {:#x}",
+            meta.size,
+            meta as *const Meta as u64 + size_of::<Meta>() as u64
+        );
+        log_trace("Allocated by", &meta.alloc_trace);
+        if meta.quarantined {
+            log_trace("Freed by", &meta.free_trace);
+        }
+    }
+    error!("==================================================================");
+
+    REPORTING.store(false, Ordering::Release);
+    if cmdline::has("kasan_panic") {
+        panic!("KASAN: {}", kind);
+    }
+}
+
+#[no_sanitize(address)]
+fn report_free(what: &str, ptr: *mut u8, meta: Option<&Meta>) {
+    let mut trace = [0; TRACE_DEPTH * 2];
+    backtrace(&mut trace);
+
+    error!("==================================================================");
+    error!("KASAN: {} of {:#x}", what, ptr as u64);
+    log_trace("Call trace", &trace);
+    if let Some(meta) = meta {
+        log_trace("Allocated by", &meta.alloc_trace);
+        if meta.quarantined {
+            log_trace("Freed by", &meta.free_trace);
+        }
+    }
+    error!("==================================================================");
+
+    if cmdline::has("kasan_panic") {
+        panic!("KASAN: {}", what);
+    }
+}
+
+/// Allocate with redzones, for the global allocator
+#[no_sanitize(address)]
+pub fn alloc(layout: Layout, zeroed: bool) -> *mut u8 {
+    let align = max(layout.align(), GRANULE as usize);
+    let offset = round_up(size_of::<Meta>() as u64, align as u64) as usize;
+    let inner = match Layout::from_size_align(
+        offset + round_up(layout.size() as u64, GRANULE) as usize + RIGHT_REDZONE,
+        align,
+    ) {
+        Ok(l) => l,
+        Err(_) => return null_mut(),
+    };
+
+    // Not zeroed by the slab allocator, it would write to poisoned memory
+    let base = slab::kmalloc(inner);
+    if base.is_null() {
+        return base;
+    }
+
+    unsafe {
+        let obj = base.add(offset);
+        let meta = &mut *(obj.sub(size_of::<Meta>()) as *mut Meta);
+        *meta = Meta {
+            magic: MAGIC,
+            quarantined: false,
+            size: layout.size(),
+            offset,
+            align,
+            alloc_trace: [0; TRACE_DEPTH],
+            free_trace: [0; TRACE_DEPTH],
+        };
+        backtrace(&mut meta.alloc_trace);
+
+        let redzone = obj.add(layout.size());
+        redzone.write_bytes(
+            REDZONE_PATTERN,
+            base.add(inner.size()) as usize - redzone as usize,
+        );
+        if zeroed {
+            obj.write_bytes(0, layout.size());
+        }
+
+        let obj = obj as u64;
+        poison(base as u64, offset as u64, HEAP_LEFT);
+        unpoison(obj, layout.size() as u64);
+        poison(
+            obj + round_up(layout.size() as u64, GRANULE),
+            RIGHT_REDZONE as u64,
+            HEAP_RIGHT,
+        );
+        obj as *mut u8
+    }
+}
+
+/// Poison and quarantine an object from `alloc`
+#[no_sanitize(address)]
+pub unsafe fn free(ptr: *mut u8, _layout: Layout) {
+    let meta = &mut *(ptr.sub(size_of::<Meta>()) as *mut Meta);
+    if meta.magic != MAGIC {
+        report_free("invalid free", ptr, None);
+        return;
+    }
+    if meta.quarantined {
+        report_free("double free", ptr, Some(meta));
+        return;
+    }
+
+    let redzone_end = ptr.add(round_up(meta.size as u64, GRANULE) as usize + RIGHT_REDZONE);
+    let mut p = ptr.add(meta.size);
+    while p < redzone_end {
+        if *p != REDZONE_PATTERN {
+            report_free("heap-out-of-bounds write found on free", ptr, Some(meta));
+            break;
+        }
+        p = p.add(1);
+    }
+
+    meta.quarantined = true;
+    backtrace(&mut meta.free_trace);
+    poison(ptr as u64, round_up(meta.size as u64, GRANULE), HEAP_FREE);
+
+    let base = ptr.sub(meta.offset) as usize;
+    let size = redzone_end as usize - base;
+    let align = meta.align;
+
+    let mut quarantine = QUARANTINE.lock();
+    while quarantine.is_full(size) {
+        let old = quarantine.pop().unwrap();
+        release(old);
+    }
+    quarantine.push((base, size, align));
+}
+
+/// Give a quarantined block back to the slab allocator
+///
+/// Free slab objects stay accessible, the allocator links them through themselves. Blocks go
+/// back to the buddy allocator, which poisons them.
+#[no_sanitize(address)]
+unsafe fn release((base, size, align): (usize, usize, usize)) {
+    unpoison(base as u64, size as u64);
+    slab::kfree(
+        base as *mut u8,
+        Layout::from_size_align_unchecked(size, align),
+    );
+}
+
+/// Resize by moving, so that stale pointers to the old object are caught
+#[no_sanitize(address)]
+pub unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+        Ok(l) => l,
+        Err(_) => return null_mut(),
+    };
+    let new = alloc(new_layout, false);
+    if !new.is_null() {
+        new.copy_from_nonoverlapping(ptr, min(layout.size(), new_size));
+        free(ptr, layout);
+    }
+    new
+}
+
+macro_rules! check_fns {
+    ($($size:expr => $load:ident, $store:ident, $report_load:ident, $report_store:ident;)*) => {$(
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub extern "C" fn $load(addr: usize) {
+            check(addr as u64, $size, false)
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub extern "C" fn $store(addr: usize) {
+            check(addr as u64, $size, true)
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub extern "C" fn $report_load(addr: usize) {
+            report(addr as u64, addr as u64, $size, false)
+        }
+
+        #[no_mangle]
+        #[no_sanitize(address)]
+        pub extern "C" fn $report_store(addr: usize) {
+            report(addr as u64, addr as u64, $size, true)
+        }
+    )*};
+}
+
+check_fns! {
+    1 => __asan_load1_noabort, __asan_store1_noabort,
+        __asan_report_load1_noabort, __asan_report_store1_noabort;
+    2 => __asan_load2_noabort, __asan_store2_noabort,
+        __asan_report_load2_noabort, __asan_report_store2_noabort;
+    4 => __asan_load4_noabort, __asan_store4_noabort,
+        __asan_report_load4_noabort, __asan_report_store4_noabort;
+    8 => __asan_load8_noabort, __asan_store8_noabort,
+        __asan_report_load8_noabort, __asan_report_store8_noabort;
+    16 => __asan_load16_noabort, __asan_store16_noabort,
+        __asan_report_load16_noabort, __asan_report_store16_noabort;
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check(addr as u64, size as u64, false)
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check(addr as u64, size as u64, true)
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_report_load_n_noabort(addr: usize, size: usize) {
+    report(addr as u64, addr as u64, size as u64, false)
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub extern "C" fn __asan_report_store_n_noabort(addr: usize, size: usize) {
+    report(addr as u64, addr as u64, size as u64, true)
+}
+
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+extern "C" {
+    fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8;
+    fn memset(dest: *mut u8, c: i32, n: usize) -> *mut u8;
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    check(src as u64, n as u64, false);
+    check(dest as u64, n as u64, true);
+    memcpy(dest, src, n)
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    check(src as u64, n as u64, false);
+    check(dest as u64, n as u64, true);
+    memmove(dest, src, n)
+}
+
+#[no_mangle]
+#[no_sanitize(address)]
+pub unsafe extern "C" fn __asan_memset(dest: *mut u8, c: i32, n: usize) -> *mut u8 {
+    check(dest as u64, n as u64, true);
+    memset(dest, c, n)
+}
+
+/// Check that out-of-bounds and freed accesses to a heap object are caught and valid ones are not
+#[no_sanitize(address)]
+pub fn self_test() {
+    const SIZE: usize = 13;
+    let layout = Layout::from_size_align(SIZE, 1).unwrap();
+    let obj = alloc(layout, true);
+    assert!(!obj.is_null(), "KASAN self-test: alloc failed");
+    let addr = obj as u64;
+
+    assert_eq!(
+        first_bad(addr, SIZE as u64),
+        None,
+        "KASAN self-test: valid access caught"
+    );
+    let over = first_bad(addr, SIZE as u64 + 1).expect("KASAN self-test: overflow missed");
+    assert_eq!(over, addr + SIZE as u64);
+    assert_eq!(kind(over), "heap-out-of-bounds");
+    let under = first_bad(addr - 1, 1).expect("KASAN self-test: underflow missed");
+    assert_eq!(kind(under), "heap-out-of-bounds");
+    assert_eq!(find_meta(over).map(|m| m.size), Some(SIZE));
+
+    unsafe { free(obj, layout) };
+    let freed = first_bad(addr, 1).expect("KASAN self-test: use-after-free missed");
+    assert_eq!(kind(freed), "use-after-free");
+    assert!(find_meta(freed).map_or(false, |m| m.quarantined));
+
+    // The scan back from the first granule must stop at the start of the physical map
+    assert!(find_meta(PHYS_MAP_OFFSET).is_none());
+    info!("KASAN self-test passed");
+}
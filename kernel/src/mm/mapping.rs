@@ -1,14 +1,27 @@
-use crate::{arch::mem::get_pt, data::misc::Pointable};
+use alloc::{vec, vec::Vec};
 
+use crate::{
+    arch::mem::get_pt,
+    data::misc::Pointable,
+    mm::{
+        alloc::{
+            phys::{alloc_frames, Zone},
+            virt::VAllocError,
+        },
+        frame::{self, FrameFlags},
+        tlb::TlbFlush,
+    },
+};
 
 use x86_64::{
     align_down, align_up,
-    instructions::tlb::flush_all,
+    instructions::tlb::flush,
     structures::paging::{
         page::PageRange,
-        page_table::{PageTableLevel}, Page, PageSize, PageTable, Size4KiB,
+        page_table::{PageTableEntry, PageTableLevel},
+        OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
     },
-    VirtAddr,
+    PhysAddr, VirtAddr,
 };
 
 pub const V_ADDR_MASK: u64 = 0x0000FFFFFFFFFFFF;
@@ -63,5 +76,162 @@ unsafe fn unmap_level(level: u64, range: PageRange) {
 
 pub unsafe fn unmap_range(range: PageRange) {
     unmap_level(4, range);
-    flush_all();
+    // The entries are not looked at, so they may have been global
+    let mut flush = TlbFlush::active();
+    flush.add_range(range, true);
+    flush.flush();
+}
+
+/// The PAT bit of a huge page entry, which is the lowest address bit
+const HUGE_PAT: u64 = 1 << 12;
+
+/// Split the huge pages mapping `addr` until it is mapped by a 4 KiB page
+///
+/// The memory stays mapped with the same flags. Returns `false` if `addr` is not in a huge page,
+/// and `CannotCommit` if there is no frame for a new table. Levels split before that stay split.
+pub unsafe fn split_huge_page(
+    pt: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Result<bool, VAllocError> {
+    let mut split = false;
+    let mut res = Ok(());
+    let mut table: *mut PageTable = pt.level_4_table();
+    for (level, index) in [
+        (4, addr.p4_index()),
+        (3, addr.p3_index()),
+        (2, addr.p2_index()),
+    ] {
+        let entry = &mut (*table)[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            match alloc_frames(0, Zone::Normal, false) {
+                Some(table_frame) => split_entry(entry, level, table_frame),
+                None => {
+                    res = Err(VAllocError::CannotCommit);
+                    break;
+                }
+            }
+            split = true;
+        }
+        table = entry.frame().unwrap().pointer().cast().as_ptr();
+    }
+
+    if split {
+        // Invalidates the whole huge page translation
+        flush(addr);
+    }
+    res.map(|_| split)
+}
+
+/// Split the 2 MiB page mapping `addr` with `table` as the new table, and unmap `addr`
+///
+/// For when no frame can be allocated but part of the huge page is being freed anyway: `table` is
+/// the frame mapped at `addr`. It loses that mapping and from then on belongs to the page table,
+/// accounted like a table frame from the allocator and freed with the table.
+pub unsafe fn split_huge_page_into(pt: &mut OffsetPageTable, addr: VirtAddr, table: PhysFrame) {
+    let l3 = pt.level_4_table()[addr.p4_index()]
+        .frame()
+        .unwrap()
+        .pointer()
+        .cast::<PageTable>()
+        .as_mut();
+    let l2 = l3[addr.p3_index()]
+        .frame()
+        .unwrap()
+        .pointer()
+        .cast::<PageTable>()
+        .as_mut();
+    let entry = &mut l2[addr.p2_index()];
+    debug_assert!(entry.flags().contains(PageTableFlags::HUGE_PAGE));
+    debug_assert_eq!(
+        entry.addr().as_u64() & !HUGE_PAT,
+        align_down(table.start_address().as_u64(), Size2MiB::SIZE)
+    );
+
+    // Huge pages are never shared, so the huge page held the only reference
+    let info = frame::of(table);
+    assert!(
+        info.refcount() == 1 && info.map_count() == 1,
+        "Splitting a huge page into a frame which is still in use"
+    );
+    info.reset();
+
+    split_entry(entry, 2, table);
+    table.pointer().cast::<PageTable>().as_mut()[addr.p1_index()].set_unused();
+    flush(addr);
+}
+
+/// Point a huge page entry at `level` to `table_frame`, filled to map the same memory with 512
+/// pages
+unsafe fn split_entry(entry: &mut PageTableEntry, level: u8, table_frame: PhysFrame) {
+    let flags = entry.flags();
+    let pat = entry.addr().as_u64() & HUGE_PAT != 0;
+    let base = entry.addr().as_u64() & !HUGE_PAT;
+
+    let (step, leaf_flags) = if level == 3 {
+        (Size2MiB::SIZE, flags)
+    } else {
+        // For 4 KiB pages the PAT bit is where the huge page bit was
+        let mut leaf = flags - PageTableFlags::HUGE_PAGE;
+        leaf.set(PageTableFlags::HUGE_PAGE, pat);
+        (Size4KiB::SIZE, leaf)
+    };
+    let pat_bit = if level == 3 && pat { HUGE_PAT } else { 0 };
+
+    frame::of(table_frame).insert_flags(FrameFlags::PAGE_TABLE);
+    let table = table_frame.pointer().cast::<PageTable>().as_mut();
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(
+            PhysAddr::new((base + i as u64 * step) | pat_bit),
+            leaf_flags,
+        );
+    }
+
+    // Permissions are decided by the leaves
+    entry.set_frame(
+        table_frame,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE),
+    );
+}
+
+/// The last level entry for `addr`, `None` if a table on the way is missing or a huge page
+pub unsafe fn leaf_entry(pml4: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = pml4.pointer().cast::<PageTable>().as_mut();
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        table = entry.frame().ok()?.pointer().cast::<PageTable>().as_mut();
+    }
+    Some(&mut table[addr.p1_index()])
+}
+
+/// Every page table frame reachable from `pml4`, including itself
+pub fn table_frames(pml4: PhysFrame) -> Vec<PhysFrame> {
+    let mut res = vec![pml4];
+    unsafe { collect_tables(pml4, 4, &mut res) };
+    res
+}
+
+unsafe fn collect_tables(table: PhysFrame, level: u8, out: &mut Vec<PhysFrame>) {
+    let table: &PageTable = table.pointer().cast().as_ref();
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        let child = PhysFrame::containing_address(entry.addr());
+        out.push(child);
+        if level > 2 {
+            collect_tables(child, level - 1, out);
+        }
+    }
 }
@@ -0,0 +1,260 @@
+//! Memory-mapped I/O
+//!
+//! `ioremap` maps device memory into kernel address space with a caching mode, the physical map
+//! is write-back and only meant for RAM. A page must not be mapped with two memory types at once,
+//! so where the physical map covers the device range its huge pages are split and the pages get
+//! the same mode, until the last region mapping them is dropped. Overlapping regions with
+//! different modes are refused.
+
+use arrayvec::ArrayVec;
+use boot_lib::PHYS_MAP_OFFSET;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+pub use crate::arch::mem::pat::CacheMode;
+use crate::{
+    arch::{
+        mem::{get_pt, pat::PTE_CACHE_MASK},
+        PAGE_SIZE,
+    },
+    mm::{
+        alloc::{
+            phys::{contains_ram, GlobalFrameAllocator},
+            virt::{VAllocFlags, GLOBAL_VM_ALLOC},
+        },
+        mapping::{leaf_entry, split_huge_page},
+        space,
+        tlb::TlbFlush,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+const MAX_REGIONS: usize = 64;
+
+/// Mapped device ranges as `(first pfn, end pfn, mode)`
+static REGIONS: IRQLocked<ArrayVec<(u64, u64, CacheMode), MAX_REGIONS>> =
+    IRQLocked::new(ArrayVec::new_const());
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MmioError {
+    /// The range contains RAM, including boot memory which is not reclaimed yet
+    Ram,
+    /// Part of the range is already mapped with this other mode
+    Conflict(CacheMode),
+    TooManyRegions,
+    NoAddressSpace,
+    /// No frame for a table to split the physical map
+    NoMemory,
+}
+
+/// Device memory mapped by `ioremap`, unmapped on drop
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    pages: u64,
+    /// Offset of the mapped range in the first page
+    offset: usize,
+    len: usize,
+    phys: PhysAddr,
+    mode: CacheMode,
+}
+
+impl MmioRegion {
+    pub fn as_ptr(&self) -> *mut u8 {
+        (self.base + self.offset).as_mut_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    fn reg32(&self, offset: usize) -> *mut u32 {
+        assert!(
+            offset % 4 == 0 && offset + 4 <= self.len,
+            "Bad MMIO offset {:#x}",
+            offset
+        );
+        unsafe { self.as_ptr().add(offset).cast() }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { self.reg32(offset).read_volatile() }
+    }
+
+    pub fn write32(&self, offset: usize, val: u32) {
+        unsafe { self.reg32(offset).write_volatile(val) }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let pml4 = Cr3::read().0;
+        let mut tlb = TlbFlush::active();
+        for i in 0..self.pages {
+            let addr = self.base + i * PAGE_SIZE;
+            unsafe {
+                if let Some(entry) = leaf_entry(pml4, addr) {
+                    entry.set_unused();
+                    tlb.add(addr, true);
+                }
+            }
+        }
+        tlb.sync();
+        space::kernel_mappings_changed();
+        // Nothing is mapped anymore, so no frames are released
+        GLOBAL_VM_ALLOC.lock().free(self.base).unwrap();
+
+        let first = self.phys.as_u64() / PAGE_SIZE;
+        let end = first + self.pages;
+        let mut regions = REGIONS.lock();
+        let i = regions
+            .iter()
+            .position(|r| *r == (first, end, self.mode))
+            .unwrap();
+        regions.remove(i);
+
+        for pfn in first..end {
+            if !regions.iter().any(|&(s, e, _)| (s..e).contains(&pfn)) {
+                unsafe { set_alias_mode(pfn, CacheMode::WriteBack, &mut tlb) };
+            }
+        }
+    }
+}
+
+fn alias_of(pfn: u64) -> VirtAddr {
+    VirtAddr::new(PHYS_MAP_OFFSET + pfn * PAGE_SIZE)
+}
+
+/// Give the physical map's page of `pfn` the mode `mode`, if the physical map covers it
+///
+/// `ioremap` split the huge page covering it beforehand, splits are never undone.
+unsafe fn set_alias_mode(pfn: u64, mode: CacheMode, tlb: &mut TlbFlush) {
+    let alias = alias_of(pfn);
+    if let Some(entry) = leaf_entry(Cr3::read().0, alias) {
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            entry.set_flags((entry.flags() - PTE_CACHE_MASK) | mode.pte_flags());
+            tlb.add(alias, true);
+        }
+    }
+}
+
+/// Map `len` bytes of device memory at `phys` with `mode`
+pub fn ioremap(phys: PhysAddr, len: usize, mode: CacheMode) -> Result<MmioRegion, MmioError> {
+    assert!(len > 0, "Empty MMIO region");
+    let first = phys.as_u64() / PAGE_SIZE;
+    let end = (phys.as_u64() + len as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+    let pages = end - first;
+
+    if contains_ram(first, end) {
+        return Err(MmioError::Ram);
+    }
+
+    // Before anything is changed, so running out of memory leaves no modes half set
+    for pfn in first..end {
+        unsafe { split_huge_page(&mut get_pt(), alias_of(pfn)) }
+            .map_err(|_| MmioError::NoMemory)?;
+    }
+
+    {
+        let mut regions = REGIONS.lock();
+        if let Some(&(_, _, other)) = regions
+            .iter()
+            .find(|&&(s, e, m)| s < end && first < e && m != mode)
+        {
+            return Err(MmioError::Conflict(other));
+        }
+        regions
+            .try_push((first, end, mode))
+            .map_err(|_| MmioError::TooManyRegions)?;
+    }
+
+    let prot = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::NO_EXECUTE;
+    let base =
+        match GLOBAL_VM_ALLOC
+            .lock()
+            .alloc(pages, VAllocFlags::RESERVE | VAllocFlags::MMIO, prot)
+        {
+            Ok(range) => range.start.start_address(),
+            Err(_) => {
+                REGIONS.lock().pop();
+                return Err(MmioError::NoAddressSpace);
+            }
+        };
+
+    let mut pt = get_pt();
+    let pml4 = Cr3::read().0;
+    let mut tlb = TlbFlush::active();
+    for i in 0..pages {
+        let addr = base + i * PAGE_SIZE;
+        unsafe {
+            // The PAT bit is the huge page bit for the mapper, so the mode is set afterwards
+            pt.map_to(
+                Page::<Size4KiB>::containing_address(addr),
+                PhysFrame::containing_address(PhysAddr::new((first + i) * PAGE_SIZE)),
+                prot,
+                &mut GlobalFrameAllocator,
+            )
+            .expect("MMIO page already mapped")
+            .ignore();
+
+            // It was mapped write-back for a moment, which may be cached
+            let entry = leaf_entry(pml4, addr).unwrap();
+            entry.set_flags(prot | mode.pte_flags());
+            tlb.add(addr, true);
+
+            set_alias_mode(first + i, mode, &mut tlb);
+        }
+    }
+    tlb.flush();
+
+    Ok(MmioRegion {
+        base,
+        pages,
+        offset: (phys.as_u64() % PAGE_SIZE) as usize,
+        len,
+        phys,
+        mode,
+    })
+}
+
+/// Check that RAM and conflicting modes are refused, maps but never touches the local APIC
+pub fn self_test() {
+    let ram = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x100000));
+    let pfn = ram.start_address().as_u64() / PAGE_SIZE;
+    if contains_ram(pfn, pfn + 1) {
+        assert_eq!(
+            ioremap(ram.start_address(), 16, CacheMode::Uncached).unwrap_err(),
+            MmioError::Ram
+        );
+    }
+
+    let apic = PhysAddr::new(0xFEE0_0000);
+    let a = ioremap(apic, 0x400, CacheMode::Uncached).expect("MMIO self-test: ioremap failed");
+    let b = ioremap(apic + 0x100u64, 4, CacheMode::Uncached)
+        .expect("MMIO self-test: same mode overlap refused");
+    assert_eq!(
+        ioremap(apic, 4, CacheMode::WriteCombining).unwrap_err(),
+        MmioError::Conflict(CacheMode::Uncached)
+    );
+    assert_eq!(b.as_ptr() as u64 % PAGE_SIZE, 0x100);
+    drop(a);
+    drop(b);
+
+    let c = ioremap(apic, 4, CacheMode::WriteCombining)
+        .expect("MMIO self-test: mode of a freed range is still taken");
+    drop(c);
+}
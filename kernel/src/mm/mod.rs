@@ -1,16 +1,23 @@
-use crate::{
-    data::late_init::LateInit,
-    sync::irq_lock::IRQLocked,
-};
+use crate::{data::late_init::LateInit, sync::irq_lock::IRQLocked};
 use arrayvec::ArrayVec;
 
-
 use uefi::table::boot::MemoryDescriptor;
 
-
 pub mod alloc;
 mod aux;
+pub mod fault;
+pub mod frame;
+pub mod info;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod mapping;
+pub mod mmio;
+pub mod oom;
+pub mod space;
+pub mod stack;
+pub mod swap;
+pub mod tlb;
+pub mod zero;
 
 pub const SYSTEM_MEMORY_MAP: IRQLocked<LateInit<&'static mut ArrayVec<MemoryDescriptor, 512>>> =
     IRQLocked::new(LateInit::new());
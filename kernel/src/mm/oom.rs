@@ -0,0 +1,122 @@
+//! Out of memory handling
+//!
+//! Subsystems holding memory they can do without register a shrinker. When the buddy allocator
+//! has no block for a request, `reclaim` runs the shrinkers and the allocation is tried once
+//! more. If that fails too the allocation path returns `None` or an error to its caller, only
+//! allocations through liballoc which cannot fail end in `alloc_error`, after the memory counters
+//! were logged.
+
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use arrayvec::ArrayVec;
+use log::error;
+
+use crate::{
+    mm::{
+        alloc::{phys::GLOBAL_PHYS_ALLOC, slab},
+        info,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+const MAX_SHRINKERS: usize = 16;
+
+/// Gives memory back to the buddy allocator
+///
+/// Shrinkers run in the middle of a failed allocation, so they must not allocate and must skip
+/// anything whose lock is held.
+pub type Shrinker = fn();
+
+static SHRINKERS: IRQLocked<ArrayVec<(&'static str, Shrinker), MAX_SHRINKERS>> =
+    IRQLocked::new(ArrayVec::new_const());
+
+/// Set while the shrinkers run, so that an allocation failing meanwhile does not run them again
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+static RECLAIMS: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Copy, Clone)]
+pub struct OomStats {
+    /// Times the shrinkers were run
+    pub reclaims: u64,
+    /// Pages they freed in total
+    pub reclaimed: u64,
+    /// Runs which freed nothing
+    pub failures: u64,
+}
+
+pub fn register_shrinker(name: &'static str, shrinker: Shrinker) {
+    SHRINKERS
+        .lock()
+        .try_push((name, shrinker))
+        .expect("Too many shrinkers");
+}
+
+/// Register the shrinkers of the allocators themselves, after the physical allocator is set up
+pub fn init() {
+    register_shrinker("slab", slab::shrink_all);
+}
+
+fn free_pages() -> u64 {
+    GLOBAL_PHYS_ALLOC.lock().free_pages()
+}
+
+/// Run the shrinkers until `pages` pages were freed, returns whether any were
+///
+/// Must be called without the physical allocator locked. Nothing is logged here, the logger
+/// itself may be the one out of memory.
+pub fn reclaim(pages: u64) -> bool {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    RECLAIMS.fetch_add(1, Ordering::Relaxed);
+
+    // Copied so that a shrinker can register another one
+    let shrinkers = SHRINKERS.lock().clone();
+    let before = free_pages();
+    for (_, shrink) in shrinkers.iter() {
+        shrink();
+        if free_pages().saturating_sub(before) >= pages {
+            break;
+        }
+    }
+    let freed = free_pages().saturating_sub(before);
+
+    RECLAIMED.fetch_add(freed, Ordering::Relaxed);
+    if freed == 0 {
+        FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+    RECLAIMING.store(false, Ordering::Release);
+    freed > 0
+}
+
+pub fn stats() -> OomStats {
+    OomStats {
+        reclaims: RECLAIMS.load(Ordering::Relaxed),
+        reclaimed: RECLAIMED.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+/// Called by liballoc when an allocation which cannot fail did
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    static HANDLING: AtomicBool = AtomicBool::new(false);
+    // Logging the counters ran out of memory as well
+    if HANDLING.swap(true, Ordering::Relaxed) {
+        panic!("Out of memory while reporting out of memory");
+    }
+
+    error!(
+        "Out of memory allocating {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+    info::dump();
+    panic!("Out of memory");
+}
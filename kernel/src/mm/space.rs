@@ -0,0 +1,604 @@
+//! Address spaces
+//!
+//! Every address space owns a PML4. The kernel half, PML4 entries 256 to 511, is shared: all of
+//! its entries are allocated at boot and copied into every new PML4, so kernel mappings made
+//! later show up everywhere. The user half is managed by a VA allocator of its own.
+//!
+//! Forking shares every mapped user page between parent and child. Writable pages become
+//! read-only copy-on-write pages in both, and the first write to one copies it, or takes it
+//! over if no one else maps the frame anymore.
+//!
+//! Every address space is on a list which the swap shrinker goes through, each keeps the position
+//! of its own clock hand.
+
+use core::{
+    ptr::{copy_nonoverlapping, null_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use log::info;
+use x86_64::{
+    instructions::tlb::Pcid,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            page::PageRange, page_table::PageTableEntry, Mapper, OffsetPageTable, Page, PageSize,
+            PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use crate::{
+    arch::{
+        cpu::{self, CpuFeatures},
+        mem::{pt_for, switch_pt},
+        PAGE_SIZE,
+    },
+    data::{late_init::LateInit, misc::Pointable},
+    mm::{
+        alloc::{
+            phys::{alloc_frames, GlobalFrameAllocator, Zone, GLOBAL_PHYS_ALLOC},
+            virt::{
+                KernelVASpace, VAddrDescriptor, VAllocError, VAllocFlags, USER_VIRT_SPACE_END,
+                USER_VIRT_SPACE_START,
+            },
+        },
+        fault,
+        frame::{self, FrameFlags},
+        mapping::leaf_entry,
+        swap,
+        tlb::{self, TlbFlush},
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+/// First PML4 entry of the kernel half
+pub const KERNEL_PML4_START: usize = 256;
+
+/// Marks a page which is read-only because it is shared copy-on-write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+static KERNEL_PML4: LateInit<PhysFrame> = LateInit::new();
+
+/// The user address space currently loaded, null if only the kernel is
+static ACTIVE: AtomicPtr<AddressSpace> = AtomicPtr::new(null_mut());
+
+/// Bumped whenever non-global kernel mappings change, TLB entries kept under a PCID are stale
+/// if it changed since the PCID was last loaded
+static KERNEL_GEN: AtomicU64 = AtomicU64::new(0);
+
+static PCIDS: IRQLocked<PcidMap> = IRQLocked::new(PcidMap::new());
+
+/// Addresses of every address space
+static SPACES: IRQLocked<Vec<usize>> = IRQLocked::new(Vec::new());
+
+/// Allocation bitmap of PCIDs, 0 belongs to the kernel
+struct PcidMap([u64; 64]);
+
+impl PcidMap {
+    const fn new() -> Self {
+        let mut map = [0; 64];
+        map[0] = 1;
+        Self(map)
+    }
+
+    fn alloc(&mut self) -> Option<Pcid> {
+        let (i, word) = self.0.iter_mut().enumerate().find(|(_, w)| **w != !0)?;
+        let bit = (!*word).trailing_zeros() as usize;
+        *word |= 1 << bit;
+        Pcid::new((i * 64 + bit) as u16).ok()
+    }
+
+    fn free(&mut self, pcid: Pcid) {
+        let id = pcid.value() as usize;
+        self.0[id / 64] &= !(1 << (id % 64));
+    }
+}
+
+/// Allocate every kernel PML4 entry so that copies of them never go out of date
+pub fn init() {
+    let (pml4, _) = Cr3::read();
+    let table = unsafe { pml4.pointer().cast::<PageTable>().as_mut() };
+
+    let mut count = 0;
+    for entry in table.iter_mut().skip(KERNEL_PML4_START) {
+        if entry.is_unused() {
+            let frame =
+                alloc_frames(0, Zone::Normal, true).expect("No memory for kernel page tables");
+            frame::of(frame).insert_flags(FrameFlags::PAGE_TABLE | FrameFlags::PINNED);
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            count += 1;
+        }
+    }
+
+    KERNEL_PML4.init(pml4);
+    tlb::set_loaded(pml4);
+    info!("Kernel address space: {} PML4 entries preallocated", count);
+}
+
+/// Note that kernel mappings were removed or changed
+pub fn kernel_mappings_changed() {
+    KERNEL_GEN.fetch_add(1, Ordering::AcqRel);
+}
+
+/// The user address space currently loaded
+pub fn active() -> Option<&'static AddressSpace> {
+    unsafe { ACTIVE.load(Ordering::Acquire).as_ref() }
+}
+
+/// Load the kernel's own page table, which has no user mappings
+pub unsafe fn switch_to_kernel() {
+    switch_pt(*KERNEL_PML4, Pcid::new(0).unwrap(), false);
+    tlb::set_loaded(*KERNEL_PML4);
+    ACTIVE.store(null_mut(), Ordering::Release);
+}
+
+/// A user address space
+///
+/// Always boxed, the active one is referenced by address.
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    pcid: Option<Pcid>,
+    vas: IRQLocked<KernelVASpace>,
+    /// The page tables changed while another address space was loaded
+    stale: AtomicBool,
+    /// `KERNEL_GEN` when this PCID was last loaded
+    kernel_gen: AtomicU64,
+    /// Where the swap clock continues
+    clock: AtomicU64,
+}
+
+impl AddressSpace {
+    /// An empty address space, `None` if out of memory
+    pub fn new() -> Option<Box<Self>> {
+        let pml4 = alloc_frames(0, Zone::Normal, true)?;
+        frame::of(pml4).insert_flags(FrameFlags::PAGE_TABLE);
+        unsafe {
+            let kernel = KERNEL_PML4.pointer().cast::<PageTable>().as_ref();
+            let table = pml4.pointer().cast::<PageTable>().as_mut();
+            for (dst, src) in table.iter_mut().zip(kernel.iter()).skip(KERNEL_PML4_START) {
+                *dst = src.clone();
+            }
+        }
+
+        let pcid = if cpu::has(CpuFeatures::PCID) {
+            PCIDS.lock().alloc()
+        } else {
+            None
+        };
+
+        let space = Box::new(Self {
+            pml4,
+            pcid,
+            vas: IRQLocked::new(KernelVASpace::with_root(
+                USER_VIRT_SPACE_START / Size4KiB::SIZE,
+                USER_VIRT_SPACE_END / Size4KiB::SIZE,
+                pml4,
+            )),
+            // The PCID may have been used by a dropped address space
+            stale: AtomicBool::new(true),
+            kernel_gen: AtomicU64::new(0),
+            clock: AtomicU64::new(USER_VIRT_SPACE_START),
+        });
+        SPACES.lock().push(&*space as *const _ as usize);
+        Some(space)
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    pub fn page_table(&self) -> OffsetPageTable<'static> {
+        pt_for(self.pml4)
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    /// Reserve user pages anywhere, `prot` is made user accessible
+    pub fn map(
+        &self,
+        pages: u64,
+        flags: VAllocFlags,
+        prot: PageTableFlags,
+    ) -> Result<PageRange, VAllocError> {
+        self.vas
+            .lock()
+            .alloc(pages, flags, prot | PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    /// Reserve user pages at `addr`
+    pub fn map_at(
+        &self,
+        addr: VirtAddr,
+        pages: u64,
+        flags: VAllocFlags,
+        prot: PageTableFlags,
+    ) -> Result<PageRange, VAllocError> {
+        self.vas
+            .lock()
+            .alloc_at(addr, pages, flags, prot | PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    /// Unmap and free every page in `range`
+    pub fn unmap(&self, range: PageRange) {
+        self.vas.lock().free_range(range);
+        self.touched();
+    }
+
+    /// Change the protection of the region starting at `addr`
+    pub fn protect(&self, addr: VirtAddr, prot: PageTableFlags) -> Result<(), VAllocError> {
+        self.vas
+            .lock()
+            .protect(addr, prot | PageTableFlags::USER_ACCESSIBLE)?;
+        self.touched();
+        Ok(())
+    }
+
+    /// The region containing `addr`
+    pub fn find(&self, addr: VirtAddr) -> Option<(PageRange, VAddrDescriptor)> {
+        self.vas.lock().find(addr)
+    }
+
+    /// Resolve a fault in this address space, which must be active
+    pub fn handle_fault(&self, addr: VirtAddr, code: PageFaultErrorCode) -> bool {
+        match unsafe { leaf_entry(self.pml4, addr) } {
+            Some(entry) if swap::is_swapped(entry) => self.swap_in(addr, entry, code),
+            _ => fault::demand_page(&self.vas, addr, code),
+        }
+    }
+
+    /// Read back the swapped out page at `addr`
+    fn swap_in(
+        &self,
+        addr: VirtAddr,
+        entry: &mut PageTableEntry,
+        code: PageFaultErrorCode,
+    ) -> bool {
+        if self.vas.is_locked() {
+            return false;
+        }
+        // Keeps the swap shrinker out of this address space
+        let vas = self.vas.lock();
+        match vas.find(addr) {
+            Some((_, vad)) if fault::access_allowed(&vad, code) => {
+                swap::swap_in(entry, vad.prot | PageTableFlags::PRESENT)
+            }
+            _ => false,
+        }
+    }
+
+    /// Swap out up to `pages` pages which were not accessed lately, returns how many were
+    pub fn swap_out(&self, pages: usize) -> usize {
+        // A fault or a change in this address space ran out of memory
+        if self.vas.is_locked() {
+            return 0;
+        }
+        let _vas = self.vas.lock();
+        let mut hand = self.clock.load(Ordering::Relaxed);
+        let done = unsafe { swap::scan(self.pml4, &mut hand, pages) };
+        self.clock.store(hand, Ordering::Relaxed);
+        self.touched();
+        done
+    }
+
+    /// Resolve a write to a copy-on-write page, returns `false` if `addr` is not one
+    pub fn handle_cow(&self, addr: VirtAddr) -> bool {
+        if self.vas.is_locked() {
+            return false;
+        }
+        match self.vas.lock().find(addr) {
+            Some((_, vad)) if vad.prot.contains(PageTableFlags::WRITABLE) => (),
+            _ => return false,
+        }
+
+        let entry = match unsafe { leaf_entry(self.pml4, addr) } {
+            Some(entry) if entry.flags().contains(PageTableFlags::PRESENT | COW) => entry,
+            _ => return false,
+        };
+
+        let old = entry.frame().unwrap();
+        let mut tlb = TlbFlush::new(self.pml4);
+        tlb.add(addr, false);
+        let flags = (entry.flags() - COW) | PageTableFlags::WRITABLE;
+        if frame::of(old).refcount() == 1 {
+            // Every other mapping is gone already
+            entry.set_flags(flags);
+        } else {
+            let new = match alloc_frames(0, Zone::Normal, false) {
+                Some(new) => new,
+                None => return false,
+            };
+            unsafe {
+                copy_nonoverlapping(
+                    old.pointer().as_ptr(),
+                    new.pointer().as_ptr(),
+                    PAGE_SIZE as usize,
+                )
+            };
+
+            let info = frame::of(new);
+            info.inc_map_count();
+            info.insert_flags(FrameFlags::USER);
+            entry.set_frame(new, flags);
+
+            frame::of(old).dec_map_count();
+            // Other CPUs may still read the old frame through their TLB
+            tlb.sync();
+            unsafe { frame::put_frame(old) };
+        }
+
+        tlb.flush();
+        true
+    }
+
+    /// A copy of this address space sharing all mapped pages copy-on-write
+    pub fn fork(&self) -> Option<Box<AddressSpace>> {
+        let child = AddressSpace::new()?;
+        let vas = self.vas.lock();
+        *child.vas.lock() = vas.duplicate(child.pml4);
+
+        let mut child_pt = child.page_table();
+        let pages = vas
+            .regions()
+            .into_iter()
+            .flat_map(|(r, vad)| r.map(move |page| (page, vad)));
+        let shared = pages.try_for_each(|(page, vad)| {
+            let entry = match unsafe { leaf_entry(self.pml4, page.start_address()) } {
+                // Swap slots have a single owner, the page is brought back before it is shared
+                Some(entry) if swap::is_swapped(entry) => {
+                    if !swap::swap_in(entry, vad.prot | PageTableFlags::PRESENT) {
+                        return None;
+                    }
+                    entry
+                }
+                Some(entry) if entry.flags().contains(PageTableFlags::PRESENT) => entry,
+                _ => return Some(()),
+            };
+
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COW;
+                entry.set_flags(flags);
+            }
+
+            let frame = entry.frame().unwrap();
+            unsafe {
+                child_pt
+                    .map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        PageTableFlags::PRESENT
+                            | PageTableFlags::WRITABLE
+                            | PageTableFlags::USER_ACCESSIBLE,
+                        &mut GlobalFrameAllocator,
+                    )
+                    .ok()?
+                    .ignore()
+            };
+
+            let info = frame::of(frame);
+            info.get();
+            info.inc_map_count();
+            Some(())
+        });
+        drop(vas);
+
+        // Writable entries may be cached even if the fork failed half way
+        if self.is_active() {
+            let mut tlb = TlbFlush::new(self.pml4);
+            tlb.add_range(user_range(), false);
+            tlb.flush();
+        } else {
+            self.stale.store(true, Ordering::Release);
+        }
+
+        shared.map(|_| child)
+    }
+
+    /// Invlpg only reaches the active PCID, others have to be flushed when loaded
+    fn touched(&self) {
+        if !self.is_active() {
+            self.stale.store(true, Ordering::Release);
+        }
+    }
+
+    /// Load this address space
+    pub unsafe fn switch(&self) {
+        match self.pcid {
+            Some(pcid) => {
+                let gen = KERNEL_GEN.load(Ordering::Acquire);
+                let stale = self.stale.swap(false, Ordering::AcqRel);
+                let kernel_stale = self.kernel_gen.swap(gen, Ordering::AcqRel) != gen;
+                switch_pt(self.pml4, pcid, !stale && !kernel_stale)
+            }
+            None => switch_pt(self.pml4, Pcid::new(0).unwrap(), false),
+        }
+        tlb::set_loaded(self.pml4);
+        ACTIVE.store(self as *const _ as *mut _, Ordering::Release);
+    }
+}
+
+/// Swap out up to `pages` pages, going through the address spaces in turn; returns how many were
+pub fn swap_out_some(pages: usize) -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    // Growing the list ran out of memory
+    if SPACES.is_locked() {
+        return 0;
+    }
+
+    let spaces = SPACES.lock();
+    let start = NEXT.load(Ordering::Relaxed);
+    let mut done = 0;
+    for i in 0..spaces.len() {
+        let index = (start + i) % spaces.len();
+        let space = unsafe { &*(spaces[index] as *const AddressSpace) };
+        done += space.swap_out(pages - done);
+        if done >= pages {
+            NEXT.store(index + 1, Ordering::Relaxed);
+            break;
+        }
+    }
+    done
+}
+
+/// Every page of the user half
+fn user_range() -> PageRange {
+    PageRange {
+        start: Page::containing_address(VirtAddr::new(USER_VIRT_SPACE_START)),
+        end: Page::containing_address(VirtAddr::new(USER_VIRT_SPACE_END)),
+    }
+}
+
+/// Free a page table at `level`, 1 being the last one, and the tables below it
+unsafe fn free_table(table: PhysFrame, level: u8) {
+    if level > 1 {
+        for entry in table.pointer().cast::<PageTable>().as_ref().iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                free_table(entry.frame().unwrap(), level - 1);
+            }
+        }
+    }
+    GLOBAL_PHYS_ALLOC.lock().free_page(table);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let this = self as *const _ as usize;
+        SPACES.lock().retain(|space| *space != this);
+
+        if self.is_active() {
+            unsafe { switch_to_kernel() }
+        }
+
+        self.vas.lock().free_range(user_range());
+
+        unsafe {
+            let table = self.pml4.pointer().cast::<PageTable>().as_ref();
+            for entry in table.iter().take(KERNEL_PML4_START) {
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    free_table(entry.frame().unwrap(), 3);
+                }
+            }
+            GLOBAL_PHYS_ALLOC.lock().free_page(self.pml4);
+        }
+
+        if let Some(pcid) = self.pcid {
+            PCIDS.lock().free(pcid);
+        }
+    }
+}
+
+/// Check isolation, demand paging, fork and that dropping frees the page tables
+pub fn self_test() {
+    let prot = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let a = AddressSpace::new().expect("Space self-test: out of memory");
+    let b = AddressSpace::new().expect("Space self-test: out of memory");
+
+    let addr = VirtAddr::new(0x4000_0000);
+    let committed = a
+        .map_at(addr, 4, VAllocFlags::RESERVE | VAllocFlags::COMMIT, prot)
+        .unwrap();
+    let reserved = a.map(16, VAllocFlags::RESERVE, prot).unwrap();
+    b.map_at(addr, 1, VAllocFlags::RESERVE | VAllocFlags::COMMIT, prot)
+        .unwrap();
+
+    let data = a.page_table().translate_addr(addr).unwrap();
+    assert_ne!(
+        Some(data),
+        b.page_table().translate_addr(addr),
+        "Space self-test: address spaces share a page"
+    );
+    let pdpt =
+        unsafe { a.pml4.pointer().cast::<PageTable>().as_ref()[addr.p4_index()].frame() }.unwrap();
+
+    unsafe {
+        a.switch();
+        cpu::with_user_access(|| {
+            let ptr = committed.start.start_address().as_mut_ptr::<u64>();
+            ptr.write_volatile(0xC0FFEE);
+            // Demand paged through the active address space
+            let ptr = (reserved.start + 3).start_address().as_mut_ptr::<u64>();
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(1);
+        });
+        b.switch();
+        cpu::with_user_access(|| {
+            assert_eq!(addr.as_ptr::<u64>().read_volatile(), 0);
+        });
+        switch_to_kernel();
+    }
+    assert!(a.page_table().translate_page(reserved.start + 3).is_ok());
+
+    // Nothing is allocated after the frames are freed, so they are still free here
+    drop(a);
+    for frame in [PhysFrame::containing_address(data), pdpt] {
+        assert_eq!(
+            frame::of(frame).refcount(),
+            0,
+            "Space self-test: frame leaked"
+        );
+    }
+    drop(b);
+
+    // Fork, then write on both sides
+    let parent = AddressSpace::new().expect("Space self-test: out of memory");
+    let pages = parent
+        .map_at(addr, 2, VAllocFlags::RESERVE | VAllocFlags::COMMIT, prot)
+        .unwrap();
+    let (first, second) = (pages.start, pages.start + 1);
+    unsafe {
+        parent.switch();
+        cpu::with_user_access(|| {
+            first.start_address().as_mut_ptr::<u64>().write_volatile(1);
+            second.start_address().as_mut_ptr::<u64>().write_volatile(2);
+        });
+    }
+
+    let child = parent.fork().expect("Space self-test: fork failed");
+    let shared = parent.page_table().translate_page(first).unwrap();
+    assert_eq!(Some(shared), child.page_table().translate_page(first).ok());
+    assert_eq!(frame::of(shared).refcount(), 2);
+
+    unsafe {
+        cpu::with_user_access(|| {
+            first.start_address().as_mut_ptr::<u64>().write_volatile(10);
+        });
+        child.switch();
+        cpu::with_user_access(|| {
+            let ptr = first.start_address().as_mut_ptr::<u64>();
+            assert_eq!(
+                ptr.read_volatile(),
+                1,
+                "Space self-test: parent write leaked"
+            );
+            ptr.write_volatile(20);
+            assert_eq!(second.start_address().as_ptr::<u64>().read_volatile(), 2);
+        });
+        parent.switch();
+        cpu::with_user_access(|| {
+            assert_eq!(first.start_address().as_ptr::<u64>().read_volatile(), 10);
+        });
+        switch_to_kernel();
+    }
+
+    // The child was the last user of the original frame and took it over
+    assert_eq!(child.page_table().translate_page(first).ok(), Some(shared));
+    assert_ne!(parent.page_table().translate_page(first).ok(), Some(shared));
+    assert_eq!(
+        parent.page_table().translate_page(second).ok(),
+        child.page_table().translate_page(second).ok()
+    );
+
+    drop(child);
+    drop(parent);
+
+    info!("Address space self-test passed");
+}
@@ -0,0 +1,185 @@
+//! Kernel stacks
+//!
+//! Stacks live in a fixed region above the heap arena, split into slots of `SLOT_PAGES`. A stack
+//! is mapped at the top of its slot and the rest of the slot is left unmapped and never demand
+//! paged, so every stack has at least one guard page below it and an overflow faults instead of
+//! corrupting memory. The fault handlers name the overflowed stack with `guard_owner`.
+
+use boot_lib::{KERNEL_STACK_BOTTOM, KERNEL_STACK_SIZE_PAGES};
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
+
+use crate::{
+    arch::PAGE_SIZE,
+    mm::alloc::virt::{alloc_and_map_at, unmap_and_free, STACK_REGION_END, STACK_REGION_START},
+    sync::irq_lock::IRQLocked,
+};
+
+/// 1 MiB per stack
+const SLOT_PAGES: u64 = 256;
+const SLOT_SIZE: u64 = SLOT_PAGES * PAGE_SIZE;
+const SLOTS: usize = ((STACK_REGION_END - STACK_REGION_START) / SLOT_SIZE) as usize;
+
+/// Largest stack, one page of the slot is always a guard
+pub const MAX_STACK_PAGES: u64 = SLOT_PAGES - 1;
+
+/// Name of the stack the bootloader set up, which runs the executor
+const BOOT_STACK: &str = "main";
+
+struct Slots {
+    used: [u64; SLOTS / 64],
+    names: [&'static str; SLOTS],
+    /// Size of the stack in each slot, to tell its guard pages from a stray access
+    pages: [u64; SLOTS],
+}
+
+static SLOTS: IRQLocked<Slots> = IRQLocked::new(Slots {
+    used: [0; SLOTS / 64],
+    names: [""; SLOTS],
+    pages: [0; SLOTS],
+});
+
+impl Slots {
+    fn take(&mut self) -> Option<usize> {
+        let (i, word) = self.used.iter_mut().enumerate().find(|(_, w)| **w != !0)?;
+        let bit = (!*word).trailing_zeros() as usize;
+        *word |= 1 << bit;
+        Some(i * 64 + bit)
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.used[slot / 64] &= !(1 << (slot % 64));
+    }
+}
+
+/// A kernel stack, unmapped and freed on drop
+#[derive(Debug)]
+pub struct Stack {
+    slot: usize,
+    pages: u64,
+}
+
+impl Stack {
+    fn slot_base(&self) -> u64 {
+        STACK_REGION_START + self.slot as u64 * SLOT_SIZE
+    }
+
+    /// Initial stack pointer, the end of the stack
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(self.slot_base() + SLOT_SIZE)
+    }
+
+    /// Lowest mapped address
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * PAGE_SIZE
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    pub fn name(&self) -> &'static str {
+        SLOTS.lock().names[self.slot]
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            unmap_and_free(PageRange {
+                start: Page::containing_address(self.bottom()),
+                end: Page::containing_address(self.top()),
+            })
+        };
+        SLOTS.lock().release(self.slot);
+    }
+}
+
+/// Allocate a stack of `pages` pages for the thread `name`
+///
+/// `None` if all slots are taken or there is no memory for the stack.
+pub fn alloc(name: &'static str, pages: u64) -> Option<Stack> {
+    assert!(
+        pages > 0 && pages <= MAX_STACK_PAGES,
+        "Bad stack size: {} pages",
+        pages
+    );
+
+    let slot = {
+        let mut slots = SLOTS.lock();
+        let slot = slots.take()?;
+        slots.names[slot] = name;
+        slots.pages[slot] = pages;
+        slot
+    };
+
+    // Dropped on failure, which gives the slot back
+    let stack = Stack { slot, pages };
+    alloc_and_map_at(
+        stack.bottom(),
+        pages,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::GLOBAL
+            | PageTableFlags::NO_EXECUTE,
+    )
+    .ok()?;
+    Some(stack)
+}
+
+/// Name of the thread whose stack overflowed into `addr`, if it is in a guard page
+pub fn guard_owner(addr: VirtAddr) -> Option<&'static str> {
+    let addr = addr.as_u64();
+
+    // The page below the boot stack is never mapped
+    let boot_bottom = KERNEL_STACK_BOTTOM - (KERNEL_STACK_SIZE_PAGES - 1) * PAGE_SIZE;
+    if addr < boot_bottom && addr >= boot_bottom - PAGE_SIZE {
+        return Some(BOOT_STACK);
+    }
+
+    if !(STACK_REGION_START..STACK_REGION_END).contains(&addr) {
+        return None;
+    }
+    // The fault may have interrupted an allocation
+    if SLOTS.is_locked() {
+        return None;
+    }
+    let slot = ((addr - STACK_REGION_START) / SLOT_SIZE) as usize;
+    let slots = SLOTS.lock();
+    let top = STACK_REGION_START + (slot as u64 + 1) * SLOT_SIZE;
+    let bottom = top - slots.pages[slot] * PAGE_SIZE;
+    if slots.is_used(slot) && addr < bottom {
+        Some(slots.names[slot])
+    } else {
+        None
+    }
+}
+
+/// Check that stacks are mapped, reused after free and guarded
+pub fn self_test() {
+    let a = alloc("self-test a", 4).expect("Stack self-test: alloc failed");
+    let b = alloc("self-test b", MAX_STACK_PAGES).expect("Stack self-test: alloc failed");
+
+    unsafe {
+        let top = (a.top().as_u64() - 8) as *mut u64;
+        top.write_volatile(0x57ac);
+        let low = a.bottom().as_mut_ptr::<u64>();
+        low.write_volatile(0x57ac);
+    }
+    assert_eq!(guard_owner(a.bottom() - 1u64), Some("self-test a"));
+    assert_eq!(guard_owner(b.bottom() - 1u64), Some("self-test b"));
+    assert_eq!(guard_owner(a.bottom()), None);
+
+    let slot = a.slot;
+    drop(a);
+    let guard = VirtAddr::new(STACK_REGION_START + slot as u64 * SLOT_SIZE);
+    assert_eq!(guard_owner(guard), None);
+    let c = alloc("self-test c", 8).expect("Stack self-test: alloc failed");
+    assert_eq!(c.slot, slot, "Stack self-test: freed slot was not reused");
+}
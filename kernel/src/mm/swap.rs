@@ -0,0 +1,493 @@
+//! Swapping anonymous user pages to disk
+//!
+//! The swap area is a disk or an MBR partition of type 0x82 prepared with `mkswap`, its first page
+//! is the header and every other page a slot. Once memory runs out the swap shrinker runs a clock
+//! over the user pages of each address space in turn: a page accessed since the hand last passed
+//! has its accessed bit cleared, one which was not is written to a free slot. The page table entry
+//! then keeps the slot number with `SWAPPED` set instead of the frame, and the page fault handler
+//! reads the page back into a new frame.
+//!
+//! Only pages mapped exactly once are swapped out, so each slot has one owner. Fork brings
+//! swapped out pages back in before sharing them, and unmapping a swapped out page frees its slot.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    convert::TryInto,
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::{error, info};
+use x86_64::{
+    structures::paging::{
+        page::PageRange, page_table::PageTableEntry, PageTable, PageTableFlags, PhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    arch::{cpu, mem::page_to_pfn, PAGE_SIZE},
+    cmdline,
+    data::misc::Pointable,
+    device::block::{self, BlockDevice, SECTOR_SIZE},
+    mm::{
+        alloc::{
+            phys::{alloc_frames, free_frames, Zone},
+            virt::VAllocFlags,
+        },
+        frame::{self, FrameFlags},
+        mapping::leaf_entry,
+        oom,
+        space::{self, AddressSpace, KERNEL_PML4_START},
+        tlb::TlbFlush,
+    },
+    sync::irq_lock::IRQLocked,
+};
+
+/// Marks a non-present entry whose address bits hold a swap slot
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// MBR partition type of swap partitions
+const SWAP_PARTITION: u8 = 0x82;
+const SIGNATURE: &[u8] = b"SWAPSPACE2";
+const HEADER_VERSION: u32 = 1;
+/// Where the version, the last page and the bad page count are in the header
+const HEADER_INFO: usize = 1024;
+const HEADER_BAD_PAGES: usize = 1536;
+
+const SECTORS_PER_PAGE: u64 = PAGE_SIZE / SECTOR_SIZE as u64;
+/// Pages swapped out by one run of the shrinker
+const SWAP_BATCH: usize = 64;
+
+static SWAP: IRQLocked<Option<SwapArea>> = IRQLocked::new(None);
+
+static SWAPPED_OUT: AtomicU64 = AtomicU64::new(0);
+static SWAPPED_IN: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SwapStats {
+    /// Slots in the swap area, 0 without one
+    pub total: u64,
+    pub used: u64,
+    pub swapped_out: u64,
+    pub swapped_in: u64,
+    /// Pages which could not be written out
+    pub errors: u64,
+}
+
+/// The header page written by `mkswap`
+struct Header([u8; PAGE_SIZE as usize]);
+
+impl Header {
+    fn read(dev: &mut dyn BlockDevice, start: u64) -> Option<Self> {
+        let mut header = Header([0; PAGE_SIZE as usize]);
+        dev.read(start, &mut header.0).ok()?;
+        if !header.0.ends_with(SIGNATURE) || header.word(HEADER_INFO) != HEADER_VERSION {
+            return None;
+        }
+        Some(header)
+    }
+
+    fn word(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn last_page(&self) -> u64 {
+        self.word(HEADER_INFO + 4) as u64
+    }
+
+    fn bad_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        let max = (self.0.len() - SIGNATURE.len() - HEADER_BAD_PAGES) / 4;
+        let count = (self.word(HEADER_INFO + 8) as usize).min(max);
+        (0..count).map(move |i| self.word(HEADER_BAD_PAGES + i * 4) as u64)
+    }
+}
+
+struct SwapArea {
+    dev: Box<dyn BlockDevice>,
+    /// First sector of the header
+    start: u64,
+    /// Bitmap of used slots, the header and bad pages are always used
+    used: Vec<u64>,
+    total: u64,
+    free: u64,
+    /// Word of `used` the search for a free slot starts at
+    hint: usize,
+}
+
+impl SwapArea {
+    fn open(mut dev: Box<dyn BlockDevice>, start: u64, sectors: u64) -> Option<Self> {
+        let header = Header::read(dev.as_mut(), start)?;
+        let slots = (header.last_page() + 1).min(sectors / SECTORS_PER_PAGE);
+        let words = (slots as usize + 63) / 64;
+
+        let mut used = vec![0; words];
+        // Past the end
+        for slot in slots..words as u64 * 64 {
+            used[slot as usize / 64] |= 1 << (slot % 64);
+        }
+        let mut area = Self {
+            dev,
+            start,
+            used,
+            total: slots,
+            free: slots,
+            hint: 0,
+        };
+        area.reserve(0);
+        for page in header.bad_pages().filter(|page| *page < slots) {
+            area.reserve(page);
+        }
+        area.total = area.free;
+        Some(area)
+    }
+
+    fn reserve(&mut self, slot: u64) {
+        let (word, bit) = (slot as usize / 64, 1 << (slot % 64));
+        if self.used[word] & bit == 0 {
+            self.used[word] |= bit;
+            self.free -= 1;
+        }
+    }
+
+    fn alloc(&mut self) -> Option<u64> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.used.len();
+        let word = (0..words)
+            .map(|i| (self.hint + i) % words)
+            .find(|w| self.used[*w] != !0)?;
+        let bit = (!self.used[word]).trailing_zeros() as u64;
+        self.used[word] |= 1 << bit;
+        self.free -= 1;
+        self.hint = word;
+        Some(word as u64 * 64 + bit)
+    }
+
+    fn free(&mut self, slot: u64) {
+        let (word, bit) = (slot as usize / 64, 1 << (slot % 64));
+        assert!(self.used[word] & bit != 0, "Freeing a free swap slot");
+        self.used[word] &= !bit;
+        self.free += 1;
+    }
+
+    fn lba(&self, slot: u64) -> u64 {
+        self.start + slot * SECTORS_PER_PAGE
+    }
+}
+
+/// The start and size of a swap area on `dev`, either the whole disk or a swap partition
+fn find_area(dev: &mut dyn BlockDevice) -> Option<(u64, u64)> {
+    if Header::read(dev, 0).is_some() {
+        return Some((0, dev.sectors()));
+    }
+    block::partitions(dev)
+        .ok()?
+        .into_iter()
+        .filter(|part| part.kind == SWAP_PARTITION)
+        .map(|part| (part.start, part.sectors))
+        .find(|(start, _)| Header::read(dev, *start).is_some())
+}
+
+/// Take the first block device with a swap area, after the disks were probed
+pub fn init() {
+    if cmdline::has("noswap") {
+        info!("Swap disabled on the command line");
+        return;
+    }
+
+    let mut found = None;
+    let dev = block::take(|dev| {
+        found = find_area(dev);
+        found.is_some()
+    });
+    let (dev, (start, sectors)) = match dev.zip(found) {
+        Some(res) => res,
+        None => {
+            info!("No swap area found");
+            return;
+        }
+    };
+
+    info!("Swap area on {} at sector {}", dev.name(), start);
+    let area = match SwapArea::open(dev, start, sectors) {
+        Some(area) => area,
+        None => {
+            error!("Could not read the swap area header");
+            return;
+        }
+    };
+    info!("Swap: {} KiB", area.total * PAGE_SIZE >> 10);
+    *SWAP.lock() = Some(area);
+    oom::register_shrinker("swap", shrink);
+}
+
+pub fn enabled() -> bool {
+    SWAP.lock().is_some()
+}
+
+fn shrink() {
+    space::swap_out_some(SWAP_BATCH);
+}
+
+/// The slot of a swapped out page
+fn slot_of(entry: &PageTableEntry) -> Option<u64> {
+    let flags = entry.flags() & (PageTableFlags::PRESENT | SWAPPED);
+    (flags == SWAPPED).then(|| entry.addr().as_u64() / PAGE_SIZE)
+}
+
+pub fn is_swapped(entry: &PageTableEntry) -> bool {
+    slot_of(entry).is_some()
+}
+
+/// Whether the page in `frame` can be swapped out
+fn evictable(frame: PhysFrame) -> bool {
+    let info = match frame::get(page_to_pfn(frame.start_address().as_u64())) {
+        Some(info) => info,
+        None => return false,
+    };
+    let flags = info.flags();
+    flags.contains(FrameFlags::USER)
+        && !flags.intersects(FrameFlags::PINNED | FrameFlags::PAGE_TABLE | FrameFlags::RESERVED)
+        && info.refcount() == 1
+        && info.map_count() == 1
+}
+
+/// Visit the last level user entries at or after `from` in `table`, until `f` returns `false`
+///
+/// `level` is 4 for the PML4, `base` the first address `table` maps. Returns whether every entry
+/// was visited.
+unsafe fn walk(
+    table: PhysFrame,
+    level: u8,
+    base: u64,
+    from: u64,
+    f: &mut impl FnMut(VirtAddr, &mut PageTableEntry) -> bool,
+) -> bool {
+    let shift = 12 + 9 * (level as u64 - 1);
+    let entries = if level == 4 { KERNEL_PML4_START } else { 512 };
+    let first = if from > base {
+        ((from - base) >> shift) as usize
+    } else {
+        0
+    };
+
+    let table = table.pointer().cast::<PageTable>().as_mut();
+    for (i, entry) in table.iter_mut().enumerate().take(entries).skip(first) {
+        let addr = base + ((i as u64) << shift);
+        if level == 1 {
+            if !f(VirtAddr::new(addr), entry) {
+                return false;
+            }
+            continue;
+        }
+
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT)
+            && !flags.contains(PageTableFlags::HUGE_PAGE)
+            && !walk(entry.frame().unwrap(), level - 1, addr, from, f)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Run the clock over the user pages of `pml4` from `hand` until `want` pages were swapped out
+///
+/// The hand goes around at most twice, so pages whose accessed bit was cleared on the first round
+/// can be taken on the second. A scan starting in the middle first finishes the lap it is in,
+/// which leaves the pages below the hand unseen, so then the two full rounds follow that partial
+/// one. Returns how many pages were swapped out. The page table must not change meanwhile.
+pub unsafe fn scan(pml4: PhysFrame, hand: &mut u64, want: usize) -> usize {
+    let mut tlb = TlbFlush::new(pml4);
+    let mut evicted = 0;
+    let laps = if *hand == 0 { 2 } else { 3 };
+    for _ in 0..laps {
+        let from = *hand;
+        let done = walk(pml4, 4, 0, from, &mut |addr, entry| {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+                return true;
+            }
+            *hand = addr.as_u64() + PAGE_SIZE;
+            if !evictable(entry.frame().unwrap()) {
+                return true;
+            }
+            if flags.contains(PageTableFlags::ACCESSED) {
+                entry.set_flags(flags - PageTableFlags::ACCESSED);
+                // Otherwise the CPU keeps using the cached entry without setting the bit again
+                tlb.add(addr, false);
+                return true;
+            }
+            if !swap_out(addr, entry, &mut tlb) {
+                return false;
+            }
+            evicted += 1;
+            evicted < want
+        });
+        if !done {
+            break;
+        }
+        *hand = 0;
+    }
+    evicted
+}
+
+/// Write the page mapped by `entry` to a free slot and free its frame
+///
+/// Nothing is logged, this runs when the allocator is out of memory.
+unsafe fn swap_out(addr: VirtAddr, entry: &mut PageTableEntry, tlb: &mut TlbFlush) -> bool {
+    let mut swap = SWAP.lock();
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return false,
+    };
+    let slot = match area.alloc() {
+        Some(slot) => slot,
+        None => return false,
+    };
+
+    // No CPU may write to the page while it is written out
+    let (frame, flags) = (entry.frame().unwrap(), entry.flags());
+    entry.set_addr(PhysAddr::new(slot * PAGE_SIZE), SWAPPED);
+    tlb.add(addr, false);
+    tlb.sync();
+
+    let data = slice::from_raw_parts(frame.pointer().as_ptr(), PAGE_SIZE as usize);
+    let lba = area.lba(slot);
+    if area.dev.write(lba, data).is_err() {
+        area.free(slot);
+        entry.set_frame(frame, flags);
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    drop(swap);
+
+    frame::of(frame).dec_map_count();
+    frame::put_frame(frame);
+    SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Read a swapped out page back into a new frame and map it with `flags`
+///
+/// Returns `false` if there is no memory or the read failed, the page stays swapped out then.
+pub fn swap_in(entry: &mut PageTableEntry, flags: PageTableFlags) -> bool {
+    let slot = match slot_of(entry) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let frame = match alloc_frames(0, Zone::Normal, false) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let mut swap = SWAP.lock();
+    let area = swap.as_mut().expect("Page swapped out without a swap area");
+    let data = unsafe { slice::from_raw_parts_mut(frame.pointer().as_ptr(), PAGE_SIZE as usize) };
+    let lba = area.lba(slot);
+    if let Err(e) = area.dev.read(lba, data) {
+        drop(swap);
+        error!("Reading swap slot {} failed: {:?}", slot, e);
+        unsafe { free_frames(frame, 0) };
+        return false;
+    }
+    area.free(slot);
+    drop(swap);
+
+    let info = frame::of(frame);
+    info.inc_map_count();
+    info.insert_flags(FrameFlags::USER);
+    entry.set_frame(frame, flags);
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Free the slot of a swapped out page and clear its entry, other entries are left alone
+pub fn discard(entry: &mut PageTableEntry) {
+    if let Some(slot) = slot_of(entry) {
+        if let Some(area) = SWAP.lock().as_mut() {
+            area.free(slot);
+        }
+        entry.set_unused();
+    }
+}
+
+pub fn stats() -> SwapStats {
+    let (total, used) = SWAP
+        .lock()
+        .as_ref()
+        .map_or((0, 0), |area| (area.total, area.total - area.free));
+    SwapStats {
+        total,
+        used,
+        swapped_out: SWAPPED_OUT.load(Ordering::Relaxed),
+        swapped_in: SWAPPED_IN.load(Ordering::Relaxed),
+        errors: ERRORS.load(Ordering::Relaxed),
+    }
+}
+
+/// Swap out the pages of an address space, fault them back in and unmap them while swapped out
+pub fn self_test() {
+    if !enabled() {
+        return;
+    }
+    let prot = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let space = AddressSpace::new().expect("Swap self-test: out of memory");
+    let pages = space
+        .map(16, VAllocFlags::RESERVE | VAllocFlags::COMMIT, prot)
+        .unwrap();
+    let used = stats().used;
+
+    let each = |f: &dyn Fn(u64, *mut u64)| unsafe {
+        space.switch();
+        cpu::with_user_access(|| {
+            for (i, page) in pages.enumerate() {
+                f(i as u64, page.start_address().as_mut_ptr());
+            }
+        });
+        space::switch_to_kernel();
+    };
+    each(&|i, ptr| unsafe { ptr.write_volatile(i) });
+
+    // The writes set the accessed bits, so this takes both rounds of the clock
+    assert_eq!(
+        space.swap_out(16),
+        16,
+        "Swap self-test: pages not swapped out"
+    );
+    assert!(
+        pages_swapped(&space, pages),
+        "Swap self-test: entries not swapped"
+    );
+    assert_eq!(stats().used, used + 16);
+
+    each(&|i, ptr| unsafe {
+        assert_eq!(
+            ptr.read_volatile(),
+            i,
+            "Swap self-test: page came back wrong"
+        );
+    });
+    assert_eq!(
+        stats().used,
+        used,
+        "Swap self-test: slots not freed on swap in"
+    );
+
+    // Slots of pages unmapped while swapped out are freed
+    assert_eq!(space.swap_out(16), 16);
+    drop(space);
+    assert_eq!(stats().used, used, "Swap self-test: slots leaked on unmap");
+
+    info!("Swap self-test passed");
+}
+
+fn pages_swapped(space: &AddressSpace, pages: PageRange) -> bool {
+    pages.into_iter().all(|page| {
+        unsafe { leaf_entry(space.pml4(), page.start_address()) }.map_or(false, |e| is_swapped(e))
+    })
+}
@@ -0,0 +1,290 @@
+//! TLB invalidation
+//!
+//! Page table changes are collected in a `TlbFlush` and invalidated together when it is flushed.
+//! Up to `MAX_SINGLE` pages are invalidated one by one with `invlpg`, beyond that a full flush is
+//! cheaper. A full flush keeps global entries unless a global page changed, using INVPCID where
+//! the CPU has it and reloading CR3 or toggling CR4.PGE otherwise.
+//!
+//! `LOADED` tracks which CPUs may cache the entries: those with the page table loaded for user
+//! pages, every CPU for kernel pages. This CPU invalidates its own entries, the others get a
+//! shootdown IPI and run the same invalidation from its handler, while the sender waits for all of
+//! them. One shootdown is in flight at a time, a CPU waiting to send serves the one in flight so
+//! that two senders cannot wait on each other.
+
+use core::{
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+
+use arrayvec::ArrayVec;
+use log::info;
+use x86_64::{
+    instructions::tlb::{self, InvPicdCommand},
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{page::PageRange, OffsetPageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    arch::{
+        apic,
+        cpu::{self, CpuFeatures, MAX_CPUS},
+        interrupt::IntIdx,
+        PAGE_SIZE,
+    },
+    data::misc::Pointable,
+    mm::{
+        alloc::{
+            phys::{alloc_frames, free_frames, Zone},
+            virt::{VAllocFlags, GLOBAL_VM_ALLOC, KERNEL_VIRT_SPACE_START},
+        },
+        mapping::leaf_entry,
+    },
+};
+
+/// Largest batch invalidated page by page
+pub const MAX_SINGLE: usize = 32;
+
+/// A set of CPUs, bit `i` is CPU `i`
+pub type CpuMask = u64;
+
+const NOT_RUNNING: AtomicU64 = AtomicU64::new(0);
+
+/// PML4 each CPU has loaded, 0 if it is not running
+static LOADED: [AtomicU64; MAX_CPUS] = [NOT_RUNNING; MAX_CPUS];
+
+/// A CPU is sending a shootdown
+static SENDING: AtomicBool = AtomicBool::new(false);
+/// The invalidations of the shootdown in flight
+static REQUEST: AtomicPtr<TlbFlush> = AtomicPtr::new(null_mut());
+/// CPUs which have yet to run `REQUEST`
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Note that this CPU loaded `pml4`, called on every address space switch
+pub fn set_loaded(pml4: PhysFrame) {
+    LOADED[cpu::this_cpu()].store(pml4.start_address().as_u64(), Ordering::Release);
+}
+
+fn online() -> CpuMask {
+    cpus_where(|pml4| pml4 != 0) | 1 << cpu::this_cpu()
+}
+
+/// CPUs running with `pml4` loaded
+pub fn cpus_using(pml4: PhysFrame) -> CpuMask {
+    cpus_where(|loaded| loaded == pml4.start_address().as_u64())
+}
+
+fn cpus_where(f: impl Fn(u64) -> bool) -> CpuMask {
+    LOADED
+        .iter()
+        .enumerate()
+        .filter(|(_, pml4)| f(pml4.load(Ordering::Acquire)))
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
+/// Pending invalidations of mappings in one page table
+///
+/// Dropping it flushes, `flush` only makes that explicit. Frames which were unmapped must not be
+/// freed before that.
+pub struct TlbFlush {
+    /// PML4 of the changed mappings
+    root: PhysFrame,
+    pages: ArrayVec<VirtAddr, MAX_SINGLE>,
+    /// Too many pages for `invlpg`
+    full: bool,
+    /// A changed entry was global
+    global: bool,
+    /// A changed page is in the kernel half, which every page table shares
+    kernel: bool,
+}
+
+impl TlbFlush {
+    /// Invalidations for the page table at `root`
+    pub fn new(root: PhysFrame) -> Self {
+        Self {
+            root,
+            pages: ArrayVec::new_const(),
+            full: false,
+            global: false,
+            kernel: false,
+        }
+    }
+
+    /// Invalidations for the active page table
+    pub fn active() -> Self {
+        Self::new(Cr3::read().0)
+    }
+
+    /// Invalidations for `pt`, which need not be active
+    pub fn for_table(pt: &mut OffsetPageTable) -> Self {
+        Self::new(PhysFrame::from_pointer(
+            NonNull::from(pt.level_4_table()).cast(),
+        ))
+    }
+
+    /// The entry mapping `addr` changed, a huge page is added once with any address in it
+    pub fn add(&mut self, addr: VirtAddr, global: bool) {
+        self.global |= global;
+        self.kernel |= addr.as_u64() >= KERNEL_VIRT_SPACE_START;
+        if !self.full && self.pages.try_push(addr).is_err() {
+            self.full = true;
+        }
+    }
+
+    /// Every entry in `range` changed
+    pub fn add_range(&mut self, range: PageRange, global: bool) {
+        let pages = (range.end.start_address() - range.start.start_address()) / PAGE_SIZE;
+        if pages > (MAX_SINGLE - self.pages.len()) as u64 {
+            self.global |= global;
+            // The last page decides, as in `add`
+            self.kernel |= (range.end - 1).start_address().as_u64() >= KERNEL_VIRT_SPACE_START;
+            self.full = true;
+        } else {
+            for page in range {
+                self.add(page.start_address(), global);
+            }
+        }
+    }
+
+    pub fn flush(self) {}
+
+    /// Invalidate everything added so far and start over
+    pub fn sync(&mut self) {
+        if self.pages.is_empty() && !self.full {
+            return;
+        }
+
+        let cpus = if self.kernel {
+            online()
+        } else {
+            cpus_using(self.root)
+        };
+        let this = 1 << cpu::this_cpu();
+        if cpus & this != 0 {
+            self.invalidate_local();
+        }
+        self.shootdown(cpus & !this);
+
+        self.pages.clear();
+        self.full = false;
+        self.global = false;
+        self.kernel = false;
+    }
+
+    /// Have `cpus` invalidate the same entries and wait until they did
+    fn shootdown(&self, cpus: CpuMask) {
+        if cpus == 0 {
+            return;
+        }
+
+        while SENDING
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            serve_shootdown();
+            core::hint::spin_loop();
+        }
+        REQUEST.store(self as *const _ as *mut _, Ordering::Release);
+        PENDING.store(cpus, Ordering::Release);
+
+        for cpu in (0..MAX_CPUS).filter(|cpu| cpus & 1 << cpu != 0) {
+            if !apic::send_ipi(cpu, IntIdx::TlbShootdown.as_u8()) {
+                // Not started yet, it will load its TLB from the new entries
+                PENDING.fetch_and(!(1 << cpu), Ordering::AcqRel);
+            }
+        }
+        while PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+
+        REQUEST.store(null_mut(), Ordering::Relaxed);
+        SENDING.store(false, Ordering::Release);
+    }
+
+    fn invalidate_local(&self) {
+        if !self.full {
+            for addr in self.pages.iter() {
+                tlb::flush(*addr);
+            }
+        } else if self.global {
+            if cpu::has(CpuFeatures::INVPCID) {
+                unsafe { tlb::flush_pcid(InvPicdCommand::All) }
+            } else {
+                // Clearing PGE drops every global entry
+                unsafe {
+                    Cr4::update(|cr4| cr4.remove(Cr4Flags::PAGE_GLOBAL));
+                    Cr4::update(|cr4| cr4.insert(Cr4Flags::PAGE_GLOBAL));
+                }
+            }
+        } else if cpu::has(CpuFeatures::INVPCID) {
+            unsafe { tlb::flush_pcid(InvPicdCommand::Single(Cr3::read_pcid().1)) }
+        } else {
+            tlb::flush_all();
+        }
+    }
+}
+
+impl Drop for TlbFlush {
+    fn drop(&mut self) {
+        self.sync()
+    }
+}
+
+/// Run the shootdown in flight if it includes this CPU, from the IPI handler
+pub fn serve_shootdown() {
+    let this = 1 << cpu::this_cpu();
+    if PENDING.load(Ordering::Acquire) & this != 0 {
+        // The sender keeps the request alive until every CPU cleared its bit
+        unsafe { (*REQUEST.load(Ordering::Acquire)).invalidate_local() };
+        PENDING.fetch_and(!this, Ordering::AcqRel);
+    }
+}
+
+/// Check that both single and full invalidation drop a stale global translation
+pub fn self_test() {
+    let prot = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::NO_EXECUTE;
+    let page = GLOBAL_VM_ALLOC
+        .lock()
+        .alloc(1, VAllocFlags::RESERVE | VAllocFlags::COMMIT, prot)
+        .expect("TLB self-test: alloc failed")
+        .start;
+    let other = alloc_frames(0, Zone::Normal, true).expect("TLB self-test: out of memory");
+
+    let ptr = page.start_address().as_mut_ptr::<u64>();
+    let entry = unsafe { leaf_entry(Cr3::read().0, page.start_address()) }.unwrap();
+    let own = entry.frame().unwrap();
+    unsafe {
+        ptr.write_volatile(1);
+        other.pointer().cast::<u64>().as_ptr().write(2);
+    }
+
+    // A few pages take the invlpg path, the range the full flush
+    for (target, expect, full) in [(other, 2, false), (own, 1, true)] {
+        entry.set_frame(target, prot);
+        let mut tlb = TlbFlush::active();
+        if full {
+            tlb.add_range(
+                PageRange {
+                    start: page,
+                    end: page + MAX_SINGLE as u64 + 1,
+                },
+                true,
+            );
+        } else {
+            tlb.add(page.start_address(), true);
+        }
+        tlb.flush();
+        assert_eq!(
+            unsafe { ptr.read_volatile() },
+            expect,
+            "TLB self-test: stale translation"
+        );
+    }
+
+    unsafe { free_frames(other, 0) };
+    GLOBAL_VM_ALLOC.lock().free(page.start_address()).unwrap();
+    info!("TLB self-test passed");
+}
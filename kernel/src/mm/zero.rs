@@ -0,0 +1,189 @@
+//! Background page zeroing
+//!
+//! An idle task takes dirty free blocks out of the buddy allocator, clears them with non-temporal
+//! stores and gives them back as clean, so that zeroed allocations rarely have to clear memory
+//! under the allocator lock. It stops once the clean pool reaches its target and restarts when
+//! the pool drops below half of it.
+//!
+//! The target is `zeropool=<MiB>` on the command line, by default 1/16 of memory up to 64 MiB.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use log::{info, warn};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::{
+    arch::{mem::zero_nt, PAGE_SIZE},
+    cmdline,
+    data::misc::Pointable,
+    mm::alloc::phys::{Zone, GLOBAL_PHYS_ALLOC, MAX_ORDER},
+    sync::irq_lock::IRQLocked,
+    task::yield_now,
+    time::Stopwatch,
+};
+
+/// Pages cleared per poll, 64 KiB
+const CHUNK_PAGES: u64 = 16;
+
+const DEFAULT_MAX_MIB: u64 = 64;
+
+static TARGET: AtomicU64 = AtomicU64::new(0);
+static WAKER: IRQLocked<Option<Waker>> = IRQLocked::new(None);
+
+static ZEROED: AtomicU64 = AtomicU64::new(0);
+static BUSY_NANOS: AtomicU64 = AtomicU64::new(0);
+static SYNC_ZEROED: AtomicU64 = AtomicU64::new(0);
+
+/// Set the clean pool target, after the physical allocator is up
+pub fn init() {
+    let total: u64 = {
+        let phys = GLOBAL_PHYS_ALLOC.lock();
+        Zone::ALL.iter().map(|&z| phys.stats(z).total).sum()
+    };
+    let default = (total / 16).min((DEFAULT_MAX_MIB << 20) / PAGE_SIZE);
+
+    let target = match cmdline::get("zeropool").map(str::parse::<u64>) {
+        None => default,
+        Some(Ok(mib)) => (mib << 20) / PAGE_SIZE,
+        Some(Err(_)) => {
+            warn!("Invalid zeropool option, using the default");
+            default
+        }
+    };
+    TARGET.store(target, Ordering::Relaxed);
+
+    info!("Clean page pool target: {} MiB", target * PAGE_SIZE >> 20);
+}
+
+/// Clean pages the zeroing task keeps free
+pub fn target() -> u64 {
+    TARGET.load(Ordering::Relaxed)
+}
+
+/// Below this many clean pages the zeroing task is woken up
+pub fn low_watermark() -> u64 {
+    target() / 2
+}
+
+/// Wake the zeroing task if it is waiting, safe to call with the allocator locked
+pub fn kick() {
+    let waker = WAKER.lock().take();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Count pages which an allocation had to clear itself
+pub fn note_sync(pages: u64) {
+    SYNC_ZEROED.fetch_add(pages, Ordering::Relaxed);
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ZeroStats {
+    /// Pages cleared by the background task
+    pub zeroed: u64,
+    /// Time the background task spent clearing them
+    pub busy: Duration,
+    /// Pages cleared on demand by zeroed allocations
+    pub sync_zeroed: u64,
+    pub clean: u64,
+    pub dirty: u64,
+    pub target: u64,
+}
+
+impl ZeroStats {
+    /// Background zeroing speed in bytes per second, 0 if nothing was measured yet
+    pub fn throughput(&self) -> u64 {
+        let nanos = self.busy.as_nanos();
+        if nanos == 0 {
+            0
+        } else {
+            ((self.zeroed * PAGE_SIZE) as u128 * 1_000_000_000 / nanos) as u64
+        }
+    }
+}
+
+pub fn stats() -> ZeroStats {
+    let (clean, free) = {
+        let phys = GLOBAL_PHYS_ALLOC.lock();
+        (phys.clean_pages(), phys.free_pages())
+    };
+
+    ZeroStats {
+        zeroed: ZEROED.load(Ordering::Relaxed),
+        busy: Duration::from_nanos(BUSY_NANOS.load(Ordering::Relaxed)),
+        sync_zeroed: SYNC_ZEROED.load(Ordering::Relaxed),
+        clean,
+        dirty: free - clean,
+        target: target(),
+    }
+}
+
+/// Resolves once the pool is low and there are dirty pages to clear
+struct PoolLow;
+
+impl Future for PoolLow {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // Register first, so that a kick between the check and returning is not lost
+        *WAKER.lock() = Some(cx.waker().clone());
+
+        let phys = GLOBAL_PHYS_ALLOC.lock();
+        let clean = phys.clean_pages();
+        if clean < low_watermark() && phys.free_pages() > clean {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A dirty block to clear, if the pool is below its target
+fn take_dirty() -> Option<(PhysFrame, usize)> {
+    let mut phys = GLOBAL_PHYS_ALLOC.lock();
+    if phys.clean_pages() >= target() {
+        return None;
+    }
+    phys.take_dirty(MAX_ORDER - 1)
+}
+
+async fn zero_block(frame: PhysFrame, order: usize) {
+    let base = frame.pointer().as_ptr();
+    let pages = 1u64 << order;
+
+    let mut done = 0;
+    while done < pages {
+        let chunk = CHUNK_PAGES.min(pages - done);
+        let start = Stopwatch::start();
+        unsafe {
+            zero_nt(
+                base.add((done * PAGE_SIZE) as usize),
+                (chunk * PAGE_SIZE) as usize,
+            )
+        };
+        BUSY_NANOS.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        ZEROED.fetch_add(chunk, Ordering::Relaxed);
+        done += chunk;
+
+        yield_now().await;
+    }
+
+    unsafe { GLOBAL_PHYS_ALLOC.lock().free(frame, order, true) };
+}
+
+/// Keep the clean pool filled, spawn with `Task::idle`
+pub async fn zero_pages() {
+    loop {
+        PoolLow.await;
+        while let Some((frame, order)) = take_dirty() {
+            zero_block(frame, order).await;
+        }
+    }
+}
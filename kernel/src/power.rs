@@ -0,0 +1,79 @@
+use log::{error, info, warn};
+use uefi::table::runtime::ResetType;
+
+use crate::{
+    arch::{
+        acpi, efi,
+        power::{self as hw, QemuExitCode},
+    },
+    cmdline,
+};
+
+/// What to do after a kernel panic, selected with `panic=` on the command line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PanicMode {
+    /// Stop the CPU, the default
+    Halt,
+    /// Reboot the machine
+    Reboot,
+    /// Exit QEMU through `isa-debug-exit` with a failure code
+    QemuExit,
+}
+
+impl PanicMode {
+    pub fn from_cmdline() -> Self {
+        match cmdline::get("panic") {
+            None | Some("halt") => PanicMode::Halt,
+            Some("reboot") => PanicMode::Reboot,
+            Some("qemu-exit") => PanicMode::QemuExit,
+            Some(other) => {
+                warn!("Unknown panic mode {:?}, halting", other);
+                PanicMode::Halt
+            }
+        }
+    }
+}
+
+/// Reboot the machine, trying UEFI, the ACPI reset register, the 8042 and finally a triple fault
+pub fn reboot() -> ! {
+    info!("Rebooting");
+
+    efi::reset(ResetType::Cold);
+
+    acpi::reset();
+    warn!("ACPI reset failed");
+
+    hw::kbc_reset();
+    warn!("8042 reset failed");
+
+    hw::triple_fault()
+}
+
+/// Power off the machine, halting if that is impossible
+pub fn shutdown() -> ! {
+    info!("Shutting down");
+
+    efi::reset(ResetType::Shutdown);
+
+    hw::emulator_poweroff();
+
+    error!("Could not power off, halting");
+    hw::halt()
+}
+
+/// Reboot into the firmware setup UI
+pub fn reboot_to_firmware() -> ! {
+    if !efi::request_boot_to_firmware() {
+        warn!("Firmware does not support booting into its UI");
+    }
+    reboot()
+}
+
+/// Called by the panic handler once the panic has been reported
+pub fn on_panic() -> ! {
+    match PanicMode::from_cmdline() {
+        PanicMode::Halt => hw::halt(),
+        PanicMode::Reboot => reboot(),
+        PanicMode::QemuExit => hw::qemu_exit(QemuExitCode::Failure),
+    }
+}
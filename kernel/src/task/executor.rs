@@ -1,65 +1,106 @@
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
 
 use crossbeam_queue::ArrayQueue;
 
-use super::{Task, TaskId};
+use super::{Priority, Task, TaskId};
+
+/// Each task is in its queue at most once, so the queues never overflow with this many tasks
+const MAX_TASKS: usize = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyTasks,
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    idle_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            idle_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
             waker_cache: BTreeMap::new(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    pub fn spawn(&mut self, task: Task) -> Result<(), SpawnError> {
+        // Queued wakeups of finished tasks take room as well
+        if self.tasks.len() + self.task_queue.len() + self.idle_queue.len() >= MAX_TASKS {
+            return Err(SpawnError::TooManyTasks);
+        }
+
         let task_id = task.id;
+        let queue = match task.priority {
+            Priority::Normal => self.task_queue.clone(),
+            Priority::Idle => self.idle_queue.clone(),
+        };
+        // The waker starts out queued, like the task
+        let waker = TaskWaker::new(task_id, queue);
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        Ok(())
     }
 
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+            if self.task_queue.is_empty() {
+                self.run_idle_task();
+            }
             self.sleep_if_idle();
         }
     }
 
     fn run_ready_tasks(&mut self) {
+        while let Some(task_id) = self.task_queue.pop() {
+            self.poll_task(task_id);
+        }
+    }
+
+    /// Poll a single idle task, so that normal tasks woken meanwhile run first
+    fn run_idle_task(&mut self) {
+        if let Some(task_id) = self.idle_queue.pop() {
+            self.poll_task(task_id);
+        }
+    }
+
+    fn poll_task(&mut self, task_id: TaskId) {
         // destructure `self` to avoid borrow checker errors
         let Self {
-            tasks,
-            task_queue,
-            waker_cache,
+            tasks, waker_cache, ..
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return, // task no longer exists
+        };
+        let task_waker = &waker_cache[&task_id];
+        // Wakeups from now on have to queue the task again
+        task_waker.queued.store(false, Ordering::Release);
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                // task done -> remove it and its cached waker, which must not queue it again
+                tasks.remove(&task_id);
+                if let Some(waker) = waker_cache.remove(&task_id) {
+                    waker.queued.store(true, Ordering::Release);
                 }
-                Poll::Pending => {}
             }
+            Poll::Pending => {}
         }
     }
 
@@ -67,7 +108,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && self.idle_queue.is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -78,18 +119,24 @@ impl Executor {
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    /// The task is in the queue and has not been polled since
+    queued: AtomicBool,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // Cannot fail, there are at most `MAX_TASKS` tasks and each is queued once
+            let _ = self.task_queue.push(self.task_id);
+        }
     }
 }
 
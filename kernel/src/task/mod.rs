@@ -8,15 +8,32 @@ use core::{
 
 pub mod executor;
 
+/// Idle tasks are only polled when no normal task is ready
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    Idle,
+}
+
 pub struct Task {
     id: TaskId,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Self::with_priority(future, Priority::Normal)
+    }
+
+    pub fn idle(future: impl Future<Output = ()> + 'static) -> Task {
+        Self::with_priority(future, Priority::Idle)
+    }
+
+    fn with_priority(future: impl Future<Output = ()> + 'static, priority: Priority) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
             future: Box::pin(future),
         }
     }
@@ -35,3 +52,24 @@ impl TaskId {
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+/// Let other tasks run, resumes on the next poll
+pub fn yield_now() -> impl Future<Output = ()> {
+    YieldNow(false)
+}
+
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
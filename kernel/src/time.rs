@@ -0,0 +1,265 @@
+//! Monotonic and wall-clock time
+//!
+//! The monotonic clock counts PIT interrupts. The wall clock is the monotonic clock plus an
+//! offset, which is taken from the CMOS RTC at boot and corrected on every RTC update.
+//!
+//! Tasks can sleep on the monotonic clock, they are woken from the timer interrupt.
+//!
+//! The monotonic clock only advances every 5 ms, so short work is timed with a `Stopwatch`, which
+//! reads the TSC. It is calibrated against the PIT at boot and assumed to run at a constant rate.
+
+use core::{
+    fmt,
+    future::Future,
+    ops::{Add, Sub},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use arrayvec::ArrayVec;
+use log::info;
+
+use crate::{
+    arch::interrupt::timer,
+    device::rtc::{self, RtcTime},
+    sync::irq_lock::IRQLocked,
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86400;
+
+/// Nanoseconds since the UNIX epoch at the moment the monotonic clock started
+static WALL_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// TSC ticks per millisecond, 0 before calibration
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+const MAX_SLEEPERS: usize = 32;
+
+/// Deadlines of sleeping tasks
+static SLEEPERS: IRQLocked<ArrayVec<(Instant, Waker), MAX_SLEEPERS>> =
+    IRQLocked::new(ArrayVec::new_const());
+
+/// A point on the monotonic clock
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(timer::nanos_since_boot())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now() - *self
+    }
+
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Self(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+/// Measures short intervals with the TSC
+#[derive(Debug, Copy, Clone)]
+pub struct Stopwatch(u64);
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Self(timer::read_tsc())
+    }
+
+    /// Zero before the TSC is calibrated
+    pub fn elapsed(&self) -> Duration {
+        let per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+        if per_ms == 0 {
+            return Duration::ZERO;
+        }
+        let ticks = timer::read_tsc().saturating_sub(self.0);
+        Duration::from_nanos((ticks as u128 * 1_000_000 / per_ms as u128) as u64)
+    }
+}
+
+/// A point on the wall clock, UTC
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemTime(u64);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+impl SystemTime {
+    /// Before the RTC has been read this counts from the epoch
+    pub fn now() -> Self {
+        Self(WALL_OFFSET.load(Ordering::Relaxed) + timer::nanos_since_boot())
+    }
+
+    pub fn from_rtc(time: RtcTime) -> Self {
+        let days = days_from_civil(time.year as i64, time.month as u64, time.day as u64);
+        let secs = days as u64 * SECS_PER_DAY
+            + time.hour as u64 * 3600
+            + time.minute as u64 * 60
+            + time.second as u64;
+        Self(secs * NANOS_PER_SEC)
+    }
+
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Seconds since the UNIX epoch
+    pub fn unix_secs(&self) -> u64 {
+        self.0 / NANOS_PER_SEC
+    }
+
+    /// Calendar date and time, with the seconds rounded down
+    pub fn to_rtc(&self) -> RtcTime {
+        let secs = self.unix_secs();
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let secs = secs % SECS_PER_DAY;
+        RtcTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, rhs: Duration) -> SystemTime {
+        Self(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+/// ISO 8601
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let t = self.to_rtc();
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            t.year,
+            t.month,
+            t.day,
+            t.hour,
+            t.minute,
+            t.second,
+            self.0 % NANOS_PER_SEC / 1_000_000
+        )
+    }
+}
+
+// See http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = (year - era * 400) as u64;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe as i64 - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = (days - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe as i64 + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Resolves once the monotonic clock reaches the deadline, at timer resolution
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let mut sleepers = SLEEPERS.lock();
+        match sleepers.iter_mut().find(|(_, w)| w.will_wake(cx.waker())) {
+            Some(entry) => entry.0 = self.deadline,
+            None => {
+                // Without room for the waker the task polls until the deadline instead
+                if sleepers
+                    .try_push((self.deadline, cx.waker().clone()))
+                    .is_err()
+                {
+                    cx.waker().wake_by_ref();
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+    }
+}
+
+/// Wake the tasks whose deadline has passed, called from the timer interrupt
+pub fn wake_sleepers() {
+    let now = Instant::now();
+    let mut sleepers = SLEEPERS.lock();
+    let mut i = 0;
+    while i < sleepers.len() {
+        if sleepers[i].0 <= now {
+            sleepers.swap_remove(i).1.wake();
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Align the wall clock with an RTC reading
+///
+/// Called from the RTC update interrupt, right after the second has changed
+pub fn sync_wall_clock(time: RtcTime) {
+    let wall = SystemTime::from_rtc(time).0;
+    WALL_OFFSET.store(
+        wall.saturating_sub(timer::nanos_since_boot()),
+        Ordering::Relaxed,
+    );
+}
+
+/// Calibrate the TSC, read the RTC and start the wall clock
+pub fn init() {
+    let per_ms = timer::measure_tsc();
+    TSC_PER_MS.store(per_ms, Ordering::Relaxed);
+    info!("TSC: {} MHz", per_ms / 1000);
+
+    let time = rtc::read_time();
+    sync_wall_clock(time);
+    info!("Wall clock: {}", SystemTime::now());
+}